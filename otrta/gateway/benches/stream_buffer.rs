@@ -0,0 +1,55 @@
+//! Relays a streamed response through the channel between the upstream reader
+//! and the client, as `forward_request_with_payment_and_upstream_body` does,
+//! at several `stream_channel_buffer` sizes. The client side yields after every
+//! chunk, standing in for a socket write, so small buffers show the cost of
+//! the reader parking each time the channel fills.
+//!
+//! Run with `cargo bench -p gateway --bench stream_buffer`.
+
+use axum::body::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::io;
+use tokio::sync::mpsc;
+
+/// 16 KiB chunks, 16 MiB per stream.
+const CHUNK_BYTES: usize = 16 * 1024;
+const CHUNKS: usize = 1024;
+
+async fn relay(buffer: usize) -> usize {
+    let (tx, mut rx) = mpsc::channel::<Result<Bytes, io::Error>>(buffer);
+    let chunk = Bytes::from(vec![b'x'; CHUNK_BYTES]);
+    let upstream = tokio::spawn(async move {
+        for _ in 0..CHUNKS {
+            if tx.send(Ok(chunk.clone())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut relayed = 0;
+    while let Some(Ok(chunk)) = rx.recv().await {
+        relayed += chunk.len();
+        tokio::task::yield_now().await;
+    }
+    upstream.await.unwrap();
+    relayed
+}
+
+fn stream_buffer(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("stream_channel_buffer");
+    group.throughput(Throughput::Bytes((CHUNK_BYTES * CHUNKS) as u64));
+    for buffer in [1, 16, 100, 1000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer),
+            &buffer,
+            |b, &buffer| {
+                b.to_async(&runtime).iter(|| relay(buffer));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stream_buffer);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bakes the git commit and build time into the binary for `/version`.
+fn main() {
+    // Image builds copy the sources without `.git`, so they can pass the hash in.
+    let commit = std::env::var("GIT_COMMIT_HASH")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|commit| commit.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Honours reproducible-build timestamps when the packager sets one.
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default()
+        });
+
+    println!("cargo:rustc-env=GIT_COMMIT_HASH={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,2 @@
+-- Remove fallback endpoints from server configuration
+ALTER TABLE server_config DROP COLUMN IF EXISTS fallback_endpoints;
@@ -0,0 +1,2 @@
+-- Add fallback endpoints to server configuration
+ALTER TABLE server_config ADD COLUMN fallback_endpoints TEXT[] NOT NULL DEFAULT '{}';
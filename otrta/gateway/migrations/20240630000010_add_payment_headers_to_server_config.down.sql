@@ -0,0 +1,3 @@
+-- Remove configurable payment header names from server configuration
+ALTER TABLE server_config DROP COLUMN IF EXISTS change_header;
+ALTER TABLE server_config DROP COLUMN IF EXISTS payment_header;
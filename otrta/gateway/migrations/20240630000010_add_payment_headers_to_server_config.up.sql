@@ -0,0 +1,3 @@
+-- Add configurable payment header names to server configuration
+ALTER TABLE server_config ADD COLUMN payment_header TEXT NOT NULL DEFAULT 'X-PAYMENT-SATS';
+ALTER TABLE server_config ADD COLUMN change_header TEXT NOT NULL DEFAULT 'X-CHANGE-SATS';
@@ -0,0 +1,2 @@
+-- Drop per-model pricing table
+DROP TABLE IF EXISTS model_pricing;
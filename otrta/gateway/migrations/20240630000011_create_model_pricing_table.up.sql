@@ -0,0 +1,7 @@
+-- Create per-model pricing table
+CREATE TABLE model_pricing (
+    model TEXT PRIMARY KEY,
+    price_sats BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ
+);
@@ -0,0 +1,2 @@
+-- Drop spend ledger table
+DROP TABLE IF EXISTS spend_ledger;
@@ -0,0 +1,13 @@
+-- Create ledger of sats spent on forwarded requests
+CREATE TABLE spend_ledger (
+    id TEXT PRIMARY KEY,
+    endpoint TEXT NOT NULL,
+    model TEXT,
+    sats_sent BIGINT NOT NULL,
+    sats_change BIGINT NOT NULL,
+    status INTEGER NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_spend_ledger_created_at ON spend_ledger (created_at);
@@ -0,0 +1,2 @@
+-- Drop client API keys table
+DROP TABLE IF EXISTS client_api_keys;
@@ -0,0 +1,2 @@
+-- Remove per-key request limit
+ALTER TABLE client_api_keys DROP COLUMN IF EXISTS rate_limit;
@@ -0,0 +1,2 @@
+-- Add an optional per-key request limit, overriding the global default
+ALTER TABLE client_api_keys ADD COLUMN rate_limit INTEGER;
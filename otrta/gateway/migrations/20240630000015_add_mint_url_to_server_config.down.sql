@@ -0,0 +1,2 @@
+-- Remove the upstream mint from server configuration
+ALTER TABLE server_config DROP COLUMN IF EXISTS mint_url;
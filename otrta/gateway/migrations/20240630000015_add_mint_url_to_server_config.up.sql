@@ -0,0 +1,2 @@
+-- Add the mint the upstream accepts tokens from to server configuration
+ALTER TABLE server_config ADD COLUMN mint_url TEXT;
@@ -0,0 +1,2 @@
+-- Drop lightning top-ups
+DROP TABLE IF EXISTS wallet_topups;
@@ -0,0 +1,9 @@
+-- Create lightning top-ups awaiting payment or already minted into the wallet
+CREATE TABLE wallet_topups (
+    id TEXT PRIMARY KEY,
+    amount BIGINT NOT NULL,
+    mint_url TEXT,
+    payment_request TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    claimed_at TIMESTAMPTZ
+);
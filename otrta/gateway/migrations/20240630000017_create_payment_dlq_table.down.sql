@@ -0,0 +1,2 @@
+-- Drop the payment dead-letter queue
+DROP TABLE IF EXISTS payment_dlq;
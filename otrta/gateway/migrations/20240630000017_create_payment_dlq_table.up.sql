@@ -0,0 +1,12 @@
+-- Create dead-letter queue of payment tokens that could not be reclaimed
+CREATE TABLE payment_dlq (
+    id TEXT PRIMARY KEY,
+    token TEXT NOT NULL,
+    last_error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    resolved_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_payment_dlq_next_attempt_at ON payment_dlq (next_attempt_at) WHERE resolved_at IS NULL;
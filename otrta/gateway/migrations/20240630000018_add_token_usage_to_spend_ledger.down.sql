@@ -0,0 +1,3 @@
+-- Remove token counts from the spend ledger
+ALTER TABLE spend_ledger DROP COLUMN IF EXISTS completion_tokens;
+ALTER TABLE spend_ledger DROP COLUMN IF EXISTS prompt_tokens;
@@ -0,0 +1,3 @@
+-- Add the token counts reported by the upstream to the spend ledger
+ALTER TABLE spend_ledger ADD COLUMN prompt_tokens BIGINT;
+ALTER TABLE spend_ledger ADD COLUMN completion_tokens BIGINT;
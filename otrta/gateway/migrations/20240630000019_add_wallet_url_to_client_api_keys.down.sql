@@ -0,0 +1,3 @@
+-- Remove per-key wallets
+ALTER TABLE payment_dlq DROP COLUMN IF EXISTS wallet_url;
+ALTER TABLE client_api_keys DROP COLUMN IF EXISTS wallet_url;
@@ -0,0 +1,3 @@
+-- Let client keys pay from their own wallet, and remember which wallet a dead-lettered token belongs to
+ALTER TABLE client_api_keys ADD COLUMN wallet_url TEXT;
+ALTER TABLE payment_dlq ADD COLUMN wallet_url TEXT;
@@ -0,0 +1,2 @@
+-- Remove server configuration names
+ALTER TABLE server_config DROP COLUMN IF EXISTS name;
@@ -0,0 +1,2 @@
+-- Name server configurations so requests can pick one with the X-Upstream header
+ALTER TABLE server_config ADD COLUMN name TEXT UNIQUE;
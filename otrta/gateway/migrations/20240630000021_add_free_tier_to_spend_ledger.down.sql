@@ -0,0 +1,2 @@
+-- Remove the free-tier marker from the spend ledger
+ALTER TABLE spend_ledger DROP COLUMN IF EXISTS free_tier;
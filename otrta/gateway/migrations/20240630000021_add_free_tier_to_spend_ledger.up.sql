@@ -0,0 +1,2 @@
+-- Mark requests for free-tier models, which are forwarded without payment
+ALTER TABLE spend_ledger ADD COLUMN free_tier BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Drop client credit balances
+DROP TABLE IF EXISTS client_credits;
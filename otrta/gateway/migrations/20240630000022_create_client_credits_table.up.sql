@@ -0,0 +1,6 @@
+-- Create table of prepaid sats credit held by each client API key
+CREATE TABLE client_credits (
+    key_id TEXT PRIMARY KEY REFERENCES client_api_keys(id) ON DELETE CASCADE,
+    balance_sats BIGINT NOT NULL DEFAULT 0 CHECK (balance_sats >= 0),
+    updated_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Retry every unresolved dead-lettered token again
+DROP INDEX IF EXISTS idx_payment_dlq_next_attempt_at;
+CREATE INDEX idx_payment_dlq_next_attempt_at ON payment_dlq (next_attempt_at) WHERE resolved_at IS NULL;
+
+ALTER TABLE payment_dlq DROP COLUMN IF EXISTS abandoned_at;
@@ -0,0 +1,5 @@
+-- Let dead-lettered tokens that can never be reclaimed stop being retried
+ALTER TABLE payment_dlq ADD COLUMN abandoned_at TIMESTAMPTZ;
+
+DROP INDEX IF EXISTS idx_payment_dlq_next_attempt_at;
+CREATE INDEX idx_payment_dlq_next_attempt_at ON payment_dlq (next_attempt_at) WHERE resolved_at IS NULL AND abandoned_at IS NULL;
@@ -0,0 +1 @@
+ALTER TABLE wallet_topups DROP COLUMN IF EXISTS wallet_url;
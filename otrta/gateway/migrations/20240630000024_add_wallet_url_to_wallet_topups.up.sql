@@ -0,0 +1,2 @@
+-- Record which tenant wallet a top-up refills; NULL is the shared wallet
+ALTER TABLE wallet_topups ADD COLUMN wallet_url TEXT;
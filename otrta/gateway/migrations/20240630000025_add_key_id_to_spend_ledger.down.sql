@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_spend_ledger_key_id_created_at;
+ALTER TABLE spend_ledger DROP COLUMN IF EXISTS key_id;
@@ -0,0 +1,3 @@
+-- Record which client key spent each entry; NULL when gateway auth is off
+ALTER TABLE spend_ledger ADD COLUMN key_id TEXT;
+CREATE INDEX idx_spend_ledger_key_id_created_at ON spend_ledger (key_id, created_at);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_spend_ledger_batch_id;
+ALTER TABLE spend_ledger DROP COLUMN IF EXISTS batch_id;
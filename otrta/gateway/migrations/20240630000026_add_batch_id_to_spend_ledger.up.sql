@@ -0,0 +1,3 @@
+-- Tag the entries of a batch's items with the batch they came in
+ALTER TABLE spend_ledger ADD COLUMN batch_id TEXT;
+CREATE INDEX idx_spend_ledger_batch_id ON spend_ledger (batch_id) WHERE batch_id IS NOT NULL;
@@ -0,0 +1,115 @@
+use crate::wallets::PaymentWallet;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Posts an alert to a webhook when a wallet's balance drops below a
+/// threshold, at most once per `interval` for each wallet.
+pub struct LowBalanceAlert {
+    threshold: i64,
+    webhook_url: String,
+    interval: Duration,
+    http_client: reqwest::Client,
+    last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl LowBalanceAlert {
+    pub fn new(
+        threshold: i64,
+        webhook_url: String,
+        interval: Duration,
+        http_client: reqwest::Client,
+    ) -> Self {
+        Self {
+            threshold,
+            webhook_url,
+            interval,
+            http_client,
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the balance of `wallet` in the background after a forward has
+    /// spent sats from it.
+    pub fn check(self: &Arc<Self>, wallet: Arc<dyn PaymentWallet>) {
+        let alert = Arc::clone(self);
+        tokio::spawn(async move { alert.check_now(wallet.as_ref()).await });
+    }
+
+    async fn check_now(&self, wallet: &dyn PaymentWallet) {
+        let balance = match wallet.balance().await {
+            Ok(balance) => balance.balance,
+            Err(e) => {
+                warn!(error = %e, "failed to query wallet balance for low-balance alert");
+                return;
+            }
+        };
+        if balance >= self.threshold || !self.claim_slot(wallet.base_url()) {
+            return;
+        }
+
+        let payload = json!({
+            "event": "low_balance",
+            "wallet": wallet.base_url(),
+            "balance": balance,
+            "threshold": self.threshold,
+            "unit": "sat",
+        });
+        match self
+            .http_client
+            .post(&self.webhook_url)
+            .json(&payload)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                info!(
+                    wallet = wallet.base_url(),
+                    balance,
+                    threshold = self.threshold,
+                    "sent low-balance alert"
+                );
+            }
+            Ok(response) => {
+                warn!(status = %response.status(), "low-balance webhook rejected the alert");
+            }
+            Err(e) => warn!(error = %e, "failed to send low-balance alert"),
+        }
+    }
+
+    /// Records an alert for the wallet at `wallet_url` as sent unless one
+    /// already went out for it within the interval.
+    fn claim_slot(&self, wallet_url: &str) -> bool {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if last_sent
+            .get(wallet_url)
+            .is_some_and(|sent| sent.elapsed() < self.interval)
+        {
+            return false;
+        }
+        last_sent.insert(wallet_url.to_string(), Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_each_wallet_on_its_own() {
+        let alert = LowBalanceAlert::new(
+            100,
+            "http://127.0.0.1:1/alerts".to_string(),
+            Duration::from_secs(3600),
+            reqwest::Client::new(),
+        );
+
+        assert!(alert.claim_slot("http://shared-wallet"));
+        assert!(!alert.claim_slot("http://shared-wallet"));
+        assert!(alert.claim_slot("http://tenant-wallet"));
+        assert!(!alert.claim_slot("http://tenant-wallet"));
+    }
+}
@@ -0,0 +1,334 @@
+//! Translation between Anthropic's Messages API and the OpenAI chat
+//! completions format the upstream speaks.
+
+use crate::sse::{SseEvent, SseParser, StreamTranslator, translate_stream};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderValue, header},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use wallet::models::{ChatCompletionRequest, ChatMessage};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub messages: Vec<AnthropicMessage>,
+    /// A string or a list of text blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<Value>,
+    pub max_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    /// A string or a list of content blocks.
+    pub content: Value,
+}
+
+impl MessagesRequest {
+    pub fn is_streaming(&self) -> bool {
+        self.stream.unwrap_or(false)
+    }
+
+    pub fn to_chat_completion(&self) -> ChatCompletionRequest {
+        let system = self.system.as_ref().map(|system| ChatMessage {
+            role: "system".to_string(),
+            content: Value::String(text_of(system)),
+            tool_calls: None,
+        });
+        let messages = system
+            .into_iter()
+            .chain(self.messages.iter().map(|message| ChatMessage {
+                role: message.role.clone(),
+                content: content_parts(&message.content),
+                tool_calls: None,
+            }))
+            .collect();
+
+        let mut extra = HashMap::new();
+        if self.is_streaming() {
+            // Lets the final stream chunk carry token counts for `message_delta`.
+            extra.insert("stream_options".to_string(), json!({"include_usage": true}));
+        }
+
+        ChatCompletionRequest {
+            model: self.model.clone(),
+            messages,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            n: None,
+            max_tokens: Some(self.max_tokens),
+            stream: self.stream,
+            stop: self.stop_sequences.clone(),
+            presence_penalty: None,
+            frequency_penalty: None,
+            logprobs: None,
+            top_logprobs: None,
+            tools: None,
+            tool_choice: None,
+            extra,
+        }
+    }
+}
+
+/// Joins the text of a string or a list of text blocks.
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Maps Anthropic content blocks onto OpenAI content parts. Text and base64
+/// or URL images carry over; other block types have no equivalent and are dropped.
+fn content_parts(content: &Value) -> Value {
+    let Value::Array(blocks) = content else {
+        return content.clone();
+    };
+
+    let parts = blocks
+        .iter()
+        .filter_map(|block| match block.get("type").and_then(Value::as_str)? {
+            "text" => Some(json!({"type": "text", "text": block.get("text")?})),
+            "image" => {
+                let source = block.get("source")?;
+                let url = match source.get("type").and_then(Value::as_str)? {
+                    "base64" => format!(
+                        "data:{};base64,{}",
+                        source.get("media_type")?.as_str()?,
+                        source.get("data")?.as_str()?
+                    ),
+                    "url" => source.get("url")?.as_str()?.to_string(),
+                    _ => return None,
+                };
+                Some(json!({"type": "image_url", "image_url": {"url": url}}))
+            }
+            _ => None,
+        })
+        .collect();
+    Value::Array(parts)
+}
+
+fn stop_reason(finish_reason: Option<&str>) -> &'static str {
+    match finish_reason {
+        Some("length") => "max_tokens",
+        Some("tool_calls") | Some("function_call") => "tool_use",
+        _ => "end_turn",
+    }
+}
+
+/// Converts a buffered chat completion into an Anthropic message.
+pub fn to_message(completion: &Value) -> Value {
+    let choice = completion.pointer("/choices/0");
+    let text = choice
+        .and_then(|choice| choice.pointer("/message/content"))
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let finish_reason = choice
+        .and_then(|choice| choice.get("finish_reason"))
+        .and_then(Value::as_str);
+
+    json!({
+        "id": completion.get("id").cloned().unwrap_or(Value::Null),
+        "type": "message",
+        "role": "assistant",
+        "model": completion.get("model").cloned().unwrap_or(Value::Null),
+        "content": [{"type": "text", "text": text}],
+        "stop_reason": stop_reason(finish_reason),
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": completion.pointer("/usage/prompt_tokens").and_then(Value::as_u64).unwrap_or(0),
+            "output_tokens": completion.pointer("/usage/completion_tokens").and_then(Value::as_u64).unwrap_or(0),
+        },
+    })
+}
+
+/// Rewrites an OpenAI-shaped error body into Anthropic's error shape.
+pub fn to_error(body: &[u8]) -> Value {
+    let parsed: Option<Value> = serde_json::from_slice(body).ok();
+    let message = parsed
+        .as_ref()
+        .and_then(|value| value.pointer("/error/message"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| String::from_utf8_lossy(body).into_owned());
+
+    json!({
+        "type": "error",
+        "error": {"type": "api_error", "message": message},
+    })
+}
+
+/// Re-renders a gateway response for an Anthropic client.
+pub async fn translate_response(response: Response, streaming: bool) -> Response {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    if streaming && parts.status.is_success() {
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        let events = translate_stream(body.into_data_stream(), MessageStream::default());
+        return Response::from_parts(parts, Body::from_stream(events));
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => Bytes::from(format!("Error reading from upstream: {}", e)),
+    };
+    let translated = if parts.status.is_success() {
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(completion) => to_message(&completion),
+            Err(_) => to_error(&bytes),
+        }
+    } else {
+        to_error(&bytes)
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(translated.to_string()))
+}
+
+/// Tracks where an Anthropic event stream is while OpenAI chunks arrive.
+#[derive(Default)]
+struct MessageStream {
+    parser: SseParser,
+    started: bool,
+    finished: bool,
+    finish_reason: Option<String>,
+    output_tokens: u64,
+}
+
+impl MessageStream {
+    fn translate_events(&mut self, events: Vec<SseEvent>) -> String {
+        let mut out = String::new();
+        for event in events {
+            match event {
+                SseEvent::Data(chunk) => out.push_str(&self.translate_chunk(&chunk)),
+                SseEvent::Done => out.push_str(&self.finish()),
+            }
+        }
+        out
+    }
+}
+
+impl StreamTranslator for MessageStream {
+    fn push(&mut self, chunk: &[u8]) -> String {
+        let events = self.parser.push(chunk);
+        self.translate_events(events)
+    }
+
+    /// Closes the open content block and message, once. Upstreams that end
+    /// without `[DONE]` still get a closed message.
+    fn finish(&mut self) -> String {
+        let events = self.parser.finish();
+        let mut out = self.translate_events(events);
+        if self.finished || !self.started {
+            return out;
+        }
+        self.finished = true;
+
+        out.push_str(&event(
+            "content_block_stop",
+            json!({"type": "content_block_stop", "index": 0}),
+        ));
+        out.push_str(&event(
+            "message_delta",
+            json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": stop_reason(self.finish_reason.as_deref()),
+                    "stop_sequence": null,
+                },
+                "usage": {"output_tokens": self.output_tokens},
+            }),
+        ));
+        out.push_str(&event("message_stop", json!({"type": "message_stop"})));
+        out
+    }
+}
+
+impl MessageStream {
+    fn translate_chunk(&mut self, chunk: &Value) -> String {
+        let mut out = String::new();
+        if !self.started {
+            self.started = true;
+            out.push_str(&event(
+                "message_start",
+                json!({
+                    "type": "message_start",
+                    "message": {
+                        "id": chunk.get("id").cloned().unwrap_or(Value::Null),
+                        "type": "message",
+                        "role": "assistant",
+                        "model": chunk.get("model").cloned().unwrap_or(Value::Null),
+                        "content": [],
+                        "stop_reason": null,
+                        "stop_sequence": null,
+                        "usage": {"input_tokens": 0, "output_tokens": 0},
+                    },
+                }),
+            ));
+            out.push_str(&event(
+                "content_block_start",
+                json!({
+                    "type": "content_block_start",
+                    "index": 0,
+                    "content_block": {"type": "text", "text": ""},
+                }),
+            ));
+        }
+
+        if let Some(text) = chunk
+            .pointer("/choices/0/delta/content")
+            .and_then(Value::as_str)
+            .filter(|text| !text.is_empty())
+        {
+            out.push_str(&event(
+                "content_block_delta",
+                json!({
+                    "type": "content_block_delta",
+                    "index": 0,
+                    "delta": {"type": "text_delta", "text": text},
+                }),
+            ));
+        }
+        if let Some(reason) = chunk
+            .pointer("/choices/0/finish_reason")
+            .and_then(Value::as_str)
+        {
+            self.finish_reason = Some(reason.to_string());
+        }
+        if let Some(tokens) = chunk
+            .pointer("/usage/completion_tokens")
+            .and_then(Value::as_u64)
+        {
+            self.output_tokens = tokens;
+        }
+        out
+    }
+}
+
+fn event(name: &str, data: Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
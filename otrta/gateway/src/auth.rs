@@ -0,0 +1,168 @@
+use crate::{db::client_api_keys, models::AppState};
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+const KEY_PREFIX: &str = "sk-gw-";
+const ANTHROPIC_KEY_HEADER: &str = "x-api-key";
+
+/// The client key a request was authenticated with, stored in the request
+/// extensions for handlers and later middleware.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey {
+    pub id: String,
+    pub name: String,
+    pub rate_limit: Option<u32>,
+    /// The wallet this key pays from instead of the shared one.
+    pub wallet_url: Option<String>,
+}
+
+tokio::task_local! {
+    static CURRENT_KEY: AuthenticatedKey;
+}
+
+/// The key the request being handled on this task authenticated with. Set for
+/// the rest of the request by [`require_api_key`], so the forward path can
+/// pick the caller's wallet without every handler extracting it.
+pub fn current_key() -> Option<AuthenticatedKey> {
+    CURRENT_KEY.try_with(Clone::clone).ok()
+}
+
+/// Runs `future` as a request authenticated with `key`.
+#[cfg(test)]
+pub async fn with_key<F: std::future::Future>(key: AuthenticatedKey, future: F) -> F::Output {
+    CURRENT_KEY.scope(key, future).await
+}
+
+/// Keys are only ever stored as their SHA-256 digest.
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn generate_key() -> String {
+    format!("{}{}", KEY_PREFIX, hex::encode(rand::random::<[u8; 24]>()))
+}
+
+/// The key a request presents, from `Authorization: Bearer` or, as Anthropic
+/// clients send it, `x-api-key`.
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get(ANTHROPIC_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(str::trim)
+}
+
+/// Rejects requests without a valid `Authorization: Bearer` or `x-api-key` client key when
+/// gateway auth is enabled.
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !state.gateway_auth_enabled {
+        return next.run(request).await;
+    }
+
+    let Some(key) = presented_key(request.headers()) else {
+        return unauthorized("Missing API key");
+    };
+
+    match client_api_keys::get_active_key_by_hash(&state.db, &hash_key(key)).await {
+        Ok(Some(record)) => {
+            let key = AuthenticatedKey {
+                id: record.id,
+                name: record.name,
+                rate_limit: record
+                    .rate_limit
+                    .and_then(|limit| u32::try_from(limit).ok()),
+                wallet_url: record.wallet_url,
+            };
+            request.extensions_mut().insert(key.clone());
+            CURRENT_KEY.scope(key, next.run(request)).await
+        }
+        Ok(None) => {
+            warn!("rejected request with an unknown API key");
+            unauthorized("Invalid API key")
+        }
+        Err(e) => {
+            error!(error = %e, "failed to look up API key");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": {
+                        "message": "Failed to verify API key",
+                        "type": "server_error",
+                    }
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Guards the management API: server configs, prices, client keys, credits
+/// and the wallet itself. Only the configured admin key is accepted, whether
+/// or not client auth is enabled, and without one every request is refused.
+pub async fn require_admin_key(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_key_hash) = state.admin_key_hash.as_deref() else {
+        warn!("rejected admin request, no admin API key is configured");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": {
+                    "message": "The admin API is disabled until an admin API key is configured",
+                    "type": "invalid_request_error",
+                    "param": null,
+                    "code": "admin_api_disabled",
+                }
+            })),
+        )
+            .into_response();
+    };
+
+    match presented_key(request.headers()) {
+        Some(key) if hash_key(key) == admin_key_hash => next.run(request).await,
+        Some(_) => {
+            warn!("rejected admin request with an invalid admin API key");
+            unauthorized("Invalid admin API key")
+        }
+        None => unauthorized("Missing admin API key"),
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({
+            "error": {
+                "message": message,
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "invalid_api_key",
+            }
+        })),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
@@ -0,0 +1,44 @@
+//! Records each wallet's balance on a timer, so its trend can be charted
+//! between the change events forwards log.
+
+use crate::{telemetry, wallets::WalletRegistry};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Each wait is stretched or shortened by up to this share of the interval, so
+/// several gateways on one wallet do not all query it at the same moment.
+const JITTER: f64 = 0.1;
+
+/// Logs the balance of the shared wallet and every tenant wallet connected so
+/// far, and updates their `gateway_wallet_balance_sats` gauges, about every
+/// `interval`. A failed query is skipped with a warning.
+pub fn spawn(wallets: Arc<WalletRegistry>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(jittered(interval)).await;
+            for wallet in wallets.all() {
+                let wallet_url = wallet.base_url();
+                match wallet.balance().await {
+                    Ok(balance) => {
+                        telemetry::record_wallet_balance(wallet_url, balance.balance);
+                        info!(
+                            wallet = wallet_url,
+                            balance = balance.balance,
+                            "wallet balance"
+                        );
+                    }
+                    Err(e) => warn!(
+                        wallet = wallet_url,
+                        error = %e,
+                        "failed to query wallet balance, skipping balance log"
+                    ),
+                }
+            }
+        }
+    });
+}
+
+fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(1.0 + rand::random_range(-JITTER..=JITTER))
+}
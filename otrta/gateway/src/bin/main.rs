@@ -1,17 +1,46 @@
 use axum::{
-    Router,
-    routing::{get, post},
+    Router, ServiceExt,
+    extract::{DefaultBodyLimit, Request},
+    middleware,
+    routing::{any, delete, get, post},
 };
 use gateway::{
+    alerts::LowBalanceAlert,
+    auth, balance_log, body_limit,
+    bulkhead::Bulkhead,
+    cache::{EmbeddingsCache, ResponseCache, SingleFlight},
+    cancel::StreamCancels,
+    circuit_breaker::CircuitBreakers,
     connection::{DatabaseSettings, get_configuration},
-    forward, handlers,
+    consolidation::Consolidator,
+    db::{client_api_keys, model_pricing},
+    dlq, forward, handlers,
+    headers::HeaderAllowlist,
+    images::{ImageInliner, ImageUrlMode},
+    model_access::{ModelPolicies, ModelPolicy},
     models::AppState,
+    payment::WalletRetry,
+    pricing::PricingStrategyKind,
+    rate_limit::{self, RateLimiter},
+    reload, request_id,
+    settings::{LiveSettings, RuntimeSettings},
+    shadow::ShadowTraffic,
+    shutdown::{ActiveStreams, shutdown_signal},
+    telemetry,
+    topup::AutoTopup,
+    upstream::UpstreamClient,
+    wallets::WalletRegistry,
+    warmup,
 };
+use secrecy::ExposeSecret;
 use sqlx::{PgPool, postgres::PgPoolOptions};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{RwLock, watch};
+use tower::Layer;
 use tower_http::{
+    compression::{CompressionLayer, DefaultPredicate, Predicate},
     cors::{Any, CorsLayer},
+    normalize_path::NormalizePathLayer,
     trace::TraceLayer,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -20,12 +49,15 @@ use wallet::api::CashuWalletClient;
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
+    let (otel_layer, _tracer_guard) = telemetry::install_tracer()
+        .expect("Failed to set up OpenTelemetry export.")
+        .unzip();
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| "wallet_gateway=debug,tower_http=debug".into()),
+            std::env::var("RUST_LOG").unwrap_or_else(|_| "gateway=debug,tower_http=debug".into()),
         ))
         .with(tracing_subscriber::fmt::layer())
+        .with(otel_layer)
         .init();
 
     let configuration = get_configuration().expect("Failed to read configuration.");
@@ -36,48 +68,417 @@ async fn main() {
         .run(&connection_pool)
         .await
         .unwrap();
-    let wallet = CashuWalletClient::new(&configuration.application.wallet_utl);
+    // /readyz reports the gateway unavailable until a config exists; say so
+    // up front so an unconfigured deployment is noticed before traffic arrives.
+    if handlers::get_server_config(&connection_pool)
+        .await
+        .is_none()
+    {
+        tracing::warn!(
+            "No server config found: forwarded requests will fail with 400 server_config_missing \
+             and /readyz will report unavailable until one is created with POST /api/server-config"
+        );
+    }
+    let admin_key_hash = configuration
+        .application
+        .admin_api_key
+        .as_ref()
+        .map(|key| key.expose_secret().trim())
+        .filter(|key| !key.is_empty())
+        .map(auth::hash_key);
+    if admin_key_hash.is_none() {
+        tracing::warn!(
+            "No admin API key configured: the management API will refuse every request \
+             until application.admin_api_key is set"
+        );
+    }
+    // Model prices only apply under per_model, so flag a table that would be ignored.
+    if configuration.application.pricing_strategy != PricingStrategyKind::PerModel
+        && model_pricing::get_all_prices(&connection_pool)
+            .await
+            .is_ok_and(|prices| !prices.is_empty())
+    {
+        tracing::warn!(
+            strategy = ?configuration.application.pricing_strategy,
+            "Model prices are configured but ignored: set application.pricing_strategy \
+             to per_model to charge them"
+        );
+    }
+    let wallet = CashuWalletClient::with_timeouts(
+        &configuration.application.wallet_utl,
+        Duration::from_millis(configuration.application.wallet_request_timeout_ms),
+        Duration::from_millis(configuration.application.wallet_lock_timeout_ms),
+    );
+    let metrics = telemetry::install_recorder().expect("Failed to install metrics recorder.");
+    let http_client = forward::build_http_client(configuration.application.upstream_redirects)
+        .expect("Failed to build HTTP client.");
+
+    let low_balance_alert = match (
+        configuration.application.low_balance_threshold_sats,
+        configuration.application.low_balance_webhook_url.clone(),
+    ) {
+        (Some(threshold), Some(webhook_url)) => Some(Arc::new(LowBalanceAlert::new(
+            threshold,
+            webhook_url,
+            Duration::from_secs(configuration.application.low_balance_alert_interval_secs),
+            http_client.clone(),
+        ))),
+        _ => None,
+    };
+
+    let auto_topup = configuration
+        .application
+        .auto_topup_reserve_sats
+        .map(|reserve| {
+            Arc::new(AutoTopup::new(
+                reserve,
+                configuration.application.auto_topup_amount_sats,
+                configuration.application.mints.first().cloned(),
+                configuration.application.auto_topup_payer_url.clone(),
+                Duration::from_secs(configuration.application.auto_topup_interval_secs),
+                wallet.clone(),
+                connection_pool.clone(),
+                http_client.clone(),
+            ))
+        });
+
+    let wallets = Arc::new(WalletRegistry::new(wallet.clone()));
+    // Connect the tenant wallets up front, so the balance log covers them
+    // before their keys make a request.
+    match client_api_keys::get_all_keys(&connection_pool).await {
+        Ok(keys) => {
+            for key in keys.iter().filter(|key| key.is_active) {
+                if let Some(wallet_url) = key.wallet_url.as_deref() {
+                    wallets.get(Some(wallet_url));
+                }
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "failed to load tenant wallets"),
+    }
+
+    if let Some(secs) = configuration.application.balance_log_interval_secs {
+        balance_log::spawn(wallets.clone(), Duration::from_secs(secs.max(1)));
+    }
+
+    dlq::spawn_worker(
+        wallets.clone(),
+        connection_pool.clone(),
+        Duration::from_secs(configuration.application.dlq_retry_interval_secs),
+        configuration.application.dlq_max_attempts,
+    );
+
+    let consolidator = Arc::new(Consolidator::new(
+        wallet.clone(),
+        connection_pool.clone(),
+        configuration.application.consolidation_threshold,
+    ));
+    consolidator.spawn(Duration::from_secs(
+        configuration.application.consolidation_interval_secs,
+    ));
+
+    let upstream: Arc<dyn UpstreamClient> = Arc::new(http_client.clone());
+    let shadow = configuration
+        .application
+        .shadow_endpoint
+        .clone()
+        .map(|endpoint| {
+            ShadowTraffic::new(
+                endpoint,
+                configuration
+                    .application
+                    .shadow_api_key
+                    .as_ref()
+                    .map(|key| key.expose_secret().to_string()),
+                configuration.application.shadow_sample_rate,
+                configuration.application.shadow_payment_sats,
+                Duration::from_millis(configuration.application.request_timeout_ms),
+                wallets.get(None),
+                http_client.clone(),
+                upstream.clone(),
+            )
+        });
+
+    let image_inliner =
+        (configuration.application.image_url_mode == ImageUrlMode::B64Json).then(|| {
+            ImageInliner::new(
+                http_client.clone(),
+                Duration::from_millis(configuration.application.request_timeout_ms),
+                configuration.application.max_inlined_image_bytes,
+            )
+        });
 
     let app_state = Arc::new(AppState {
         db: connection_pool.clone(),
+        server_configs: Arc::new(connection_pool.clone()),
         users: RwLock::new(HashMap::new()),
         organizations: RwLock::new(HashMap::new()),
         api_keys: RwLock::new(HashMap::new()),
         models: RwLock::new(HashMap::new()),
         providers: RwLock::new(HashMap::new()),
         credits: RwLock::new(HashMap::new()),
+        wallets: wallets.clone(),
         wallet,
+        upstream,
+        http_client,
+        streaming_upstream: Arc::new(
+            forward::build_streaming_http_client(configuration.application.upstream_redirects)
+                .expect("Failed to build streaming HTTP client."),
+        ),
+        metrics,
+        max_batch_requests: configuration.application.max_batch_requests,
+        low_balance_alert,
+        consolidator,
+        auto_topup,
+        track_stream_usage: configuration.application.track_stream_usage,
+        stream_channel_buffer: configuration.application.stream_channel_buffer.max(1),
+        stream_request_body_bytes: configuration.application.stream_request_body_bytes,
+        max_request_body_bytes: configuration.application.max_request_body_bytes,
+        max_file_upload_bytes: configuration.application.max_file_upload_bytes,
+        model_policy: ModelPolicies::new(ModelPolicy::from_settings(&configuration.application)),
+        runtime: LiveSettings::new(RuntimeSettings::from_settings(
+            &configuration.application,
+            connection_pool.clone(),
+        )),
+        image_inliner,
+        readiness_check_upstream: configuration.application.readiness_check_upstream,
+        circuit_breakers: CircuitBreakers::new(
+            configuration.application.circuit_breaker_threshold,
+            Duration::from_secs(configuration.application.circuit_breaker_cooldown_secs),
+        ),
+        upstream_retries: configuration.application.upstream_retries,
+        upstream_retry_backoff: Duration::from_millis(
+            configuration.application.upstream_retry_backoff_ms,
+        ),
+        max_retry_after: Duration::from_millis(configuration.application.max_retry_after_ms),
+        wallet_retry: WalletRetry {
+            retries: configuration.application.wallet_retries,
+            backoff: Duration::from_millis(configuration.application.wallet_retry_backoff_ms),
+            max_wait: Duration::from_millis(configuration.application.max_retry_after_ms),
+        },
+        l402_enabled: configuration.application.l402_enabled,
+        gateway_auth_enabled: configuration.application.gateway_auth_enabled,
+        admin_key_hash,
+        rate_limiter: RateLimiter::new(
+            configuration.application.rate_limit_requests,
+            Duration::from_secs(configuration.application.rate_limit_window_secs),
+        ),
+        forwarded_headers: HeaderAllowlist::new(
+            &configuration.application.forwarded_request_headers,
+            &configuration.application.stripped_request_headers,
+        ),
+        shadow,
+        mints: configuration.application.mints.clone(),
+        active_streams: ActiveStreams::default(),
+        stream_cancels: StreamCancels::default(),
+        credit_mode: configuration.application.credit_mode,
+        bulkhead: configuration
+            .application
+            .max_concurrent_upstream_requests
+            .map(|limit| {
+                Bulkhead::new(
+                    limit,
+                    Duration::from_millis(configuration.application.upstream_queue_timeout_ms),
+                )
+            }),
+        models_cache: ResponseCache::new(Duration::from_secs(
+            configuration.application.models_cache_ttl_secs,
+        )),
+        embeddings_cache: configuration
+            .application
+            .embeddings_cache_ttl_secs
+            .map(|secs| {
+                EmbeddingsCache::new(
+                    Duration::from_secs(secs),
+                    configuration.application.embeddings_cache_max_entries,
+                )
+            }),
+        models_in_flight: SingleFlight::new(),
     });
 
-    let app = Router::new()
-        .route("/api/openai-models", get(handlers::list_openai_models))
+    if configuration.application.warm_up_upstream {
+        warmup::spawn(app_state.clone());
+    }
+
+    if let Some(secs) = configuration.application.config_reload_interval_secs {
+        reload::spawn(app_state.clone(), Duration::from_secs(secs.max(1)));
+    }
+
+    let active_streams = app_state.active_streams.clone();
+    let compress_responses = configuration.application.compress_responses;
+    // The default predicate already skips event streams, images and tiny bodies.
+    let compression = CompressionLayer::new().compress_when(DefaultPredicate::new().and(
+        move |_: axum::http::StatusCode,
+              _: axum::http::Version,
+              _: &axum::http::HeaderMap,
+              _: &axum::http::Extensions| compress_responses,
+    ));
+    // Management routes answer to the admin key alone, never to a client key,
+    // so they are layered on their own and merged in after the client layers.
+    let admin_routes = Router::new()
         .route("/api/wallet/redeem", post(handlers::redeem_token))
         .route("/api/wallet/balance", get(handlers::get_balance))
+        .route("/api/wallet/receive", post(handlers::receive_token))
+        .route("/api/wallet/topup", post(handlers::create_topup))
+        .route("/api/wallet/dlq", get(handlers::list_dead_letters))
+        .route("/api/wallet/topup/{id}/claim", post(handlers::claim_topup))
+        .route(
+            "/api/wallet/consolidate",
+            post(handlers::consolidate_wallet),
+        )
+        .route(
+            "/api/server-config",
+            get(handlers::get_current_server_config),
+        )
+        .route("/api/server-config", post(handlers::update_server_config))
+        .route("/api/server-configs", get(handlers::list_server_configs))
+        .route(
+            "/api/server-configs/{name}",
+            delete(handlers::delete_server_config),
+        )
+        .route("/api/model-pricing", post(handlers::update_model_price))
+        .route(
+            "/api/model-pricing/{*model}",
+            delete(handlers::delete_model_price),
+        )
+        .route("/admin/reload", post(handlers::reload_config))
+        .route("/admin/usage", get(handlers::get_all_usage))
+        .route(
+            "/admin/config",
+            get(handlers::get_admin_config).put(handlers::update_admin_config),
+        )
+        .route("/api/api-keys", get(handlers::list_client_api_keys))
+        .route("/api/api-keys", post(handlers::create_client_api_key))
+        .route(
+            "/api/api-keys/{id}",
+            delete(handlers::delete_client_api_key),
+        )
+        .route(
+            "/api/api-keys/{id}/credits",
+            get(handlers::get_client_credits).post(handlers::add_client_credits),
+        )
+        .layer(DefaultBodyLimit::max(
+            configuration.application.max_request_body_bytes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_admin_key,
+        ));
+    let app = Router::new()
+        .route("/api/openai-models", get(handlers::list_openai_models))
+        .route("/balance", get(handlers::get_wallet_balance))
+        .route("/metrics", get(handlers::get_metrics))
+        .route("/estimate", post(handlers::estimate_cost))
+        .route("/usage", get(handlers::get_usage))
+        .route("/requests/{id}/cancel", post(handlers::cancel_request))
         .route(
             "/v1/chat/completions",
             post(forward::forward_chat_completions),
         )
+        .route(
+            "/v1/chat/completions/batch",
+            post(forward::forward_chat_completions_batch),
+        )
         .route("/chat/completions", post(forward::forward_chat_completions))
+        .route("/v1/messages", post(forward::forward_anthropic_messages))
+        .route("/api/chat", post(forward::forward_ollama_chat))
+        .route("/api/generate", post(forward::forward_ollama_generate))
+        .route("/v1/completions", post(forward::forward_completions))
+        .route("/v1/responses", post(forward::forward_responses))
+        .route("/completions", post(forward::forward_completions))
         .route("/models", get(forward::forward_list_models))
         .route("/models/{model_id}", get(forward::get_specific_model))
         .route("/embeddings", post(forward::forward_embeddings))
+        .route("/moderations", post(forward::forward_moderations))
+        .route("/rerank", post(forward::forward_rerank))
         .route(
             "/images/generations",
             post(forward::forward_image_generations),
         )
+        .route("/audio/speech", post(forward::forward_audio_speech))
+        .route(
+            "/audio/transcriptions",
+            post(forward::forward_audio_transcriptions),
+        )
         .route("/v1/models", get(forward::forward_list_models))
-        .route("/v1/models/{model_id}", get(forward::get_specific_model))
+        .route(
+            "/v1/models/{model_id}",
+            get(forward::get_specific_model).delete(forward::delete_model),
+        )
         .route("/v1/embeddings", post(forward::forward_embeddings))
+        .route("/v1/moderations", post(forward::forward_moderations))
+        .route("/v1/rerank", post(forward::forward_rerank))
+        .route(
+            "/v1/files",
+            get(forward::forward_list_files).post(forward::forward_upload_file),
+        )
+        .route(
+            "/v1/files/{file_id}",
+            get(forward::forward_retrieve_file).delete(forward::forward_delete_file),
+        )
+        .route(
+            "/v1/files/{file_id}/content",
+            get(forward::forward_file_content),
+        )
         .route(
             "/v1/images/generations",
             post(forward::forward_image_generations),
         )
+        .route("/v1/audio/speech", post(forward::forward_audio_speech))
         .route(
-            "/api/server-config",
-            get(handlers::get_current_server_config),
+            "/v1/audio/transcriptions",
+            post(forward::forward_audio_transcriptions),
         )
-        .route("/api/server-config", post(handlers::update_server_config))
+        .route(
+            "/v1/assistants",
+            get(forward::forward_list_assistants).post(forward::forward_create_assistant),
+        )
+        .route(
+            "/v1/assistants/{assistant_id}",
+            get(forward::forward_retrieve_assistant)
+                .post(forward::forward_modify_assistant)
+                .patch(forward::forward_modify_assistant)
+                .delete(forward::forward_delete_assistant),
+        )
+        // Fine-tuning jobs are relayed as-is; listing them pins the methods
+        // each resource accepts.
+        .route(
+            "/v1/fine_tuning/jobs",
+            get(forward::forward_passthrough).post(forward::forward_passthrough),
+        )
+        .route(
+            "/v1/fine_tuning/jobs/{job_id}",
+            get(forward::forward_passthrough).patch(forward::forward_passthrough),
+        )
+        .route(
+            "/v1/fine_tuning/jobs/{job_id}/cancel",
+            post(forward::forward_passthrough),
+        )
+        .route("/v1/{*path}", any(forward::forward_passthrough))
+        .route("/api/model-pricing", get(handlers::list_model_prices))
+        // Layers run outermost-last, so authentication happens before rate limiting.
+        .layer(DefaultBodyLimit::max(
+            configuration.application.max_request_body_bytes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            body_limit::enforce,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::enforce,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_api_key,
+        ))
+        .merge(admin_routes)
+        // Added after the layers so probes and build info need no API key and are never throttled.
+        .route("/healthz", get(handlers::healthz))
+        .route("/readyz", get(handlers::readyz))
+        .route("/version", get(handlers::version))
         .with_state(app_state)
+        .layer(middleware::from_fn(request_id::propagate))
+        .layer(compression)
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -87,9 +488,12 @@ async fn main() {
                 .allow_private_network(true),
         )
         .layer(TraceLayer::new_for_http());
-    println!(
+    // Runs before routing, so `/v1/chat/completions/` reaches the typed handler.
+    let app = NormalizePathLayer::trim_trailing_slash().layer(app);
+    tracing::info!(
         "Server starting on http://{}:{}",
-        configuration.application.host, configuration.application.port
+        configuration.application.host,
+        configuration.application.port
     );
     let listener = tokio::net::TcpListener::bind(format!(
         "{}:{}",
@@ -97,7 +501,33 @@ async fn main() {
     ))
     .await
     .unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    let mut graceful_rx = shutdown_rx.clone();
+    let server = axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+        .with_graceful_shutdown(async move {
+            let _ = graceful_rx.wait_for(|stopping| *stopping).await;
+        });
+
+    let grace_period = Duration::from_secs(configuration.application.shutdown_grace_period_secs);
+    let mut grace_rx = shutdown_rx;
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            let _ = grace_rx.wait_for(|stopping| *stopping).await;
+            tokio::time::sleep(grace_period).await;
+        } => {
+            tracing::warn!(
+                active_streams = active_streams.count(),
+                "shutdown grace period elapsed, dropping remaining streams"
+            );
+        }
+    }
 }
 
 pub async fn get_connection_pool(configuration: &DatabaseSettings) -> Result<PgPool, sqlx::Error> {
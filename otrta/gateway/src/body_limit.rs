@@ -0,0 +1,52 @@
+use crate::{error::ForwardError, models::AppState, payment::COST_HEADER};
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Rejects bodies over the configured size with an OpenAI-shaped 413 before
+/// any handler deserializes them or mints a token. A declared `Content-Length`
+/// is checked up front; chunked bodies are cut off by axum's `DefaultBodyLimit`
+/// while being read, and its plain-text rejection is rewritten here. File
+/// uploads are relayed without buffering and get their own, larger limit.
+pub async fn enforce(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let limit = if is_file_upload(&request) {
+        state.max_file_upload_bytes
+    } else {
+        state.max_request_body_bytes
+    };
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(length) = declared
+        && length > limit as u64
+    {
+        warn!(length, limit, "rejecting oversized request body");
+        return ForwardError::PayloadTooLarge { limit }.into_response();
+    }
+
+    let response = next.run(request).await;
+    // Upstream responses carry the cost header and are passed through untouched.
+    let from_extractor = !response.headers().contains_key(COST_HEADER)
+        && !response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE && from_extractor {
+        warn!(limit, "request body exceeded the limit while being read");
+        return ForwardError::PayloadTooLarge { limit }.into_response();
+    }
+    response
+}
+
+fn is_file_upload(request: &Request) -> bool {
+    request.method() == Method::POST && request.uri().path() == "/v1/files"
+}
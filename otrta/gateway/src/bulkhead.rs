@@ -0,0 +1,75 @@
+use crate::error::ForwardError;
+use metrics::{counter, gauge};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+const IN_FLIGHT: &str = "gateway_upstream_in_flight";
+
+/// Caps how many requests are at the upstream at once. A request that finds
+/// every slot taken waits up to `queue_timeout` for one before being turned
+/// away with a 503.
+pub struct Bulkhead {
+    limit: usize,
+    queue_timeout: Duration,
+    permits: Arc<Semaphore>,
+}
+
+impl Bulkhead {
+    pub fn new(limit: usize, queue_timeout: Duration) -> Self {
+        let limit = limit.max(1);
+        Self {
+            limit,
+            queue_timeout,
+            permits: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<BulkheadPermit, ForwardError> {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .ok_or_else(|| {
+                        warn!(
+                            limit = self.limit,
+                            "upstream concurrency limit reached, rejecting request"
+                        );
+                        counter!("gateway_bulkhead_rejected_total").increment(1);
+                        ForwardError::Overloaded {
+                            retry_after: self.queue_timeout.as_secs().max(1),
+                        }
+                    })?
+            }
+        };
+        self.record_in_flight();
+        Ok(BulkheadPermit {
+            _permit: permit,
+            bulkhead: self.permits.clone(),
+            limit: self.limit,
+        })
+    }
+
+    fn record_in_flight(&self) {
+        gauge!(IN_FLIGHT).set((self.limit - self.permits.available_permits()) as f64);
+    }
+}
+
+/// One request's slot, held until its response has been relayed in full.
+pub struct BulkheadPermit {
+    _permit: OwnedSemaphorePermit,
+    bulkhead: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl Drop for BulkheadPermit {
+    fn drop(&mut self) {
+        // The slot is returned after this runs, so count it as already free.
+        let in_flight = self.limit - self.bulkhead.available_permits() - 1;
+        gauge!(IN_FLIGHT).set(in_flight as f64);
+    }
+}
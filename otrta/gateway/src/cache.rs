@@ -0,0 +1,215 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, RwLock};
+use wallet::models::EmbeddingRequest;
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    cached_at: Instant,
+}
+
+impl CachedResponse {
+    pub fn new(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+            cached_at: Instant::now(),
+        }
+    }
+}
+
+impl IntoResponse for CachedResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, Body::from(self.body)).into_response()
+    }
+}
+
+/// An in-memory cache of upstream responses keyed by upstream URL.
+pub struct ResponseCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<String, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let entries = self.entries.read().await;
+        entries
+            .get(key)
+            .filter(|entry| entry.cached_at.elapsed() < self.ttl)
+            .cloned()
+    }
+
+    pub async fn insert(&self, key: String, response: CachedResponse) {
+        let mut entries = self.entries.write().await;
+        entries.retain(|_, entry| entry.cached_at.elapsed() < self.ttl);
+        entries.insert(key, response);
+    }
+
+    pub async fn clear(&self) {
+        self.entries.write().await.clear();
+    }
+}
+
+/// Embedding responses keyed by a hash of the request, so the same input is
+/// only paid for once per TTL. Holds at most `capacity` entries and evicts the
+/// least recently used one to make room.
+pub struct EmbeddingsCache {
+    ttl: Duration,
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, (u64, CachedResponse)>,
+    /// Last use of each key, oldest first.
+    order: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        let Some((used, _)) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((used, _)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+impl EmbeddingsCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// The model, every input in order, the remaining parameters and the
+    /// upstream they are sent to all change the vectors, so all go in the key.
+    /// So does the paying wallet, so tenants never share an answer.
+    pub fn key(wallet_url: &str, upstream: Option<&str>, request: &EmbeddingRequest) -> String {
+        let extra: BTreeMap<&String, &Value> = request.extra.iter().collect();
+        let material =
+            serde_json::json!([wallet_url, upstream, request.model, request.input, extra]);
+        hex::encode(Sha256::digest(material.to_string().as_bytes()))
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut lru = self.lru.lock().unwrap();
+        let (_, entry) = lru.entries.get(key)?;
+        if entry.cached_at.elapsed() >= self.ttl {
+            lru.remove(key);
+            return None;
+        }
+        let entry = entry.clone();
+        lru.touch(key);
+        Some(entry)
+    }
+
+    pub fn insert(&self, key: String, response: CachedResponse) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(&key);
+        while lru.entries.len() >= self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+        lru.clock += 1;
+        let used = lru.clock;
+        lru.order.insert(used, key.clone());
+        lru.entries.insert(key, (used, response));
+    }
+}
+
+/// Deduplicates concurrent requests for the same key so that only one of them
+/// reaches the upstream and pays, while the others wait for its response.
+#[derive(Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<CachedResponse>>>>,
+}
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> CachedResponse
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = CachedResponse>,
+    {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        let response = cell.get_or_init(f).await.clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(key);
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn runs_one_request_for_fifty_concurrent_callers() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+
+        let responses = futures::future::join_all((0..50).map(|_| {
+            flight.run("models", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                CachedResponse::new(StatusCode::OK, HeaderMap::new(), Bytes::from("models"))
+            })
+        }))
+        .await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(responses.iter().all(|response| response.body == "models"));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::auth;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Identifies a streaming response for `POST /requests/{id}/cancel`.
+pub const STREAM_ID_HEADER: &str = "X-Stream-Id";
+
+/// Streams that are still relaying, by stream ID, so a client that cannot
+/// disconnect can still stop one explicitly.
+#[derive(Clone, Default)]
+pub struct StreamCancels(Arc<Mutex<HashMap<String, Registration>>>);
+
+struct Registration {
+    /// The client key that started the stream; only it may cancel it.
+    owner: Option<String>,
+    cancel: oneshot::Sender<()>,
+}
+
+impl StreamCancels {
+    /// Registers a stream for the calling key under a fresh, unguessable ID.
+    pub fn register(&self) -> CancelHandle {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let (cancel, cancelled) = oneshot::channel();
+        let owner = auth::current_key().map(|key| key.id);
+        self.0
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Registration { owner, cancel });
+        CancelHandle {
+            id,
+            cancelled,
+            streams: self.clone(),
+        }
+    }
+
+    /// Signals the stream to stop. False when no stream the caller may cancel
+    /// is registered under `id`.
+    pub fn cancel(&self, id: &str) -> bool {
+        let caller = auth::current_key().map(|key| key.id);
+        let mut streams = self.0.lock().unwrap();
+        if streams.get(id).is_none_or(|stream| stream.owner != caller) {
+            return false;
+        }
+        streams
+            .remove(id)
+            .is_some_and(|stream| stream.cancel.send(()).is_ok())
+    }
+}
+
+/// Held by the task relaying a stream; unregisters it when dropped.
+pub struct CancelHandle {
+    pub id: String,
+    cancelled: oneshot::Receiver<()>,
+    streams: StreamCancels,
+}
+
+impl CancelHandle {
+    /// Resolves once the stream has been cancelled.
+    pub async fn cancelled(&mut self) {
+        if (&mut self.cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        self.streams.0.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,116 @@
+use metrics::{counter, gauge};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One probe request is let through to test whether the upstream recovered.
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+/// Per-endpoint circuit breakers. After `threshold` consecutive failures an
+/// endpoint is skipped for `cooldown`, then a single probe decides whether it
+/// closes again.
+pub struct CircuitBreakers {
+    threshold: u32,
+    cooldown: Duration,
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request may be sent to `endpoint` right now.
+    pub fn allow(&self, endpoint: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(endpoint) else {
+            return true;
+        };
+
+        match state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= *until => {
+                *state = BreakerState::HalfOpen {
+                    probe_started: Instant::now(),
+                };
+                record_transition(endpoint, "half_open");
+                true
+            }
+            BreakerState::Open { .. } => false,
+            // A probe that never reported back must not keep the breaker stuck.
+            BreakerState::HalfOpen { probe_started } => {
+                if probe_started.elapsed() >= self.cooldown {
+                    *probe_started = Instant::now();
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self, endpoint: &str) {
+        let mut states = self.states.lock().unwrap();
+        if let Some(state) =
+            states.insert(endpoint.to_string(), BreakerState::Closed { failures: 0 })
+            && !matches!(state, BreakerState::Closed { .. })
+        {
+            record_transition(endpoint, "closed");
+        }
+    }
+
+    pub fn record_failure(&self, endpoint: &str) {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry(endpoint.to_string())
+            .or_insert(BreakerState::Closed { failures: 0 });
+
+        let trips = match state {
+            BreakerState::Closed { failures } => {
+                *failures += 1;
+                *failures >= self.threshold
+            }
+            BreakerState::HalfOpen { .. } => true,
+            BreakerState::Open { .. } => false,
+        };
+
+        if trips {
+            *state = BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            };
+            record_transition(endpoint, "open");
+        }
+    }
+}
+
+fn record_transition(endpoint: &str, state: &'static str) {
+    if state == "open" {
+        warn!(endpoint, "circuit breaker opened, shunning upstream");
+    } else {
+        info!(endpoint, state, "circuit breaker state changed");
+    }
+
+    counter!(
+        "gateway_circuit_breaker_transitions_total",
+        "endpoint" => endpoint.to_string(),
+        "state" => state
+    )
+    .increment(1);
+    gauge!("gateway_circuit_breaker_open", "endpoint" => endpoint.to_string())
+        .set(if state == "open" { 1.0 } else { 0.0 });
+}
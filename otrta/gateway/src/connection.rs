@@ -1,6 +1,11 @@
+use crate::credits::CreditMode;
+use crate::forward::RedirectMode;
+use crate::images::ImageUrlMode;
+use crate::pricing::PricingStrategyKind;
 use secrecy::{ExposeSecret, SecretString};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
 #[derive(Debug, serde::Deserialize, Clone)]
@@ -17,6 +22,79 @@ pub struct ApplicationSettings {
     pub worker: usize,
     pub connections: usize,
     pub wallet_utl: String,
+    pub max_retry_payment_sats: i64,
+    pub max_sats_per_request: i64,
+    pub max_choices_per_request: u32,
+    pub max_batch_requests: usize,
+    pub credit_mode: CreditMode,
+    pub moderation_payment_sats: i64,
+    pub pricing_strategy: PricingStrategyKind,
+    pub token_estimate_sats_per_1k_tokens: i64,
+    pub rerank_payment_sats: i64,
+    pub rerank_payment_sats_per_document: i64,
+    pub image_prices: HashMap<String, i64>,
+    pub daily_budget_sats: Option<i64>,
+    pub low_balance_threshold_sats: Option<i64>,
+    pub low_balance_webhook_url: Option<String>,
+    pub low_balance_alert_interval_secs: u64,
+    pub balance_log_interval_secs: Option<u64>,
+    pub auto_topup_reserve_sats: Option<i64>,
+    pub auto_topup_amount_sats: i64,
+    pub auto_topup_payer_url: Option<String>,
+    pub auto_topup_interval_secs: u64,
+    pub dlq_retry_interval_secs: u64,
+    pub dlq_max_attempts: u32,
+    pub consolidation_threshold: u64,
+    pub consolidation_interval_secs: u64,
+    pub request_timeout_ms: u64,
+    pub streaming_timeout_ms: u64,
+    pub max_request_timeout_ms: u64,
+    pub stream_idle_timeout_ms: u64,
+    pub track_stream_usage: bool,
+    pub stream_channel_buffer: usize,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown_secs: u64,
+    pub max_concurrent_upstream_requests: Option<usize>,
+    pub upstream_queue_timeout_ms: u64,
+    pub upstream_retries: u32,
+    pub upstream_redirects: RedirectMode,
+    pub upstream_retry_backoff_ms: u64,
+    pub max_retry_after_ms: u64,
+    pub wallet_retries: u32,
+    pub wallet_retry_backoff_ms: u64,
+    pub wallet_request_timeout_ms: u64,
+    pub wallet_lock_timeout_ms: u64,
+    pub models_cache_ttl_secs: u64,
+    pub embeddings_cache_ttl_secs: Option<u64>,
+    pub embeddings_cache_max_entries: usize,
+    pub config_reload_interval_secs: Option<u64>,
+    pub readiness_check_upstream: bool,
+    pub warm_up_upstream: bool,
+    pub l402_enabled: bool,
+    pub shutdown_grace_period_secs: u64,
+    pub gateway_auth_enabled: bool,
+    pub admin_api_key: Option<SecretString>,
+    pub rate_limit_requests: u32,
+    pub rate_limit_window_secs: u64,
+    pub forwarded_request_headers: Vec<String>,
+    pub stripped_request_headers: Vec<String>,
+    pub compress_responses: bool,
+    pub stream_request_body_bytes: Option<u64>,
+    pub max_request_body_bytes: usize,
+    pub max_file_upload_bytes: usize,
+    pub model_allowlist: HashMap<String, Vec<String>>,
+    pub model_denylist: HashMap<String, Vec<String>>,
+    pub free_models: Vec<String>,
+    pub model_aliases: HashMap<String, String>,
+    pub max_tokens_ceiling: Option<u32>,
+    pub max_tokens_ceilings: HashMap<String, u32>,
+    pub image_url_mode: ImageUrlMode,
+    pub max_inlined_image_bytes: usize,
+    pub shadow_endpoint: Option<String>,
+    pub shadow_api_key: Option<SecretString>,
+    pub shadow_sample_rate: f64,
+    pub shadow_payment_sats: Option<i64>,
+    pub mints: Vec<String>,
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
@@ -42,7 +120,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(&self.password.expose_secret())
+            .password(self.password.expose_secret())
             .port(self.port)
             .ssl_mode(ssl_mode)
     }
@@ -54,7 +132,7 @@ impl DatabaseSettings {
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    println!("{:?}", std::env::current_dir());
+    tracing::debug!(path = ?base_path, "loading configuration");
     let configuration_directory = base_path.join("configuration");
 
     // Detect the running environment.
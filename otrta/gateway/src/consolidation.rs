@@ -0,0 +1,169 @@
+use crate::db::{Pool, payment_dlq};
+use crate::redact::Redacted;
+use cdk::nuts::Token;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use wallet::api::CashuWalletClient;
+
+/// Swaps the many small proofs left behind by change and reclaimed tokens back
+/// into a few large ones, so `wallet.send` stays fast as volume grows.
+///
+/// The wallet does not report how many proofs it holds, so a run is triggered
+/// by the number of tokens received since the last one.
+pub struct Consolidator {
+    wallet: CashuWalletClient,
+    db: Pool,
+    threshold: u64,
+    received: AtomicU64,
+    running: Mutex<()>,
+}
+
+/// What a consolidation run moved.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ConsolidationReport {
+    pub consolidated_sats: i64,
+    pub mints: usize,
+    /// Proofs gathered into the consolidation tokens, counted from the tokens.
+    pub proofs_swapped: usize,
+    /// Tokens received into the wallet since the previous run.
+    pub tokens_received: u64,
+    pub balance: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConsolidationError {
+    #[error("a consolidation is already running")]
+    AlreadyRunning,
+    #[error(transparent)]
+    Wallet(#[from] anyhow::Error),
+}
+
+impl Consolidator {
+    pub fn new(wallet: CashuWalletClient, db: Pool, threshold: u64) -> Self {
+        Self {
+            wallet,
+            db,
+            threshold,
+            received: AtomicU64::new(0),
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Counts a token received into the wallet since the last consolidation.
+    pub fn record_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Checks every `interval` and consolidates once the threshold is crossed.
+    pub fn spawn(self: &Arc<Self>, interval: Duration) {
+        let consolidator = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if consolidator.received.load(Ordering::Relaxed) < consolidator.threshold {
+                    continue;
+                }
+                if let Err(e) = consolidator.consolidate().await {
+                    warn!(error = %e, "background wallet consolidation failed");
+                }
+            }
+        });
+    }
+
+    /// Sends each mint's whole balance to a token and receives it straight
+    /// back, which replaces its proofs with the fewest that cover the balance.
+    /// The wallet's proof lock is held throughout, so requests wait for the
+    /// swap instead of finding the wallet empty. Each token sits in the
+    /// dead-letter queue until it is received, so a failed receive leaves it
+    /// to the dead-letter worker rather than losing the balance.
+    pub async fn consolidate(&self) -> Result<ConsolidationReport, ConsolidationError> {
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(ConsolidationError::AlreadyRunning);
+        };
+        let wallet = self.wallet.exclusive().await?;
+        let mut report = ConsolidationReport {
+            tokens_received: self.received.swap(0, Ordering::Relaxed),
+            ..ConsolidationReport::default()
+        };
+
+        let balance = wallet.balance().await?;
+        let mint_balances: Vec<(Option<String>, i64)> = match &balance.mints {
+            Some(mints) if !mints.is_empty() => mints
+                .iter()
+                .filter_map(|(mint, info)| {
+                    let sats = info.get("balance")?.as_i64()?;
+                    Some((Some(mint.clone()), sats))
+                })
+                .collect(),
+            _ => vec![(None, balance.balance)],
+        };
+
+        for (mint, sats) in mint_balances.into_iter().filter(|(_, sats)| *sats > 0) {
+            let token = match wallet.send(sats, mint.as_deref()).await {
+                Ok(token) => token.token,
+                Err(e) => {
+                    warn!(mint = ?mint, error = %Redacted(&e), "failed to gather proofs for consolidation");
+                    continue;
+                }
+            };
+            let dead_letter = match payment_dlq::insert_entry(
+                &self.db,
+                &token,
+                self.wallet.base_url(),
+                "held by a running consolidation",
+            )
+            .await
+            {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    warn!(mint = ?mint, error = %e, "failed to queue consolidation token, receiving it anyway");
+                    None
+                }
+            };
+            // One retry, since the sats sit in an unclaimed token until this succeeds.
+            let received = match wallet.receive(&token).await {
+                Ok(received) => Ok(received),
+                Err(_) => wallet.receive(&token).await,
+            };
+            match received {
+                Ok(_) => {
+                    report.consolidated_sats += sats;
+                    report.mints += 1;
+                    report.proofs_swapped += count_proofs(&token);
+                    if let Some(id) = dead_letter
+                        && let Err(e) = payment_dlq::mark_resolved(&self.db, &id).await
+                    {
+                        warn!(id = %id, error = %e, "failed to resolve consolidation token");
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        mint = ?mint,
+                        error = %Redacted(&e),
+                        "failed to receive consolidated proofs, left to the dead-letter queue"
+                    );
+                }
+            }
+        }
+
+        report.balance = wallet.balance().await?.balance;
+        info!(
+            consolidated_sats = report.consolidated_sats,
+            mints = report.mints,
+            proofs_swapped = report.proofs_swapped,
+            tokens_received = report.tokens_received,
+            "consolidated wallet proofs"
+        );
+        Ok(report)
+    }
+}
+
+fn count_proofs(token: &str) -> usize {
+    Token::from_str(token).map_or(0, |token| token.proofs().len())
+}
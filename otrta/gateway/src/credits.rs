@@ -0,0 +1,121 @@
+use crate::{
+    auth,
+    db::{Pool, client_credits},
+    error::ForwardError,
+};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+/// How a client key's prepaid credits combine with ecash payment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreditMode {
+    /// Credits are not consulted.
+    #[default]
+    Off,
+    /// Requests the key's credits cover are charged to them and paid from
+    /// the shared wallet. Once they run out the key's own wallet pays; a key
+    /// without one gets a 402.
+    CreditsFirst,
+    /// Every request is charged to the key's credits and still paid from the
+    /// key's wallet, so credits act as a spending allowance.
+    CreditsAndEcash,
+}
+
+/// Credits taken from a key ahead of a request. Whatever the request does not
+/// end up spending goes back when it is settled or dropped.
+pub struct CreditHold {
+    db: Pool,
+    key_id: String,
+    reserved: i64,
+    settled: bool,
+}
+
+/// Which wallet pays for a request once credits have been considered.
+pub enum Payer {
+    Shared,
+    Caller,
+}
+
+/// Reserves `amount` of the calling key's credits as `mode` requires.
+pub async fn reserve(
+    db: &Pool,
+    mode: CreditMode,
+    amount: i64,
+) -> Result<(Option<CreditHold>, Payer), ForwardError> {
+    let key = match auth::current_key() {
+        Some(key) if mode != CreditMode::Off && amount > 0 => key,
+        _ => return Ok((None, Payer::Caller)),
+    };
+
+    let debited = match client_credits::debit(db, &key.id, amount).await {
+        Ok(debited) => debited,
+        Err(e) => {
+            warn!(key = %key.id, error = %e, "failed to debit client credits");
+            None
+        }
+    };
+    let hold = debited.map(|remaining| {
+        debug!(key = %key.id, amount, remaining, "charged request to client credits");
+        CreditHold {
+            db: db.clone(),
+            key_id: key.id.clone(),
+            reserved: amount,
+            settled: false,
+        }
+    });
+
+    match (mode, hold) {
+        (CreditMode::CreditsFirst, Some(hold)) => Ok((Some(hold), Payer::Shared)),
+        (CreditMode::CreditsFirst, None) if key.wallet_url.is_some() => Ok((None, Payer::Caller)),
+        (CreditMode::CreditsAndEcash, Some(hold)) => Ok((Some(hold), Payer::Caller)),
+        _ => Err(ForwardError::InsufficientCredits {
+            required: amount,
+            credits: client_credits::get_balance(db, &key.id).await.unwrap_or(0),
+        }),
+    }
+}
+
+impl CreditHold {
+    /// Charges what the request actually cost: returns the unspent part of the
+    /// hold, or takes the overage when a 402 retry paid more than expected.
+    pub async fn settle(mut self, spent: i64) {
+        self.settled = true;
+        let difference = self.reserved - spent.max(0);
+        let result = if difference > 0 {
+            client_credits::add_credits(&self.db, &self.key_id, difference)
+                .await
+                .map(|_| ())
+        } else if difference < 0 {
+            client_credits::debit(&self.db, &self.key_id, -difference)
+                .await
+                .map(|debited| {
+                    if debited.is_none() {
+                        warn!(key = %self.key_id, overage = -difference, "credits could not cover the full request cost");
+                    }
+                })
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            warn!(key = %self.key_id, error = %e, "failed to settle client credits");
+        }
+    }
+}
+
+impl Drop for CreditHold {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        // The request never reached the upstream, so nothing was spent.
+        let db = self.db.clone();
+        let key_id = std::mem::take(&mut self.key_id);
+        let reserved = self.reserved;
+        tokio::spawn(async move {
+            if let Err(e) = client_credits::add_credits(&db, &key_id, reserved).await {
+                warn!(key = %key_id, error = %e, "failed to refund client credits");
+            }
+        });
+    }
+}
@@ -0,0 +1,125 @@
+use crate::db::helpers::{generate_id, offset_option_to_chrono, offset_to_chrono};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+pub struct ClientApiKeyRecord {
+    pub id: String,
+    pub name: String,
+    pub is_active: bool,
+    pub rate_limit: Option<i32>,
+    pub wallet_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+pub async fn get_all_keys(pool: &PgPool) -> Result<Vec<ClientApiKeyRecord>, sqlx::Error> {
+    let keys = sqlx::query!(
+        r#"
+        SELECT id, name, is_active, rate_limit, wallet_url, created_at, updated_at
+        FROM client_api_keys
+        ORDER BY created_at
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| ClientApiKeyRecord {
+        id: record.id,
+        name: record.name,
+        is_active: record.is_active,
+        rate_limit: record.rate_limit,
+        wallet_url: record.wallet_url,
+        created_at: offset_to_chrono(record.created_at),
+        updated_at: offset_option_to_chrono(record.updated_at),
+    })
+    .collect();
+
+    Ok(keys)
+}
+
+pub async fn get_active_key_by_hash(
+    pool: &PgPool,
+    key_hash: &str,
+) -> Result<Option<ClientApiKeyRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        SELECT id, name, is_active, rate_limit, wallet_url, created_at, updated_at
+        FROM client_api_keys
+        WHERE key_hash = $1 AND is_active
+        "#,
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|r| ClientApiKeyRecord {
+        id: r.id,
+        name: r.name,
+        is_active: r.is_active,
+        rate_limit: r.rate_limit,
+        wallet_url: r.wallet_url,
+        created_at: offset_to_chrono(r.created_at),
+        updated_at: offset_option_to_chrono(r.updated_at),
+    }))
+}
+
+pub async fn create_key(
+    pool: &PgPool,
+    name: &str,
+    key_hash: &str,
+    rate_limit: Option<i32>,
+    wallet_url: Option<&str>,
+) -> Result<ClientApiKeyRecord, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO client_api_keys (id, name, key_hash, rate_limit, wallet_url, created_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        RETURNING id, name, is_active, rate_limit, wallet_url, created_at, updated_at
+        "#,
+        generate_id("key"),
+        name,
+        key_hash,
+        rate_limit,
+        wallet_url
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(ClientApiKeyRecord {
+        id: record.id,
+        name: record.name,
+        is_active: record.is_active,
+        rate_limit: record.rate_limit,
+        wallet_url: record.wallet_url,
+        created_at: offset_to_chrono(record.created_at),
+        updated_at: offset_option_to_chrono(record.updated_at),
+    })
+}
+
+pub async fn delete_key(pool: &PgPool, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM client_api_keys
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+impl ClientApiKeyRecord {
+    pub fn to_model(&self) -> crate::models::ClientApiKey {
+        crate::models::ClientApiKey {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            is_active: self.is_active,
+            rate_limit: self.rate_limit,
+            wallet_url: self.wallet_url.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use sqlx::PgPool;
+
+/// The key's credit balance; zero for a key that was never credited.
+pub async fn get_balance(pool: &PgPool, key_id: &str) -> Result<i64, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        SELECT balance_sats FROM client_credits WHERE key_id = $1
+        "#,
+        key_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map_or(0, |r| r.balance_sats))
+}
+
+/// Adds `amount` sats to the key's balance and returns the new balance.
+pub async fn add_credits(pool: &PgPool, key_id: &str, amount: i64) -> Result<i64, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO client_credits (key_id, balance_sats, updated_at)
+        VALUES ($1, $2, NOW())
+        ON CONFLICT (key_id)
+        DO UPDATE SET balance_sats = client_credits.balance_sats + EXCLUDED.balance_sats, updated_at = NOW()
+        RETURNING balance_sats
+        "#,
+        key_id,
+        amount
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(record.balance_sats)
+}
+
+/// Takes `amount` sats from the key's balance in one statement, so concurrent
+/// requests cannot overdraw it. `None` when the balance is too low.
+pub async fn debit(pool: &PgPool, key_id: &str, amount: i64) -> Result<Option<i64>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        UPDATE client_credits
+        SET balance_sats = balance_sats - $2, updated_at = NOW()
+        WHERE key_id = $1 AND balance_sats >= $2
+        RETURNING balance_sats
+        "#,
+        key_id,
+        amount
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|r| r.balance_sats))
+}
@@ -10,6 +10,10 @@ pub fn offset_to_chrono(dt: OffsetDateTime) -> DateTime<Utc> {
     DateTime::<Utc>::from_timestamp(timestamp, 0).unwrap_or_default()
 }
 
+pub fn chrono_to_offset(dt: DateTime<Utc>) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(dt.timestamp()).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
 pub fn offset_option_to_chrono(dt_opt: Option<OffsetDateTime>) -> Option<DateTime<Utc>> {
     dt_opt.map(offset_to_chrono)
 }
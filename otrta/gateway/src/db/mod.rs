@@ -1,5 +1,11 @@
+pub mod client_api_keys;
+pub mod client_credits;
 pub mod helpers;
+pub mod model_pricing;
+pub mod payment_dlq;
 pub mod server_config;
+pub mod spend_ledger;
+pub mod wallet_topups;
 
 pub use helpers::*;
 pub type Pool = sqlx::PgPool;
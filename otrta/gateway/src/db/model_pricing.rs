@@ -0,0 +1,96 @@
+use crate::db::helpers::{offset_option_to_chrono, offset_to_chrono};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+pub struct ModelPricingRecord {
+    pub model: String,
+    pub price_sats: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+pub async fn get_all_prices(pool: &PgPool) -> Result<Vec<ModelPricingRecord>, sqlx::Error> {
+    let prices = sqlx::query!(
+        r#"
+        SELECT model, price_sats, created_at, updated_at
+        FROM model_pricing
+        ORDER BY model
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| ModelPricingRecord {
+        model: record.model,
+        price_sats: record.price_sats,
+        created_at: offset_to_chrono(record.created_at),
+        updated_at: offset_option_to_chrono(record.updated_at),
+    })
+    .collect();
+
+    Ok(prices)
+}
+
+pub async fn get_price(pool: &PgPool, model: &str) -> Result<Option<i64>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        SELECT price_sats
+        FROM model_pricing
+        WHERE model = $1
+        "#,
+        model
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|r| r.price_sats))
+}
+
+pub async fn upsert_price(
+    pool: &PgPool,
+    model: &str,
+    price_sats: i64,
+) -> Result<ModelPricingRecord, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO model_pricing (model, price_sats, created_at)
+        VALUES ($1, $2, NOW())
+        ON CONFLICT (model) DO UPDATE SET price_sats = EXCLUDED.price_sats, updated_at = NOW()
+        RETURNING model, price_sats, created_at, updated_at
+        "#,
+        model,
+        price_sats
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(ModelPricingRecord {
+        model: record.model,
+        price_sats: record.price_sats,
+        created_at: offset_to_chrono(record.created_at),
+        updated_at: offset_option_to_chrono(record.updated_at),
+    })
+}
+
+pub async fn delete_price(pool: &PgPool, model: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM model_pricing
+        WHERE model = $1
+        "#,
+        model
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+impl ModelPricingRecord {
+    pub fn to_model(&self) -> crate::models::ModelPrice {
+        crate::models::ModelPrice {
+            model: self.model.clone(),
+            price_sats: self.price_sats,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::db::helpers::{generate_id, offset_option_to_chrono, offset_to_chrono};
+use crate::models::{DeadLetter, DeadLetterStatus};
+use crate::redact::mask_token;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+/// Longest wait between two attempts at the same token.
+const MAX_BACKOFF_SECS: i32 = 3600;
+
+pub struct PaymentDlqRecord {
+    pub id: String,
+    pub token: String,
+    pub wallet_url: Option<String>,
+    pub last_error: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub abandoned_at: Option<DateTime<Utc>>,
+}
+
+/// Queues `token` for another try at putting it back into the wallet at
+/// `wallet_url`, returning the entry's id.
+pub async fn insert_entry(
+    pool: &PgPool,
+    token: &str,
+    wallet_url: &str,
+    error: &str,
+) -> Result<String, sqlx::Error> {
+    let id = generate_id("dlq");
+    sqlx::query!(
+        r#"
+        INSERT INTO payment_dlq (id, token, wallet_url, last_error, next_attempt_at, created_at)
+        VALUES ($1, $2, $3, $4, NOW(), NOW())
+        "#,
+        id,
+        token,
+        wallet_url,
+        error
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(id)
+}
+
+pub async fn get_outstanding(pool: &PgPool) -> Result<Vec<PaymentDlqRecord>, sqlx::Error> {
+    let records = sqlx::query!(
+        r#"
+        SELECT id, token, wallet_url, last_error, attempts, next_attempt_at, created_at, abandoned_at
+        FROM payment_dlq
+        WHERE resolved_at IS NULL
+        ORDER BY created_at
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| PaymentDlqRecord {
+        id: r.id,
+        token: r.token,
+        wallet_url: r.wallet_url,
+        last_error: r.last_error,
+        attempts: r.attempts,
+        next_attempt_at: offset_to_chrono(r.next_attempt_at),
+        created_at: offset_to_chrono(r.created_at),
+        abandoned_at: offset_option_to_chrono(r.abandoned_at),
+    })
+    .collect();
+
+    Ok(records)
+}
+
+pub async fn get_due(pool: &PgPool, limit: i64) -> Result<Vec<PaymentDlqRecord>, sqlx::Error> {
+    let records = sqlx::query!(
+        r#"
+        SELECT id, token, wallet_url, last_error, attempts, next_attempt_at, created_at, abandoned_at
+        FROM payment_dlq
+        WHERE resolved_at IS NULL AND abandoned_at IS NULL AND next_attempt_at <= NOW()
+        ORDER BY next_attempt_at
+        LIMIT $1
+        "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| PaymentDlqRecord {
+        id: r.id,
+        token: r.token,
+        wallet_url: r.wallet_url,
+        last_error: r.last_error,
+        attempts: r.attempts,
+        next_attempt_at: offset_to_chrono(r.next_attempt_at),
+        created_at: offset_to_chrono(r.created_at),
+        abandoned_at: offset_option_to_chrono(r.abandoned_at),
+    })
+    .collect();
+
+    Ok(records)
+}
+
+pub async fn mark_resolved(pool: &PgPool, id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE payment_dlq
+        SET resolved_at = NOW()
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Records a failed attempt and pushes the next one back exponentially.
+pub async fn record_failure(pool: &PgPool, id: &str, error: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE payment_dlq
+        SET attempts = attempts + 1,
+            last_error = $2,
+            next_attempt_at = NOW() + make_interval(secs => LEAST(POWER(2, LEAST(attempts, 12)), $3))
+        WHERE id = $1
+        "#,
+        id,
+        error,
+        MAX_BACKOFF_SECS as f64
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Stops retrying a token that can never be reclaimed or has run out of
+/// attempts. It stays listed so an operator can see what was lost.
+pub async fn mark_abandoned(pool: &PgPool, id: &str, error: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE payment_dlq
+        SET attempts = attempts + 1,
+            last_error = $2,
+            abandoned_at = NOW()
+        WHERE id = $1
+        "#,
+        id,
+        error
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+impl PaymentDlqRecord {
+    pub fn to_model(&self) -> DeadLetter {
+        DeadLetter {
+            id: self.id.clone(),
+            token: mask_token(&self.token),
+            wallet_url: self.wallet_url.clone(),
+            last_error: self.last_error.clone(),
+            attempts: self.attempts,
+            status: if self.abandoned_at.is_some() {
+                DeadLetterStatus::Abandoned
+            } else {
+                DeadLetterStatus::Pending
+            },
+            next_attempt_at: self.next_attempt_at,
+            created_at: self.created_at,
+            abandoned_at: self.abandoned_at,
+        }
+    }
+}
@@ -1,12 +1,19 @@
 use crate::db::helpers::{offset_option_to_chrono, offset_to_chrono};
 use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
 use sqlx::PgPool;
 use wallet::models::ServerConfig;
 
+#[derive(Clone, Debug)]
 pub struct ServerConfigRecord {
     pub id: String,
+    pub name: Option<String>,
     pub endpoint: String,
     pub api_key: String,
+    pub fallback_endpoints: Vec<String>,
+    pub payment_header: String,
+    pub change_header: String,
+    pub mint_url: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: Option<DateTime<Utc>>,
 }
@@ -14,8 +21,9 @@ pub struct ServerConfigRecord {
 pub async fn get_all_configs(pool: &PgPool) -> Result<Vec<ServerConfigRecord>, sqlx::Error> {
     let configs = sqlx::query!(
         r#"
-        SELECT id, endpoint, api_key, created_at, updated_at
+        SELECT id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
         FROM server_config
+        ORDER BY created_at
         "#
     )
     .fetch_all(pool)
@@ -23,8 +31,13 @@ pub async fn get_all_configs(pool: &PgPool) -> Result<Vec<ServerConfigRecord>, s
     .into_iter()
     .map(|record| ServerConfigRecord {
         id: record.id,
+        name: record.name,
         endpoint: record.endpoint,
         api_key: record.api_key,
+        fallback_endpoints: record.fallback_endpoints,
+        payment_header: record.payment_header,
+        change_header: record.change_header,
+        mint_url: record.mint_url,
         created_at: offset_to_chrono(record.created_at),
         updated_at: offset_option_to_chrono(record.updated_at),
     })
@@ -39,7 +52,7 @@ pub async fn get_config_by_id(
 ) -> Result<Option<ServerConfigRecord>, sqlx::Error> {
     let record = sqlx::query!(
         r#"
-        SELECT id, endpoint, api_key, created_at, updated_at
+        SELECT id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
         FROM server_config
         WHERE id = $1
         "#,
@@ -51,8 +64,13 @@ pub async fn get_config_by_id(
     match record {
         Some(r) => Ok(Some(ServerConfigRecord {
             id: r.id,
+            name: r.name,
             endpoint: r.endpoint,
             api_key: r.api_key,
+            fallback_endpoints: r.fallback_endpoints,
+            payment_header: r.payment_header,
+            change_header: r.change_header,
+            mint_url: r.mint_url,
             created_at: offset_to_chrono(r.created_at),
             updated_at: offset_option_to_chrono(r.updated_at),
         })),
@@ -63,8 +81,9 @@ pub async fn get_config_by_id(
 pub async fn get_default_config(pool: &PgPool) -> Result<Option<ServerConfigRecord>, sqlx::Error> {
     let record = sqlx::query!(
         r#"
-        SELECT id, endpoint, api_key, created_at, updated_at
+        SELECT id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
         FROM server_config
+        WHERE name IS NULL
         ORDER BY created_at ASC
         LIMIT 1
         "#
@@ -75,8 +94,13 @@ pub async fn get_default_config(pool: &PgPool) -> Result<Option<ServerConfigReco
     match record {
         Some(r) => Ok(Some(ServerConfigRecord {
             id: r.id,
+            name: r.name,
             endpoint: r.endpoint,
             api_key: r.api_key,
+            fallback_endpoints: r.fallback_endpoints,
+            payment_header: r.payment_header,
+            change_header: r.change_header,
+            mint_url: r.mint_url,
             created_at: offset_to_chrono(r.created_at),
             updated_at: offset_option_to_chrono(r.updated_at),
         })),
@@ -84,6 +108,59 @@ pub async fn get_default_config(pool: &PgPool) -> Result<Option<ServerConfigReco
     }
 }
 
+/// The config a request selects with the `X-Upstream` header.
+pub async fn get_config_by_name(
+    pool: &PgPool,
+    name: &str,
+) -> Result<Option<ServerConfigRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        SELECT id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
+        FROM server_config
+        WHERE name = $1
+        "#,
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|r| ServerConfigRecord {
+        id: r.id,
+        name: r.name,
+        endpoint: r.endpoint,
+        api_key: r.api_key,
+        fallback_endpoints: r.fallback_endpoints,
+        payment_header: r.payment_header,
+        change_header: r.change_header,
+        mint_url: r.mint_url,
+        created_at: offset_to_chrono(r.created_at),
+        updated_at: offset_option_to_chrono(r.updated_at),
+    }))
+}
+
+/// Where forwarded requests look up the upstream they go to. Production
+/// reads the database; tests can supply a fixed config.
+pub trait ServerConfigSource: Send + Sync {
+    fn default_config(&self) -> BoxFuture<'_, Result<Option<ServerConfigRecord>, sqlx::Error>>;
+    fn config_by_name<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ServerConfigRecord>, sqlx::Error>>;
+}
+
+impl ServerConfigSource for PgPool {
+    fn default_config(&self) -> BoxFuture<'_, Result<Option<ServerConfigRecord>, sqlx::Error>> {
+        Box::pin(get_default_config(self))
+    }
+
+    fn config_by_name<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ServerConfigRecord>, sqlx::Error>> {
+        Box::pin(get_config_by_name(self, name))
+    }
+}
+
 pub async fn create_config(
     pool: &PgPool,
     config: &ServerConfig,
@@ -95,21 +172,31 @@ pub async fn create_config(
 
     let record = sqlx::query!(
         r#"
-        INSERT INTO server_config (id, endpoint, api_key, created_at)
-        VALUES ($1, $2, $3, NOW())
-        RETURNING id, endpoint, api_key, created_at, updated_at
+        INSERT INTO server_config (id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW())
+        RETURNING id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
         "#,
         id,
+        config.name,
         config.endpoint,
-        config.api_key
+        config.api_key,
+        &config.fallback_endpoints,
+        config.payment_header,
+        config.change_header,
+        config.mint_url
     )
     .fetch_one(pool)
     .await?;
 
     Ok(ServerConfigRecord {
         id: record.id,
+        name: record.name,
         endpoint: record.endpoint,
         api_key: record.api_key,
+        fallback_endpoints: record.fallback_endpoints,
+        payment_header: record.payment_header,
+        change_header: record.change_header,
+        mint_url: record.mint_url,
         created_at: offset_to_chrono(record.created_at),
         updated_at: offset_option_to_chrono(record.updated_at),
     })
@@ -123,12 +210,18 @@ pub async fn update_config(
     let record = sqlx::query!(
         r#"
         UPDATE server_config
-        SET endpoint = $1, api_key = $2, updated_at = NOW()
-        WHERE id = $3
-        RETURNING id, endpoint, api_key, created_at, updated_at
+        SET endpoint = $1, api_key = $2, fallback_endpoints = $3, payment_header = $4,
+            change_header = $5, mint_url = $6, name = $7, updated_at = NOW()
+        WHERE id = $8
+        RETURNING id, name, endpoint, api_key, fallback_endpoints, payment_header, change_header, mint_url, created_at, updated_at
         "#,
         config.endpoint,
         config.api_key,
+        &config.fallback_endpoints,
+        config.payment_header,
+        config.change_header,
+        config.mint_url,
+        config.name,
         id
     )
     .fetch_one(pool)
@@ -136,8 +229,13 @@ pub async fn update_config(
 
     Ok(ServerConfigRecord {
         id: record.id,
+        name: record.name,
         endpoint: record.endpoint,
         api_key: record.api_key,
+        fallback_endpoints: record.fallback_endpoints,
+        payment_header: record.payment_header,
+        change_header: record.change_header,
+        mint_url: record.mint_url,
         created_at: offset_to_chrono(record.created_at),
         updated_at: offset_option_to_chrono(record.updated_at),
     })
@@ -185,8 +283,46 @@ pub async fn count_configs(pool: &PgPool) -> Result<i64, sqlx::Error> {
 impl ServerConfigRecord {
     pub fn to_model(&self) -> ServerConfig {
         ServerConfig {
+            name: self.name.clone(),
             endpoint: self.endpoint.clone(),
             api_key: self.api_key.clone(),
+            fallback_endpoints: self.fallback_endpoints.clone(),
+            payment_header: self.payment_header.clone(),
+            change_header: self.change_header.clone(),
+            mint_url: self.mint_url.clone(),
         }
     }
+
+    /// The primary endpoint followed by the fallbacks, in the order they are tried.
+    pub fn endpoints(&self) -> Vec<String> {
+        std::iter::once(self.endpoint.clone())
+            .chain(self.fallback_endpoints.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tries_the_primary_endpoint_before_the_fallbacks_in_order() {
+        let config = ServerConfigRecord {
+            id: "default".to_string(),
+            name: None,
+            endpoint: "http://primary".to_string(),
+            api_key: String::new(),
+            fallback_endpoints: vec!["http://second".to_string(), "http://third".to_string()],
+            payment_header: "X-PAYMENT-SATS".to_string(),
+            change_header: "X-CHANGE-SATS".to_string(),
+            mint_url: None,
+            created_at: Utc::now(),
+            updated_at: None,
+        };
+
+        assert_eq!(
+            config.endpoints(),
+            vec!["http://primary", "http://second", "http://third"]
+        );
+    }
 }
@@ -0,0 +1,185 @@
+use crate::db::helpers::{chrono_to_offset, generate_id};
+use crate::models::{UsageGroupBy, UsageSummary};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use tracing::warn;
+
+pub struct SpendLedgerEntry {
+    pub endpoint: String,
+    pub model: Option<String>,
+    pub sats_sent: i64,
+    pub sats_change: i64,
+    pub status: i32,
+    pub latency_ms: i64,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    /// Forwarded unpaid because the model is on the free tier.
+    pub free_tier: bool,
+    /// The client key that made the request, `None` when gateway auth is off.
+    pub key_id: Option<String>,
+    /// The batch the request was an item of.
+    pub batch_id: Option<String>,
+}
+
+/// Whose entries a usage report counts.
+#[derive(Clone, Copy, Debug)]
+pub enum UsageScope<'a> {
+    /// Only those of one client key, or of unauthenticated requests for `None`.
+    Key(Option<&'a str>),
+    /// Every entry in the ledger.
+    All,
+}
+
+impl<'a> UsageScope<'a> {
+    fn bind(self) -> (bool, Option<&'a str>) {
+        match self {
+            UsageScope::Key(key_id) => (false, key_id),
+            UsageScope::All => (true, None),
+        }
+    }
+}
+
+pub async fn insert_entry(pool: &PgPool, entry: &SpendLedgerEntry) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO spend_ledger (id, endpoint, model, sats_sent, sats_change, status, latency_ms, prompt_tokens, completion_tokens, free_tier, key_id, batch_id, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, NOW())
+        "#,
+        generate_id("spend"),
+        entry.endpoint,
+        entry.model,
+        entry.sats_sent,
+        entry.sats_change,
+        entry.status,
+        entry.latency_ms,
+        entry.prompt_tokens,
+        entry.completion_tokens,
+        entry.free_tier,
+        entry.key_id,
+        entry.batch_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Net sats spent since midnight UTC.
+pub async fn spent_today(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT COALESCE(SUM(sats_sent - sats_change), 0)::BIGINT as "spent!"
+        FROM spend_ledger
+        WHERE created_at >= date_trunc('day', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'
+        "#
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(result.spent)
+}
+
+/// Totals over the ledger entries in `scope` in `[start, end)`.
+pub async fn usage_totals(
+    pool: &PgPool,
+    scope: UsageScope<'_>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<UsageSummary, sqlx::Error> {
+    let (all_keys, key_id) = scope.bind();
+    let record = sqlx::query!(
+        r#"
+        SELECT COUNT(*) as "requests!",
+            COALESCE(SUM(sats_sent), 0)::BIGINT as "sats_sent!",
+            COALESCE(SUM(sats_change), 0)::BIGINT as "sats_change!",
+            SUM(prompt_tokens)::BIGINT as prompt_tokens,
+            SUM(completion_tokens)::BIGINT as completion_tokens
+        FROM spend_ledger
+        WHERE created_at >= $1 AND created_at < $2
+            AND ($3 OR key_id IS NOT DISTINCT FROM $4)
+        "#,
+        chrono_to_offset(start),
+        chrono_to_offset(end),
+        all_keys,
+        key_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(UsageSummary::new(
+        record.requests,
+        record.sats_sent,
+        record.sats_change,
+        record.prompt_tokens,
+        record.completion_tokens,
+    ))
+}
+
+/// One page of totals over the entries in `scope` in `[start, end)`, grouped
+/// by model, endpoint or UTC day.
+pub async fn usage_by(
+    pool: &PgPool,
+    scope: UsageScope<'_>,
+    group_by: UsageGroupBy,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<(String, UsageSummary)>, sqlx::Error> {
+    let (all_keys, key_id) = scope.bind();
+    let groups = sqlx::query!(
+        r#"
+        SELECT CASE $3
+                WHEN 'model' THEN COALESCE(model, 'unknown')
+                WHEN 'endpoint' THEN endpoint
+                ELSE to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD')
+            END as "key!",
+            COUNT(*) as "requests!",
+            COALESCE(SUM(sats_sent), 0)::BIGINT as "sats_sent!",
+            COALESCE(SUM(sats_change), 0)::BIGINT as "sats_change!",
+            SUM(prompt_tokens)::BIGINT as prompt_tokens,
+            SUM(completion_tokens)::BIGINT as completion_tokens
+        FROM spend_ledger
+        WHERE created_at >= $1 AND created_at < $2
+            AND ($6 OR key_id IS NOT DISTINCT FROM $7)
+        GROUP BY 1
+        ORDER BY 1
+        LIMIT $4 OFFSET $5
+        "#,
+        chrono_to_offset(start),
+        chrono_to_offset(end),
+        group_by.as_str(),
+        limit,
+        offset,
+        all_keys,
+        key_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        (
+            r.key,
+            UsageSummary::new(
+                r.requests,
+                r.sats_sent,
+                r.sats_change,
+                r.prompt_tokens,
+                r.completion_tokens,
+            ),
+        )
+    })
+    .collect();
+
+    Ok(groups)
+}
+
+/// Writes the entry in the background so recording it never delays a response.
+pub fn record_spend(pool: &PgPool, entry: SpendLedgerEntry) {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        if let Err(e) = insert_entry(&pool, &entry).await {
+            warn!(error = %e, endpoint = %entry.endpoint, "failed to record spend ledger entry");
+        }
+    });
+}
@@ -0,0 +1,101 @@
+use crate::db::helpers::{generate_id, offset_option_to_chrono, offset_to_chrono};
+use crate::models::{Topup, TopupStatus};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+pub struct WalletTopupRecord {
+    pub id: String,
+    pub amount: i64,
+    pub mint_url: Option<String>,
+    /// The tenant wallet the top-up refills, or `None` for the shared one.
+    pub wallet_url: Option<String>,
+    pub payment_request: String,
+    pub created_at: DateTime<Utc>,
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+pub async fn create_topup(
+    pool: &PgPool,
+    amount: i64,
+    mint_url: Option<&str>,
+    wallet_url: Option<&str>,
+    payment_request: &str,
+) -> Result<WalletTopupRecord, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO wallet_topups (id, amount, mint_url, wallet_url, payment_request, created_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        RETURNING id, amount, mint_url, wallet_url, payment_request, created_at, claimed_at
+        "#,
+        generate_id("topup"),
+        amount,
+        mint_url,
+        wallet_url,
+        payment_request
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(WalletTopupRecord {
+        id: record.id,
+        amount: record.amount,
+        mint_url: record.mint_url,
+        wallet_url: record.wallet_url,
+        payment_request: record.payment_request,
+        created_at: offset_to_chrono(record.created_at),
+        claimed_at: offset_option_to_chrono(record.claimed_at),
+    })
+}
+
+pub async fn get_topup(pool: &PgPool, id: &str) -> Result<Option<WalletTopupRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"
+        SELECT id, amount, mint_url, wallet_url, payment_request, created_at, claimed_at
+        FROM wallet_topups
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|r| WalletTopupRecord {
+        id: r.id,
+        amount: r.amount,
+        mint_url: r.mint_url,
+        wallet_url: r.wallet_url,
+        payment_request: r.payment_request,
+        created_at: offset_to_chrono(r.created_at),
+        claimed_at: offset_option_to_chrono(r.claimed_at),
+    }))
+}
+
+/// Marks a top-up as minted. Returns false if it was already claimed.
+pub async fn mark_claimed(pool: &PgPool, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE wallet_topups
+        SET claimed_at = NOW()
+        WHERE id = $1 AND claimed_at IS NULL
+        "#,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+impl WalletTopupRecord {
+    pub fn to_model(&self, status: TopupStatus) -> Topup {
+        Topup {
+            id: self.id.clone(),
+            amount: self.amount,
+            mint_url: self.mint_url.clone(),
+            invoice: self.payment_request.clone(),
+            status,
+            created_at: self.created_at,
+            claimed_at: self.claimed_at,
+        }
+    }
+}
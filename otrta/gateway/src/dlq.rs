@@ -0,0 +1,63 @@
+use crate::db::{Pool, payment_dlq};
+use crate::payment::is_transient_wallet_error;
+use crate::redact::{Redacted, redact};
+use crate::wallets::WalletRegistry;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Tokens retried per pass, so a long queue cannot monopolise the wallet.
+const BATCH_SIZE: i64 = 50;
+
+/// Retries `wallet.receive` on queued tokens every `interval` until each one
+/// makes it back into the wallet. Failed tokens back off exponentially; a
+/// token the wallet refuses for good, or that fails `max_attempts` times, is
+/// abandoned.
+pub fn spawn_worker(wallets: Arc<WalletRegistry>, db: Pool, interval: Duration, max_attempts: u32) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            retry_due(&wallets, &db, max_attempts).await;
+        }
+    });
+}
+
+async fn retry_due(wallets: &WalletRegistry, db: &Pool, max_attempts: u32) {
+    let due = match payment_dlq::get_due(db, BATCH_SIZE).await {
+        Ok(due) => due,
+        Err(e) => {
+            warn!(error = %e, "failed to read the payment dead-letter queue");
+            return;
+        }
+    };
+
+    for entry in due {
+        // Taken from the registry so the retry queues behind requests using the same wallet.
+        let wallet = wallets.get(entry.wallet_url.as_deref());
+        let result = match wallet.receive(&entry.token).await {
+            Ok(res) => {
+                info!(
+                    id = %entry.id,
+                    reclaimed = res.balance - res.initial_balance,
+                    "reclaimed dead-lettered token"
+                );
+                payment_dlq::mark_resolved(db, &entry.id).await
+            }
+            Err(e) => {
+                let attempts = entry.attempts.saturating_add(1);
+                let error = redact(&e.to_string()).into_owned();
+                if !is_transient_wallet_error(&e) || attempts as u32 >= max_attempts {
+                    warn!(id = %entry.id, attempts, error = %Redacted(&e), "abandoning dead-lettered token");
+                    payment_dlq::mark_abandoned(db, &entry.id, &error).await
+                } else {
+                    warn!(id = %entry.id, attempts, error = %Redacted(&e), "dead-lettered token still not reclaimable");
+                    payment_dlq::record_failure(db, &entry.id, &error).await
+                }
+            }
+        };
+        if let Err(e) = result {
+            warn!(id = %entry.id, error = %e, "failed to update dead-letter entry");
+        }
+    }
+}
@@ -1,8 +1,11 @@
+use crate::redact::{Redacted, redact};
+use crate::upstream::UpstreamError;
 use axum::{
     Json,
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
+use chrono::{DateTime, Utc};
 use serde_json::json;
 use std::fmt;
 
@@ -45,3 +48,179 @@ impl IntoResponse for AppError {
 }
 
 impl std::error::Error for AppError {}
+
+/// Everything that can stop a request from being forwarded, rendered as an
+/// OpenAI-shaped error body.
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    #[error("Server configuration missing. Cannot process request without a configured endpoint.")]
+    ConfigMissing,
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("{message}")]
+    InvalidBody {
+        message: String,
+        /// Dotted path to the field at fault, when there is one.
+        param: Option<String>,
+    },
+    #[error("The model `{0}` does not exist or you do not have access to it.")]
+    ModelNotFound(String),
+    #[error("No upstream is configured under the name `{0}`.")]
+    UnknownUpstream(String),
+    #[error("Request body exceeds the {limit} byte limit")]
+    PayloadTooLarge { limit: usize },
+    #[error("Failed to serialize request body: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Insufficient wallet balance: {required} sats required, {balance} sats available")]
+    InsufficientBalance { required: i64, balance: i64 },
+    #[error("Insufficient credits: {required} sats required, {credits} sats of credit available")]
+    InsufficientCredits { required: i64, credits: i64 },
+    #[error("Request requires {required} sats, above the per-request cap of {cap} sats")]
+    SpendCapExceeded { required: i64, cap: i64 },
+    #[error("Daily budget of {budget} sats exhausted ({spent} sats spent); resets at {}", resets_at.to_rfc3339())]
+    DailyBudgetExhausted {
+        budget: i64,
+        spent: i64,
+        resets_at: DateTime<Utc>,
+    },
+    #[error("Failed to generate payment token: {}", Redacted(.0))]
+    Payment(anyhow::Error),
+    #[error("Failed to build upstream request: {0}")]
+    RequestBuild(reqwest::Error),
+    #[error("Error forwarding request: {0}")]
+    Upstream(UpstreamError),
+    #[error("Error reading from upstream: {0}")]
+    UpstreamRead(UpstreamError),
+    #[error("The request deadline passed before it could be forwarded")]
+    DeadlineExceeded,
+    #[error("All upstream endpoints are failing; try again shortly")]
+    CircuitOpen,
+    #[error("Too many requests in flight to the upstream; try again shortly")]
+    Overloaded { retry_after: u64 },
+    #[error("No valid upstream endpoint configured")]
+    NoUpstream,
+}
+
+impl ForwardError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ForwardError::ConfigMissing
+            | ForwardError::InvalidRequest(_)
+            | ForwardError::InvalidBody { .. } => StatusCode::BAD_REQUEST,
+            ForwardError::InsufficientBalance { .. }
+            | ForwardError::InsufficientCredits { .. }
+            | ForwardError::SpendCapExceeded { .. } => StatusCode::PAYMENT_REQUIRED,
+            ForwardError::ModelNotFound(_) | ForwardError::UnknownUpstream(_) => {
+                StatusCode::NOT_FOUND
+            }
+            ForwardError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ForwardError::DailyBudgetExhausted { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ForwardError::Serialization(_)
+            | ForwardError::Payment(_)
+            | ForwardError::RequestBuild(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ForwardError::Upstream(e) | ForwardError::UpstreamRead(e) => e.status(),
+            ForwardError::CircuitOpen | ForwardError::Overloaded { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ForwardError::NoUpstream => StatusCode::BAD_GATEWAY,
+            ForwardError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_type(&self) -> &'static str {
+        match self {
+            ForwardError::ConfigMissing => "server_error",
+            ForwardError::InvalidRequest(_)
+            | ForwardError::InvalidBody { .. }
+            | ForwardError::ModelNotFound(_)
+            | ForwardError::UnknownUpstream(_)
+            | ForwardError::PayloadTooLarge { .. } => "invalid_request_error",
+            ForwardError::InsufficientBalance { .. }
+            | ForwardError::InsufficientCredits { .. }
+            | ForwardError::SpendCapExceeded { .. }
+            | ForwardError::DailyBudgetExhausted { .. }
+            | ForwardError::Payment(_) => "payment_error",
+            _ => "gateway_error",
+        }
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            ForwardError::ConfigMissing => Some("server_config_missing"),
+            ForwardError::ModelNotFound(_) => Some("model_not_found"),
+            ForwardError::UnknownUpstream(_) => Some("upstream_not_found"),
+            ForwardError::PayloadTooLarge { .. } => Some("request_too_large"),
+            ForwardError::InsufficientBalance { .. } => Some("insufficient_balance"),
+            ForwardError::InsufficientCredits { .. } => Some("insufficient_credits"),
+            ForwardError::SpendCapExceeded { .. } => Some("spend_cap_exceeded"),
+            ForwardError::DailyBudgetExhausted { .. } => Some("daily_budget_exhausted"),
+            ForwardError::CircuitOpen => Some("circuit_open"),
+            ForwardError::DeadlineExceeded => Some("deadline_exceeded"),
+            ForwardError::Overloaded { .. } => Some("upstream_overloaded"),
+            ForwardError::Upstream(e) | ForwardError::UpstreamRead(e) if e.is_timeout() => {
+                Some("upstream_timeout")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for ForwardError {
+    fn into_response(self) -> Response {
+        let mut error = json!({
+            "message": redact(&self.to_string()),
+            "type": self.error_type(),
+            "param": null,
+            "code": self.code(),
+        });
+        match &self {
+            ForwardError::InsufficientBalance { required, balance } => {
+                error["required"] = json!(required);
+                error["balance"] = json!(balance);
+                error["shortfall"] = json!(required - balance);
+            }
+            ForwardError::InsufficientCredits { required, credits } => {
+                error["required"] = json!(required);
+                error["credits"] = json!(credits);
+            }
+            ForwardError::SpendCapExceeded { required, cap } => {
+                error["required"] = json!(required);
+                error["cap"] = json!(cap);
+            }
+            ForwardError::PayloadTooLarge { limit } => {
+                error["limit"] = json!(limit);
+            }
+            ForwardError::ModelNotFound(_) => {
+                error["param"] = json!("model");
+            }
+            ForwardError::InvalidBody { param, .. } => {
+                error["param"] = json!(param);
+            }
+            ForwardError::DailyBudgetExhausted {
+                budget,
+                spent,
+                resets_at,
+            } => {
+                error["budget"] = json!(budget);
+                error["spent"] = json!(spent);
+                error["resets_at"] = json!(resets_at.to_rfc3339());
+            }
+            _ => {}
+        }
+
+        let mut response = (self.status(), Json(json!({ "error": error }))).into_response();
+        let retry_after = match &self {
+            ForwardError::DailyBudgetExhausted { resets_at, .. } => {
+                Some((*resets_at - Utc::now()).num_seconds().max(1))
+            }
+            ForwardError::Overloaded { retry_after } => Some(*retry_after as i64),
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::ForwardError;
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use serde::de::DeserializeOwned;
+
+/// A JSON request body that is rejected with an OpenAI-shaped
+/// `invalid_request_error` naming the offending field, instead of axum's
+/// plain-text 400.
+pub struct JsonBody<T>(pub T);
+
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json(request.headers()) {
+            return Err(ForwardError::InvalidBody {
+                message: "Expected a request with `Content-Type: application/json`".to_string(),
+                param: None,
+            }
+            .into_response());
+        }
+        // Oversized bodies keep axum's rejection, which the body limit layer rewrites.
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let deserializer = &mut serde_json::Deserializer::from_slice(&bytes);
+        serde_path_to_error::deserialize(deserializer)
+            .map(JsonBody)
+            .map_err(|e| invalid_body(e).into_response())
+    }
+}
+
+/// Whether the body is declared as JSON, including `+json` types.
+pub fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .is_some_and(|mime| {
+            mime.type_() == mime::APPLICATION
+                && (mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON))
+        })
+}
+
+fn invalid_body(error: serde_path_to_error::Error<serde_json::Error>) -> ForwardError {
+    let path = error.path().to_string();
+    let inner = error.into_inner();
+    // A missing field is reported against its parent, so name it in `param`.
+    let missing = inner
+        .to_string()
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next())
+        .map(str::to_string);
+    let param = match missing {
+        Some(field) if path == "." => Some(field),
+        Some(field) => Some(format!("{}.{}", path, field)),
+        None if path == "." => None,
+        None => Some(path),
+    };
+    let message = if inner.is_syntax() || inner.is_eof() {
+        format!("Request body is not valid JSON: {}", inner)
+    } else {
+        format!("Invalid request body: {}", inner)
+    };
+    ForwardError::InvalidBody { message, param }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, post_json};
+    use axum::http::StatusCode;
+    use serde_json::{Value, json};
+    use wallet::models::ChatCompletionRequest;
+
+    async fn rejection(body: Value) -> (StatusCode, Value) {
+        let request = post_json("/v1/chat/completions", &body);
+        let Err(response) = JsonBody::<ChatCompletionRequest>::from_request(request, &()).await
+        else {
+            panic!("{body} was accepted");
+        };
+        (
+            response.status(),
+            body_json(response).await["error"].clone(),
+        )
+    }
+
+    #[tokio::test]
+    async fn names_a_missing_required_field() {
+        let (status, error) = rejection(json!({ "messages": [] })).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["type"], "invalid_request_error");
+        assert_eq!(error["param"], "model");
+        assert!(
+            error["message"]
+                .as_str()
+                .unwrap()
+                .contains("missing field `model`")
+        );
+    }
+
+    #[tokio::test]
+    async fn names_the_path_to_a_field_of_the_wrong_type() {
+        let (status, error) = rejection(json!({
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": "hi" }],
+            "max_tokens": "lots",
+        }))
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["type"], "invalid_request_error");
+        assert_eq!(error["param"], "max_tokens");
+        assert!(error["message"].as_str().unwrap().contains("invalid type"));
+    }
+
+    #[tokio::test]
+    async fn names_a_missing_field_inside_a_nested_object() {
+        let (_, error) = rejection(json!({
+            "model": "gpt-4o",
+            "messages": [{ "content": "hi" }],
+        }))
+        .await;
+
+        assert_eq!(error["param"], "messages[0].role");
+    }
+}
@@ -1,284 +1,3133 @@
-use crate::{db::Pool, handlers::get_server_config, models::*};
+use crate::{
+    anthropic::{self, MessagesRequest},
+    auth,
+    cache::{CachedResponse, EmbeddingsCache},
+    cancel::STREAM_ID_HEADER,
+    credits::{self, Payer},
+    db::{
+        Pool,
+        helpers::generate_id,
+        server_config::ServerConfigRecord,
+        spend_ledger::{SpendLedgerEntry, record_spend, spent_today},
+    },
+    error::ForwardError,
+    extract::{self, JsonBody},
+    headers::end_to_end_headers,
+    models::*,
+    ollama::{self, OllamaChatRequest, OllamaGenerateRequest, OllamaShape},
+    payment::{COST_HEADER, PaidResponse, PaymentError, PaymentHeaders, PaymentLayer},
+    prepare::prepare,
+    pricing::{
+        DEFAULT_PAYMENT_AMOUNT, EndpointType, PaymentAmount, RequestMeta, fixed_payment_amount,
+        price_request, scale_by_choices,
+    },
+    request_id::{self, REQUEST_ID_HEADER},
+    settings::{RuntimeSettings, Snapshot},
+    sse::{TokenUsage, UsageTracker},
+    telemetry,
+    upstream::{UpstreamClient, UpstreamService},
+    wallets::PaymentWallet,
+};
 use axum::{
     Json,
-    body::Body,
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode, header},
+    body::{Body, Bytes},
+    extract::{Path, Query, RawQuery, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
     response::{IntoResponse, Response},
 };
-use futures_util::StreamExt;
+use chrono::{NaiveTime, Utc};
+use futures_util::{StreamExt, future::join_all};
 use reqwest::Client;
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
 use std::io;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use wallet::{
-    api::{CashuWalletApi, CashuWalletClient},
-    models::{ChatCompletionRequest, EmbeddingRequest, ImageGenerationRequest},
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::{Instrument, Span, debug, error, info, info_span, instrument, warn};
+use wallet::models::{
+    ChatCompletionRequest, CompletionRequest, EmbeddingRequest, ImageGenerationRequest,
+    ModerationRequest, RerankRequest, ResponsesRequest, SpeechRequest,
 };
 
+/// Lets a client shorten or extend its request timeout, up to the configured maximum.
+pub const TIMEOUT_HEADER: &str = "X-Timeout-Ms";
+/// Milliseconds the client will wait from now. Also sent upstream with what is
+/// left of it.
+pub const DEADLINE_MS_HEADER: &str = "X-Deadline-Ms";
+/// RFC 3339 time after which the client stops waiting.
+pub const DEADLINE_HEADER: &str = "X-Deadline";
+/// Lets a client choose which of the configured mints pays for its request.
+pub const MINT_HEADER: &str = "X-Mint-Url";
+/// Names the server config a request is routed to; the default config otherwise.
+pub const UPSTREAM_HEADER: &str = "X-Upstream";
+
 pub async fn forward_chat_completions(
     State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
-    Json(request): Json<ChatCompletionRequest>,
+    JsonBody(request): JsonBody<ChatCompletionRequest>,
 ) -> Response {
-    let is_streaming = request.stream.unwrap_or(false);
+    forward_chat_request(&state, headers, query, request, true).await
+}
+
+/// Accepts Anthropic's Messages API, forwards it as a chat completion and
+/// translates the answer, streamed or not, back into Anthropic's format.
+pub async fn forward_anthropic_messages(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    JsonBody(request): JsonBody<MessagesRequest>,
+) -> Response {
+    let is_streaming = request.is_streaming();
+    let response =
+        forward_chat_request(&state, headers, query, request.to_chat_completion(), false).await;
+    anthropic::translate_response(response, is_streaming).await
+}
+
+/// Accepts Ollama's `/api/chat` and answers in Ollama's format, streaming
+/// newline-delimited JSON by default as Ollama does.
+pub async fn forward_ollama_chat(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(request): JsonBody<OllamaChatRequest>,
+) -> Response {
+    let is_streaming = request.is_streaming();
+    let response =
+        forward_chat_request(&state, headers, None, request.to_chat_completion(), false).await;
+    ollama::translate_response(response, OllamaShape::Chat, is_streaming).await
+}
 
+/// Accepts Ollama's `/api/generate`, sent upstream as a single-turn chat.
+pub async fn forward_ollama_generate(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(request): JsonBody<OllamaGenerateRequest>,
+) -> Response {
+    let is_streaming = request.is_streaming();
+    let response =
+        forward_chat_request(&state, headers, None, request.to_chat_completion(), false).await;
+    ollama::translate_response(response, OllamaShape::Generate, is_streaming).await
+}
+
+/// Forwards a chat completion, whether the client sent it as one or the
+/// gateway built it, e.g. translated from Ollama or one item of a batch. Only
+/// requests sent as chat completions are `mirror`ed to the shadow upstream.
+async fn forward_chat_request(
+    state: &AppState,
+    headers: HeaderMap,
+    query: Option<String>,
+    mut request: ChatCompletionRequest,
+    mirror: bool,
+) -> Response {
+    let snapshot = Snapshot::current(state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
+    let is_streaming = request.stream.unwrap_or(false);
     let endpoint_fn =
         move |base_endpoint: &str| -> String { format!("{}/v1/chat/completions", base_endpoint) };
 
-    let response = forward_request_with_payment_with_body(
+    if mirror && let Some(shadow) = &state.shadow {
+        shadow.mirror(
+            "/v1/chat/completions",
+            EndpointType::ChatCompletions,
+            &request,
+        );
+    }
+
+    forward_request_with_payment_with_body(
         headers,
-        &state.db,
-        &state.wallet,
-        endpoint_fn,
+        state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::ChatCompletions,
+        fixed_payment_amount(amount),
         Some(request),
         is_streaming,
     )
+    .await
+    .into_response()
+}
+
+tokio::task_local! {
+    static BATCH_ID: String;
+}
+
+/// The batch the request being forwarded on this task is an item of.
+fn current_batch() -> Option<String> {
+    BATCH_ID.try_with(Clone::clone).ok()
+}
+
+/// Forwards every chat request of a batch concurrently, each paid for by its
+/// own token and held to the bulkhead like any other request, and answers
+/// with every item's status and body in order plus the batch's total cost.
+/// Each item is a ledger entry of its own, tagged with the batch's id.
+pub async fn forward_chat_completions_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(requests): JsonBody<Vec<ChatCompletionRequest>>,
+) -> Response {
+    if requests.is_empty() || requests.len() > state.max_batch_requests {
+        return ForwardError::InvalidRequest(format!(
+            "A batch must hold between 1 and {} requests",
+            state.max_batch_requests
+        ))
+        .into_response();
+    }
+
+    let id = generate_id("batch");
+    let items = join_all(requests.into_iter().map(|request| {
+        let headers = headers.clone();
+        let state = &state;
+        BATCH_ID.scope(id.clone(), async move {
+            let response = if request.stream.unwrap_or(false) {
+                ForwardError::InvalidRequest(
+                    "Streaming is not supported for batched requests".to_string(),
+                )
+                .into_response()
+            } else {
+                forward_chat_request(state, headers, None, request, false).await
+            };
+            buffer_response(response).await
+        })
+    }))
     .await;
 
-    response.into_response()
+    let data: Vec<BatchItem> = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| BatchItem {
+            index,
+            status: item.status.as_u16(),
+            cost_sats: item
+                .headers
+                .get(COST_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            body: serde_json::from_slice(&item.body).unwrap_or_else(|_| {
+                Value::String(String::from_utf8_lossy(&item.body).into_owned())
+            }),
+        })
+        .collect();
+    let total_cost_sats = data.iter().map(|item| item.cost_sats).sum();
+
+    let mut response = Json(BatchResponse {
+        id,
+        object: "batch".to_string(),
+        data,
+        total_cost_sats,
+    })
+    .into_response();
+    response
+        .headers_mut()
+        .insert(COST_HEADER, HeaderValue::from(total_cost_sats));
+    response
+}
+
+pub async fn forward_completions(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    JsonBody(mut request): JsonBody<CompletionRequest>,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
+    let is_streaming = request.stream.unwrap_or(false);
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/completions", base_endpoint) };
+
+    forward_request_with_payment_with_body(
+        headers,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::Completions,
+        fixed_payment_amount(amount),
+        Some(request),
+        is_streaming,
+    )
+    .await
+    .into_response()
+}
+
+/// Forwards OpenAI's Responses API as-is; streamed responses keep their own
+/// event types.
+pub async fn forward_responses(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    JsonBody(mut request): JsonBody<ResponsesRequest>,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
+    let is_streaming = request.stream.unwrap_or(false);
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/responses", base_endpoint) };
+
+    forward_request_with_payment_with_body(
+        headers,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::Responses,
+        fixed_payment_amount(amount),
+        Some(request),
+        is_streaming,
+    )
+    .await
+    .into_response()
 }
 
 pub async fn forward_list_models(
     State(state): State<Arc<AppState>>,
+    Query(cache_query): Query<CacheQuery>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
 ) -> Response {
     let endpoint_fn = |base_endpoint: &str| -> String { format!("{}/v1/models", base_endpoint) };
 
-    let response =
-        forward_request_with_payment(headers, &state.db, &state.wallet, endpoint_fn).await;
+    let bypass_cache = cache_query.refresh || requests_no_cache(&headers);
+    let response = forward_cached_request_with_payment(
+        headers,
+        &state,
+        with_query(endpoint_fn, query),
+        bypass_cache,
+    )
+    .await;
 
     response.into_response()
 }
 
 pub async fn forward_embeddings(
     State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
-    Json(request): Json<EmbeddingRequest>,
+    JsonBody(mut request): JsonBody<EmbeddingRequest>,
 ) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
     let endpoint_fn =
         |base_endpoint: &str| -> String { format!("{}/v1/embeddings", base_endpoint) };
 
+    if let Some(shadow) = &state.shadow {
+        shadow.mirror("/v1/embeddings", EndpointType::Embeddings, &request);
+    }
+
+    let cache = state
+        .embeddings_cache
+        .as_ref()
+        .filter(|_| !requests_no_cache(&headers));
+    let cache_key = cache.map(|_| {
+        let upstream = headers
+            .get(UPSTREAM_HEADER)
+            .and_then(|value| value.to_str().ok());
+        EmbeddingsCache::key(state.wallets.for_caller().base_url(), upstream, &request)
+    });
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        if let Some(mut cached) = cache.get(key) {
+            telemetry::record_embeddings_cache_hit(amount);
+            // Nothing was paid for this answer.
+            cached.headers.insert(COST_HEADER, HeaderValue::from(0));
+            return cached.into_response();
+        }
+        telemetry::record_embeddings_cache_miss();
+    }
+
     let response = forward_request_with_payment_with_body(
         headers,
-        &state.db,
-        &state.wallet,
-        endpoint_fn,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::Embeddings,
+        fixed_payment_amount(amount),
         Some(request),
         false,
     )
-    .await;
+    .await
+    .into_response();
 
-    response.into_response()
+    let (Some(cache), Some(key)) = (cache, cache_key) else {
+        return response;
+    };
+    let buffered = buffer_response(response).await;
+    if buffered.status.is_success() {
+        cache.insert(key, buffered.clone());
+    }
+    buffered.into_response()
 }
 
 pub async fn forward_image_generations(
     State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
-    Json(request): Json<ImageGenerationRequest>,
+    JsonBody(mut request): JsonBody<ImageGenerationRequest>,
 ) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
     let endpoint_fn =
         |base_endpoint: &str| -> String { format!("{}/v1/images/generations", base_endpoint) };
 
     let response = forward_request_with_payment_with_body(
         headers,
-        &state.db,
-        &state.wallet,
-        endpoint_fn,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::ImageGenerations,
+        fixed_payment_amount(amount),
         Some(request),
         false,
     )
     .await;
 
-    response.into_response()
+    match &state.image_inliner {
+        Some(inliner) => inliner.inline(response.into_response()).await,
+        None => response.into_response(),
+    }
 }
 
-pub async fn get_specific_model(
-    Path(model_id): Path<String>,
+pub async fn forward_audio_speech(
     State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
+    JsonBody(mut request): JsonBody<SpeechRequest>,
 ) -> Response {
-    let model_endpoint =
-        move |endpoint: &str| -> String { format!("{}/v1/models/{}", endpoint, model_id) };
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
+    let endpoint_fn =
+        |base_endpoint: &str| -> String { format!("{}/v1/audio/speech", base_endpoint) };
+
+    let response = forward_request_with_payment_with_body(
+        headers,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::AudioSpeech,
+        fixed_payment_amount(amount),
+        Some(request),
+        // Audio is passed through as it arrives instead of being buffered.
+        true,
+    )
+    .await;
 
-    let response =
-        forward_request_with_payment(headers, &state.db, &state.wallet, model_endpoint).await;
     response.into_response()
 }
 
-pub async fn forward_request_with_payment(
-    original_headers: HeaderMap,
-    db: &Pool,
-    wallet: &CashuWalletClient,
-    endpoint_fn: impl Fn(&str) -> String,
-) -> Response<Body> {
+pub async fn forward_moderations(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    JsonBody(mut request): JsonBody<ModerationRequest>,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
+    };
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/moderations", base_endpoint) };
+
     forward_request_with_payment_with_body(
-        original_headers,
-        db,
-        wallet,
-        endpoint_fn,
-        None::<serde_json::Value>, // Use Value as a placeholder type
+        headers,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::Moderations,
+        fixed_payment_amount(amount),
+        Some(request),
         false,
     )
     .await
+    .into_response()
 }
 
-pub async fn forward_request_with_payment_with_body<T: serde::Serialize>(
-    original_headers: HeaderMap,
-    db: &Pool,
-    wallet: &CashuWalletClient,
-    endpoint_fn: impl Fn(&str) -> String,
-    body: Option<T>,
-    is_streaming: bool,
-) -> Response<Body> {
-    let server_config = if let Some(config) = get_server_config(&db).await {
-        config
-    } else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "error": {
-                    "message": "Server configuration missing. Cannot process request without a configured endpoint.",
-                    "type": "server_error",
-                    "param": null,
-                    "code": "server_config_missing"
-                }
-            })),
-        ).into_response();
+pub async fn forward_rerank(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    JsonBody(mut request): JsonBody<RerankRequest>,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let amount = match prepare(&snapshot, &mut request).await {
+        Ok(amount) => amount,
+        Err(e) => return e.into_response(),
     };
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/rerank", base_endpoint) };
+
+    forward_request_with_payment_with_body(
+        headers,
+        &state,
+        snapshot,
+        Method::POST,
+        with_query(endpoint_fn, query),
+        EndpointType::Rerank,
+        fixed_payment_amount(amount),
+        Some(request),
+        false,
+    )
+    .await
+    .into_response()
+}
 
-    let mut client_builder = Client::builder();
+pub async fn forward_audio_transcriptions(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let endpoint_fn =
+        |base_endpoint: &str| -> String { format!("{}/v1/audio/transcriptions", base_endpoint) };
 
-    if is_streaming {
-        use std::time::Duration;
-        client_builder = client_builder
-            .timeout(Duration::from_secs(300))
-            .pool_idle_timeout(None)
-            .pool_max_idle_per_host(0);
-    }
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type.clone(),
+        None => {
+            return ForwardError::InvalidRequest(
+                "Missing multipart/form-data content type".to_string(),
+            )
+            .into_response();
+        }
+    };
 
-    let client = client_builder.build().unwrap();
-    let endpoint_url = endpoint_fn(&server_config.endpoint);
+    let upstream_body =
+        UpstreamBody::relay(content_type, &headers, body, state.max_request_body_bytes);
 
-    let mut req_builder = if body.is_some() {
-        client.post(endpoint_url)
-    } else {
-        client.get(endpoint_url)
+    let context = ForwardContext {
+        endpoint_type: EndpointType::AudioTranscriptions,
+        method: Method::POST,
+        model: None,
+        amount: untyped_price(&snapshot, EndpointType::AudioTranscriptions).await,
+        free_tier: false,
+        snapshot,
+        key_id: auth::current_key().map(|key| key.id),
+        batch_id: current_batch(),
     };
 
-    if let Some(body_data) = body {
-        req_builder = req_builder.json(&body_data);
-    }
+    let response = forward_request_with_payment_and_upstream_body(
+        headers,
+        &state,
+        with_query(endpoint_fn, query),
+        context,
+        Some(upstream_body),
+        false,
+    )
+    .await;
 
-    let token_result = wallet.send(10, None, None, None, None).await;
-    let token = match token_result {
-        Ok(token) => token.token,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({
-                    "error": {
-                        "message": format!("Failed to generate payment token: {}", e),
-                        "type": "payment_error",
-                    }
-                })),
+    response.into_response()
+}
+
+/// Uploads a file for assistants or fine-tuning; the multipart body streams
+/// through without being buffered.
+pub async fn forward_upload_file(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type.clone(),
+        None => {
+            return ForwardError::InvalidRequest(
+                "Missing multipart/form-data content type".to_string(),
             )
-                .into_response();
+            .into_response();
         }
     };
+    let upstream_body =
+        UpstreamBody::relay(content_type, &headers, body, state.max_file_upload_bytes);
 
-    req_builder = req_builder.header(
-        header::AUTHORIZATION,
-        format!("Bearer {}", server_config.api_key),
-    );
-    req_builder = req_builder.header(header::CONTENT_TYPE, "application/json");
-    req_builder = req_builder.header("X-PAYMENT-SATS", &token);
+    forward_files(
+        &state,
+        headers,
+        Method::POST,
+        String::new(),
+        query,
+        Some(upstream_body),
+        false,
+    )
+    .await
+}
 
-    if let Some(accept) = original_headers.get(header::ACCEPT) {
-        req_builder = req_builder.header(header::ACCEPT, accept);
-    }
+pub async fn forward_list_files(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    forward_files(
+        &state,
+        headers,
+        Method::GET,
+        String::new(),
+        query,
+        None,
+        false,
+    )
+    .await
+}
 
-    match req_builder.send().await {
-        Ok(resp) => {
-            let status = resp.status();
-            let headers = resp.headers().clone();
+pub async fn forward_retrieve_file(
+    Path(file_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let path = format!("/{}", file_id);
+    forward_files(&state, headers, Method::GET, path, query, None, false).await
+}
 
-            let mut response = Response::builder().status(status);
+/// File contents can be large binaries, so they stream to the client.
+pub async fn forward_file_content(
+    Path(file_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let path = format!("/{}/content", file_id);
+    forward_files(&state, headers, Method::GET, path, query, None, true).await
+}
 
-            if is_streaming && !headers.contains_key(header::CONTENT_TYPE) {
-                response = response.header(header::CONTENT_TYPE, "text/event-stream");
-            }
+pub async fn forward_delete_file(
+    Path(file_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let path = format!("/{}", file_id);
+    forward_files(&state, headers, Method::DELETE, path, query, None, false).await
+}
 
-            if let Some(change_sats) = headers.get("X-CHANGE-SATS") {
-                if let Ok(res) = wallet
-                    .receive(Some(change_sats.to_str().unwrap()), None, None)
-                    .await
-                {
-                    println!("received change, balance: '{}'", res.balance);
-                }
-            }
+async fn forward_files(
+    state: &AppState,
+    headers: HeaderMap,
+    method: Method,
+    path: String,
+    query: Option<String>,
+    body: Option<UpstreamBody>,
+    is_streaming: bool,
+) -> Response {
+    let snapshot = Snapshot::current(state);
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/files{}", base_endpoint, path) };
 
-            let response_headers = response.headers_mut().unwrap();
-            for (name, value) in headers.iter() {
-                if name != "connection" && name != "transfer-encoding" {
-                    response_headers.insert(name, value.clone());
-                }
-            }
+    let context = ForwardContext {
+        endpoint_type: EndpointType::Files,
+        method,
+        model: None,
+        amount: untyped_price(&snapshot, EndpointType::Files).await,
+        free_tier: false,
+        snapshot,
+        key_id: auth::current_key().map(|key| key.id),
+        batch_id: current_batch(),
+    };
 
-            let (tx, rx) = mpsc::channel::<Result<Vec<u8>, io::Error>>(100);
-            let mut stream = resp.bytes_stream();
+    forward_request_with_payment_and_upstream_body(
+        headers,
+        state,
+        with_query(endpoint_fn, query),
+        context,
+        body,
+        is_streaming,
+    )
+    .await
+    .into_response()
+}
 
-            tokio::spawn(async move {
-                while let Some(item) = stream.next().await {
-                    match item {
-                        Ok(chunk) => {
-                            if tx.send(Ok(chunk.to_vec())).await.is_err() {
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            let _ = tx
-                                .send(Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("Error reading from upstream: {}", e),
-                                )))
-                                .await;
-                            break;
-                        }
-                    }
-                }
-            });
+pub async fn forward_list_assistants(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    forward_assistants(&state, headers, Method::GET, String::new(), query, None).await
+}
 
-            let stream = ReceiverStream::new(rx);
+pub async fn forward_create_assistant(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let body = relay_request_body(&state, &headers, body);
+    forward_assistants(
+        &state,
+        headers,
+        Method::POST,
+        String::new(),
+        query,
+        Some(body),
+    )
+    .await
+}
 
-            let mapped_stream = stream.map(|result| {
-                result.map(|bytes| {
-                    let bytes: axum::body::Bytes = bytes.into();
-                    bytes
-                })
-            });
+pub async fn forward_retrieve_assistant(
+    Path(assistant_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let path = format!("/{}", assistant_id);
+    forward_assistants(&state, headers, Method::GET, path, query, None).await
+}
 
-            let body = Body::from_stream(mapped_stream);
+/// Assistants are modified with a partial body; OpenAI accepts both POST and
+/// PATCH for it, and the method is kept as the client sent it.
+pub async fn forward_modify_assistant(
+    Path(assistant_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let path = format!("/{}", assistant_id);
+    let body = relay_request_body(&state, &headers, body);
+    forward_assistants(&state, headers, method, path, query, Some(body)).await
+}
 
-            return response.body(body).unwrap_or_else(|e| {
-                eprintln!("Error creating streaming response: {}", e);
-                Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("Error creating streaming response"))
-                    .unwrap()
-            });
-        }
-        Err(error) => {
-            let error_json = Json(json!({
-                "error": {
-                    "message": format!("Error forwarding request: {}", error),
-                    "type": "gateway_error"
-                }
-            }));
+pub async fn forward_delete_assistant(
+    Path(assistant_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let path = format!("/{}", assistant_id);
+    forward_assistants(&state, headers, Method::DELETE, path, query, None).await
+}
 
-            (StatusCode::INTERNAL_SERVER_ERROR, error_json).into_response()
-        }
+async fn forward_assistants(
+    state: &AppState,
+    headers: HeaderMap,
+    method: Method,
+    path: String,
+    query: Option<String>,
+    body: Option<UpstreamBody>,
+) -> Response {
+    let snapshot = Snapshot::current(state);
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}/v1/assistants{}", base_endpoint, path) };
+
+    let context = ForwardContext {
+        endpoint_type: EndpointType::Passthrough,
+        method,
+        model: None,
+        amount: untyped_price(&snapshot, EndpointType::Passthrough).await,
+        free_tier: false,
+        snapshot,
+        key_id: auth::current_key().map(|key| key.id),
+        batch_id: current_batch(),
+    };
+
+    forward_request_with_payment_and_upstream_body(
+        headers,
+        state,
+        with_query(endpoint_fn, query),
+        context,
+        body,
+        false,
+    )
+    .await
+    .into_response()
+}
+
+/// Relays a body in the content type the client declared, JSON when it
+/// declared none.
+fn relay_request_body(state: &AppState, headers: &HeaderMap, body: Body) -> UpstreamBody {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_static("application/json"));
+    UpstreamBody::relay(content_type, headers, body, state.max_request_body_bytes)
+}
+
+/// Paths with a typed handler, `*` standing for one segment. The passthrough
+/// refuses them, so a variant spelling such as `/v1//chat/completions` cannot
+/// skip the pricing and model checks of the typed route.
+const TYPED_ROUTES: [&str; 18] = [
+    "/v1/chat/completions",
+    "/v1/chat/completions/batch",
+    "/v1/messages",
+    "/v1/completions",
+    "/v1/responses",
+    "/v1/models",
+    "/v1/models/*",
+    "/v1/embeddings",
+    "/v1/moderations",
+    "/v1/rerank",
+    "/v1/files",
+    "/v1/files/*",
+    "/v1/files/*/content",
+    "/v1/images/generations",
+    "/v1/audio/speech",
+    "/v1/audio/transcriptions",
+    "/v1/assistants",
+    "/v1/assistants/*",
+];
+
+/// Proxies any `/v1/*` path without a typed handler, keeping the method, query
+/// string and body as the client sent them. The path is relayed with empty
+/// segments dropped, and a JSON body naming a `model` goes through the same
+/// alias, allowlist and token ceiling rules as the typed routes; its `n` is
+/// clamped and priced per choice.
+pub async fn forward_passthrough(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let snapshot = Snapshot::current(&state);
+    let path = match passthrough_path(uri.path()) {
+        Ok(path) => path,
+        Err(e) => return e.into_response(),
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let endpoint_fn =
+        move |base_endpoint: &str| -> String { format!("{}{}", base_endpoint, path_and_query) };
+
+    let is_streaming = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("text/event-stream"));
+
+    let mut model = None;
+    let mut amount = untyped_price(&snapshot, EndpointType::Passthrough).await;
+    let upstream_body = if !has_request_body(&method, &headers) {
+        None
+    } else if extract::is_json(&headers) {
+        let mut value = match read_json_body(&state, body).await {
+            Ok(value) => value,
+            Err(e) => return e.into_response(),
+        };
+        if let Some(object) = value.as_object_mut() {
+            if let Some(Value::String(requested)) = object.get_mut("model") {
+                let policy = &snapshot.policy;
+                policy.aliases.apply(requested);
+                if let Err(e) = policy.access.check(EndpointType::Passthrough, requested) {
+                    return e.into_response();
+                }
+                model = Some(requested.clone());
+            }
+            if let Some(model) = &model {
+                snapshot.policy.max_tokens.clamp_body(model, object);
+            }
+        }
+        amount = price_request(
+            snapshot.runtime.pricing.as_ref(),
+            EndpointType::Passthrough,
+            model.as_deref(),
+            DEFAULT_PAYMENT_AMOUNT,
+            &value,
+        )
+        .await;
+        if let Some(object) = value.as_object_mut()
+            && let Some(requested) = object.get("n").and_then(Value::as_u64)
+        {
+            let mut n = Some(u32::try_from(requested).unwrap_or(u32::MAX));
+            amount = scale_by_choices(amount, &mut n, snapshot.runtime.max_choices_per_request);
+            object.insert("n".to_string(), Value::from(n));
+        }
+        Some(UpstreamBody::json(value, false))
+    } else {
+        Some(relay_request_body(&state, &headers, body))
+    };
+
+    let context = ForwardContext {
+        endpoint_type: EndpointType::Passthrough,
+        method,
+        model,
+        amount,
+        free_tier: false,
+        snapshot,
+        key_id: auth::current_key().map(|key| key.id),
+        batch_id: current_batch(),
+    };
+
+    let response = forward_request_with_payment_and_upstream_body(
+        headers,
+        &state,
+        endpoint_fn,
+        context,
+        upstream_body,
+        is_streaming,
+    )
+    .await;
+
+    response.into_response()
+}
+
+/// The path the passthrough relays: repeated and trailing slashes dropped,
+/// and dot segments, encoded separators and typed routes refused.
+fn passthrough_path(path: &str) -> Result<String, ForwardError> {
+    let mut segments = Vec::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let lowered = segment.to_ascii_lowercase();
+        let decoded = lowered.replace("%2e", ".");
+        if decoded == "."
+            || decoded == ".."
+            || lowered.contains("%2f")
+            || lowered.contains("%5c")
+            || segment.contains('\\')
+        {
+            return Err(ForwardError::InvalidRequest(format!(
+                "Path `{}` is not relayed: it contains a relative or encoded segment",
+                path
+            )));
+        }
+        segments.push(segment);
+    }
+    let normalized = format!("/{}", segments.join("/"));
+
+    let lowered = normalized.to_ascii_lowercase();
+    let typed = TYPED_ROUTES.iter().find(|route| {
+        let mut route = route.split('/');
+        let mut path = lowered.split('/');
+        loop {
+            match (route.next(), path.next()) {
+                (None, None) => return true,
+                (Some("*"), Some(_)) => {}
+                (Some(expected), Some(actual)) if expected == actual => {}
+                _ => return false,
+            }
+        }
+    });
+    if let Some(route) = typed {
+        return Err(ForwardError::InvalidRequest(format!(
+            "Path `{}` is served by `{}`; call that route directly",
+            path, route
+        )));
+    }
+    Ok(normalized)
+}
+
+/// Buffers a JSON body up to the request body limit so its `model` can be checked.
+async fn read_json_body(state: &AppState, body: Body) -> Result<Value, ForwardError> {
+    let limit = state.max_request_body_bytes;
+    let bytes = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ForwardError::PayloadTooLarge { limit })?;
+    serde_json::from_slice(&bytes).map_err(|e| ForwardError::InvalidBody {
+        message: format!("Request body is not valid JSON: {}", e),
+        param: None,
+    })
+}
+
+fn has_request_body(method: &Method, headers: &HeaderMap) -> bool {
+    if method == Method::GET || method == Method::HEAD {
+        return false;
+    }
+
+    headers.contains_key(header::TRANSFER_ENCODING)
+        || headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length > 0)
+}
+
+pub async fn delete_model(
+    Path(model_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let model_endpoint =
+        move |endpoint: &str| -> String { format!("{}/v1/models/{}", endpoint, model_id) };
+
+    forward_request_with_payment(headers, &state, Method::DELETE, model_endpoint)
+        .await
+        .into_response()
+}
+
+pub async fn get_specific_model(
+    Path(model_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(cache_query): Query<CacheQuery>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let model_endpoint =
+        move |endpoint: &str| -> String { format!("{}/v1/models/{}", endpoint, model_id) };
+
+    let bypass_cache = cache_query.refresh || requests_no_cache(&headers);
+    let response = forward_cached_request_with_payment(
+        headers,
+        &state,
+        with_query(model_endpoint, query),
+        bypass_cache,
+    )
+    .await;
+    response.into_response()
+}
+
+/// Serves a paid GET request from the models cache when possible, otherwise
+/// forwards it and caches a successful response. Concurrent misses for the
+/// same upstream URL share a single upstream request and payment.
+pub async fn forward_cached_request_with_payment(
+    original_headers: HeaderMap,
+    state: &AppState,
+    endpoint_fn: impl Fn(&str) -> String,
+    bypass_cache: bool,
+) -> Response<Body> {
+    let server_config = match resolve_server_config(state, &original_headers).await {
+        Ok(server_config) => server_config,
+        Err(ForwardError::ConfigMissing) => {
+            return forward_request_with_payment(original_headers, state, Method::GET, endpoint_fn)
+                .await
+                .into_response();
+        }
+        Err(e) => return e.into_response(),
+    };
+    // Answers are cached per paying wallet, so no tenant is served one another paid for.
+    let cache_key = format!(
+        "{} {}",
+        state.wallets.for_caller().base_url(),
+        endpoint_fn(&server_config.endpoint)
+    );
+
+    if !bypass_cache && let Some(cached) = state.models_cache.get(&cache_key).await {
+        telemetry::record_cache_hit();
+        return unpaid(cached, &server_config.change_header).into_response();
+    }
+    telemetry::record_cache_miss();
+
+    let mut led = false;
+    let response = state
+        .models_in_flight
+        .run(&cache_key, || {
+            led = true;
+            async {
+                let response = forward_request_with_payment(
+                    original_headers,
+                    state,
+                    Method::GET,
+                    &endpoint_fn,
+                )
+                .await
+                .into_response();
+                let buffered = buffer_response(response).await;
+                if buffered.status.is_success() {
+                    state
+                        .models_cache
+                        .insert(cache_key.clone(), buffered.clone())
+                        .await;
+                }
+                buffered
+            }
+        })
+        .await;
+
+    // Only the request that led the upstream call paid for the answer.
+    if led {
+        response.into_response()
+    } else {
+        unpaid(response, &server_config.change_header).into_response()
+    }
+}
+
+/// A shared answer for a caller that paid nothing for it: the cost reads 0
+/// and the upstream's change token, already received, is left out.
+fn unpaid(mut response: CachedResponse, change_header: &str) -> CachedResponse {
+    response.headers.insert(COST_HEADER, HeaderValue::from(0));
+    response.headers.remove(change_header);
+    response
+}
+
+async fn buffer_response(response: Response<Body>) -> CachedResponse {
+    let (parts, body) = response.into_parts();
+    match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => CachedResponse::new(parts.status, parts.headers, bytes),
+        Err(e) => {
+            error!(error = %e, "failed to read upstream response");
+            let error_json = json!({
+                "error": {
+                    "message": format!("Error reading from upstream: {}", e),
+                    "type": "gateway_error"
+                }
+            });
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            CachedResponse::new(
+                StatusCode::BAD_GATEWAY,
+                headers,
+                Bytes::from(error_json.to_string()),
+            )
+        }
+    }
+}
+
+/// Appends the client's query string to the upstream URL, dropping the
+/// gateway's own `refresh` parameter.
+fn with_query(
+    endpoint_fn: impl Fn(&str) -> String,
+    query: Option<String>,
+) -> impl Fn(&str) -> String {
+    let query = query.map(|query| {
+        query
+            .split('&')
+            .filter(|param| !param.is_empty() && param.split('=').next() != Some("refresh"))
+            .collect::<Vec<_>>()
+            .join("&")
+    });
+
+    move |base_endpoint: &str| -> String {
+        match query.as_deref() {
+            Some(query) if !query.is_empty() => {
+                format!("{}?{}", endpoint_fn(base_endpoint), query)
+            }
+            _ => endpoint_fn(base_endpoint),
+        }
+    }
+}
+
+fn requests_no_cache(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("no-cache"))
+}
+
+pub async fn forward_request_with_payment(
+    original_headers: HeaderMap,
+    state: &AppState,
+    method: Method,
+    endpoint_fn: impl Fn(&str) -> String,
+) -> Result<Response<Body>, ForwardError> {
+    let snapshot = Snapshot::current(state);
+    let amount = untyped_price(&snapshot, EndpointType::Models).await;
+    forward_request_with_payment_with_body(
+        original_headers,
+        state,
+        snapshot,
+        method,
+        endpoint_fn,
+        EndpointType::Models,
+        fixed_payment_amount(amount),
+        None::<serde_json::Value>, // Use Value as a placeholder type
+        false,
+    )
+    .await
+}
+
+/// Prices a request no typed handler reads, by its endpoint alone, under the
+/// snapshot's strategy.
+async fn untyped_price(snapshot: &Snapshot, endpoint: EndpointType) -> i64 {
+    let request = RequestMeta {
+        model: None,
+        base_price: DEFAULT_PAYMENT_AMOUNT,
+        body: None,
+    };
+    snapshot.runtime.pricing.price(endpoint, &request).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn forward_request_with_payment_with_body<T: serde::Serialize>(
+    original_headers: HeaderMap,
+    state: &AppState,
+    snapshot: Snapshot,
+    method: Method,
+    endpoint_fn: impl Fn(&str) -> String,
+    endpoint_type: EndpointType,
+    payment_amount: impl PaymentAmount<T>,
+    body: Option<T>,
+    is_streaming: bool,
+) -> Result<Response<Body>, ForwardError> {
+    let amount = payment_amount.resolve(endpoint_type, body.as_ref());
+
+    let body_json = body
+        .map(|body_data| serde_json::to_value(&body_data))
+        .transpose()?;
+
+    let context = ForwardContext {
+        endpoint_type,
+        method,
+        model: body_json
+            .as_ref()
+            .and_then(|value| value.get("model"))
+            .and_then(|model| model.as_str())
+            .map(str::to_string),
+        amount,
+        free_tier: false,
+        snapshot,
+        key_id: auth::current_key().map(|key| key.id),
+        batch_id: current_batch(),
+    };
+    let streams_request = state.stream_request_body_bytes.is_some_and(|threshold| {
+        request_body_length(&original_headers).is_some_and(|length| length >= threshold)
+    });
+    let upstream_body = body_json.map(|value| UpstreamBody::json(value, streams_request));
+
+    forward_request_with_payment_and_upstream_body(
+        original_headers,
+        state,
+        endpoint_fn,
+        context,
+        upstream_body,
+        is_streaming,
+    )
+    .await
+}
+
+/// What a forwarded request is for and what it pays, as recorded in the spend ledger.
+pub struct ForwardContext {
+    pub endpoint_type: EndpointType,
+    pub method: Method,
+    pub model: Option<String>,
+    pub amount: i64,
+    /// Set when the model is on the free tier and the request goes out unpaid.
+    pub free_tier: bool,
+    pub snapshot: Snapshot,
+    /// The client key the request authenticated with, recorded with its spend.
+    pub key_id: Option<String>,
+    /// The batch the request is an item of, tagging its ledger entry.
+    pub batch_id: Option<String>,
+}
+
+impl ForwardContext {
+    fn spend_entry(
+        &self,
+        status: StatusCode,
+        sent: i64,
+        change: Option<i64>,
+        started: Instant,
+        usage: Option<TokenUsage>,
+    ) -> SpendLedgerEntry {
+        SpendLedgerEntry {
+            endpoint: self.endpoint_type.as_str().to_string(),
+            model: self.model.clone(),
+            sats_sent: sent,
+            sats_change: change.unwrap_or(0),
+            status: i32::from(status.as_u16()),
+            latency_ms: started.elapsed().as_millis() as i64,
+            prompt_tokens: usage.map(|usage| usage.prompt_tokens),
+            completion_tokens: usage.map(|usage| usage.completion_tokens),
+            free_tier: self.free_tier,
+            key_id: self.key_id.clone(),
+            batch_id: self.batch_id.clone(),
+        }
+    }
+}
+
+/// A request body sent verbatim to the upstream, together with its content type.
+pub struct UpstreamBody {
+    pub content_type: HeaderValue,
+    pub body: reqwest::Body,
+    /// Sent upstream for streamed bodies, whose length reqwest cannot know.
+    pub content_length: Option<u64>,
+}
+
+/// Size of the pieces a streamed JSON body is written in.
+const STREAMED_BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+impl UpstreamBody {
+    /// Serializes `value` up front, or with `stream` writes it out in chunks
+    /// as the upstream reads them so the whole encoding is never held at once.
+    /// A streamed body cannot be replayed, so it gets no payment retry,
+    /// rate-limit retry or failover.
+    pub fn json(value: serde_json::Value, stream: bool) -> Self {
+        let body = if stream {
+            let (tx, rx) = mpsc::channel::<Result<Bytes, io::Error>>(4);
+            tokio::task::spawn_blocking(move || {
+                let mut writer = ChunkWriter {
+                    tx,
+                    buffer: Vec::with_capacity(STREAMED_BODY_CHUNK_BYTES),
+                };
+                let result = serde_json::to_writer(&mut writer, &value)
+                    .map_err(io::Error::from)
+                    .and_then(|_| writer.flush_chunk());
+                if let Err(e) = result {
+                    let _ = writer.tx.blocking_send(Err(e));
+                }
+            });
+            reqwest::Body::wrap_stream(ReceiverStream::new(rx))
+        } else {
+            reqwest::Body::from(value.to_string())
+        };
+        Self {
+            content_type: HeaderValue::from_static("application/json"),
+            body,
+            content_length: None,
+        }
+    }
+
+    /// Relays the client's body as it arrives, so an upload of any size is
+    /// held in memory a chunk at a time. The stream fails once more than
+    /// `limit` bytes have been read, whatever length the client declared.
+    pub fn relay(content_type: HeaderValue, headers: &HeaderMap, body: Body, limit: usize) -> Self {
+        let mut remaining = limit;
+        let chunks = body.into_data_stream().map(move |chunk| {
+            let chunk = chunk.map_err(io::Error::other)?;
+            remaining = remaining.checked_sub(chunk.len()).ok_or_else(|| {
+                io::Error::other(format!("Request body exceeds the {} byte limit", limit))
+            })?;
+            Ok::<_, io::Error>(chunk)
+        });
+        Self {
+            content_type,
+            body: reqwest::Body::wrap_stream(chunks),
+            content_length: request_body_length(headers),
+        }
+    }
+}
+
+/// Hands serialized JSON to the body stream a chunk at a time.
+struct ChunkWriter {
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(STREAMED_BODY_CHUNK_BYTES),
+        ));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upstream stopped reading"))
+    }
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= STREAMED_BODY_CHUNK_BYTES {
+            self.flush_chunk()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_chunk()
+    }
+}
+
+/// The client's declared body size, when it sent one.
+fn request_body_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+#[instrument(
+    skip_all,
+    fields(endpoint = tracing::field::Empty, streaming = is_streaming, amount = context.amount)
+)]
+pub async fn forward_request_with_payment_and_upstream_body(
+    original_headers: HeaderMap,
+    state: &AppState,
+    endpoint_fn: impl Fn(&str) -> String,
+    mut context: ForwardContext,
+    body: Option<UpstreamBody>,
+    is_streaming: bool,
+) -> Result<Response<Body>, ForwardError> {
+    let in_flight = telemetry::track_in_flight(context.endpoint_type);
+    let runtime = context.snapshot.runtime.clone();
+    let server_config = resolve_server_config(state, &original_headers).await?;
+    let (timeout, deadline) = request_timeout(&runtime, &original_headers, is_streaming)?;
+
+    if let Some(model) = context.model.as_deref()
+        && context.snapshot.policy.is_free(model)
+    {
+        debug!(model, "free-tier model, forwarding without payment");
+        context.amount = 0;
+        context.free_tier = true;
+        Span::current().record("amount", 0);
+    }
+
+    let amount = context.amount;
+    if amount > runtime.max_sats_per_request {
+        return Err(ForwardError::SpendCapExceeded {
+            required: amount,
+            cap: runtime.max_sats_per_request,
+        });
+    }
+
+    let (credit_hold, payer) = if context.free_tier {
+        (None, Payer::Caller)
+    } else {
+        credits::reserve(&state.db, state.credit_mode, amount).await?
+    };
+    let wallet = match payer {
+        Payer::Shared => state.wallets.get(None),
+        Payer::Caller => state.wallets.for_caller(),
+    };
+    match wallet.balance().await {
+        _ if context.free_tier => {}
+        Ok(balance) if balance.balance < amount => {
+            warn!(
+                balance = balance.balance,
+                required = amount,
+                "wallet balance too low for request"
+            );
+            return Err(ForwardError::InsufficientBalance {
+                required: amount,
+                balance: balance.balance,
+            });
+        }
+        Ok(_) => {}
+        Err(e) => warn!(error = %e, "failed to check wallet balance before forwarding"),
+    }
+
+    let mint = select_mint(
+        state,
+        wallet.as_ref(),
+        &original_headers,
+        server_config.mint_url.as_deref(),
+    )
+    .await?;
+
+    let upstream = if is_streaming {
+        &state.streaming_upstream
+    } else {
+        &state.upstream
+    };
+    let endpoint_url = endpoint_fn(&server_config.endpoint);
+    Span::current().record("endpoint", endpoint_url.as_str());
+
+    let mut req_builder = state
+        .http_client
+        .request(context.method.clone(), endpoint_url)
+        .timeout(timeout);
+    if deadline {
+        req_builder = req_builder.header(DEADLINE_MS_HEADER, timeout.as_millis() as u64);
+    }
+
+    let content_type = match body {
+        Some(body_data) => {
+            if let Some(length) = body_data.content_length {
+                req_builder = req_builder.header(header::CONTENT_LENGTH, length);
+            }
+            req_builder = req_builder.body(body_data.body);
+            body_data.content_type
+        }
+        None => HeaderValue::from_static("application/json"),
+    };
+
+    if server_config.api_key.is_empty() {
+        debug!("no upstream api key configured, omitting Authorization header");
+    } else {
+        req_builder = req_builder.header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", server_config.api_key),
+        );
+    }
+    req_builder = req_builder.header(header::CONTENT_TYPE, content_type);
+
+    for (name, value) in state.forwarded_headers.filter(&original_headers) {
+        req_builder = req_builder.header(name, value);
+    }
+    if let Some(request_id) = request_id::from_headers(&original_headers) {
+        req_builder = req_builder.header(REQUEST_ID_HEADER, request_id);
+    }
+
+    if let Some(budget) = runtime.daily_budget_sats
+        && !context.free_tier
+    {
+        check_daily_budget(&state.db, budget, amount).await?;
+    }
+
+    let bulkhead_permit = match &state.bulkhead {
+        Some(bulkhead) => Some(bulkhead.acquire().await?),
+        None => None,
+    };
+
+    let mut pending_request = match req_builder.build() {
+        Ok(request) => Some(request),
+        Err(e) => {
+            error!(error = %e, "failed to build upstream request");
+            return Err(ForwardError::RequestBuild(e));
+        }
+    };
+
+    let payment = PaymentLayer::new(
+        wallet.clone(),
+        PaymentHeaders::from_names(&server_config.payment_header, &server_config.change_header),
+        amount,
+        runtime.max_retry_payment_sats,
+    )
+    .with_spend_cap(runtime.max_sats_per_request)
+    .with_l402(state.l402_enabled)
+    .with_mint(mint)
+    .with_dead_letters(state.db.clone())
+    .with_free_tier(context.free_tier)
+    .with_wallet_retry(state.wallet_retry);
+    let endpoints = server_config.endpoints();
+    let mut outcome = None;
+    let started = Instant::now();
+
+    let mut short_circuited = false;
+
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        let Some(mut request) = pending_request.take() else {
+            break;
+        };
+
+        if !state.circuit_breakers.allow(endpoint) {
+            debug!(endpoint = %endpoint, "circuit open, skipping endpoint");
+            short_circuited = true;
+            pending_request = Some(request);
+            continue;
+        }
+
+        if index > 0 {
+            match reqwest::Url::parse(&endpoint_fn(endpoint)) {
+                Ok(url) => *request.url_mut() = url,
+                Err(e) => {
+                    warn!(endpoint = %endpoint, error = %e, "skipping invalid fallback endpoint");
+                    pending_request = Some(request);
+                    continue;
+                }
+            }
+            info!(endpoint = %endpoint, "failing over to fallback endpoint");
+        }
+
+        if index + 1 < endpoints.len() {
+            pending_request = request.try_clone();
+        }
+
+        let attempt =
+            send_with_retries(state, upstream, &payment, request, context.endpoint_type).await;
+        let should_fail_over = match &attempt {
+            Ok(paid) => paid.response.status().is_server_error(),
+            Err(PaymentError::Upstream { error, .. }) => error.is_connect(),
+            Err(
+                PaymentError::Payment(_)
+                | PaymentError::InsufficientFunds { .. }
+                | PaymentError::SpendCap { .. },
+            ) => false,
+        };
+        match &attempt {
+            Ok(paid) if !paid.response.status().is_server_error() => {
+                state.circuit_breakers.record_success(endpoint)
+            }
+            Ok(_) | Err(PaymentError::Upstream { .. }) => {
+                state.circuit_breakers.record_failure(endpoint)
+            }
+            Err(_) => {}
+        }
+
+        outcome = Some(attempt);
+        if !should_fail_over {
+            break;
+        }
+    }
+
+    match outcome {
+        Some(Ok(paid)) => {
+            // Only the shared wallet is consolidated.
+            if paid.returned.is_some() && state.wallets.is_shared(wallet.as_ref()) {
+                state.consolidator.record_received();
+            }
+            let status = paid.response.status();
+            let headers = paid.response.headers().clone();
+            // Upstream errors are short JSON bodies, so they are buffered and passed
+            // through verbatim with their own content type even for streamed requests.
+            let streams_body = is_streaming && status.is_success();
+
+            let mut response = Response::builder().status(status);
+
+            if streams_body && !headers.contains_key(header::CONTENT_TYPE) {
+                response = response.header(header::CONTENT_TYPE, "text/event-stream");
+            }
+
+            let response_headers = response.headers_mut().unwrap();
+            for (name, value) in end_to_end_headers(&headers) {
+                // The body is re-chunked as it streams, so its length may not match.
+                if streams_body && name == header::CONTENT_LENGTH {
+                    continue;
+                }
+                response_headers.append(name, value.clone());
+            }
+            // Change is settled before the body is read, so the cost is known up front.
+            response_headers.insert(COST_HEADER, HeaderValue::from(paid.net_spent()));
+
+            if !streams_body {
+                if status.is_client_error() {
+                    debug!(status = %status, "passing upstream error body through");
+                }
+                let bytes = paid.response.bytes().await;
+                let usage = bytes
+                    .as_ref()
+                    .ok()
+                    .filter(|_| status.is_success())
+                    .and_then(|bytes| TokenUsage::from_body(bytes));
+                record_spend(
+                    &state.db,
+                    context.spend_entry(status, paid.sent, paid.returned, started, usage),
+                );
+                if let Some(hold) = credit_hold {
+                    hold.settle(paid.sent - paid.returned.unwrap_or(0)).await;
+                }
+                if let Some(alert) = &state.low_balance_alert {
+                    alert.check(wallet.clone());
+                }
+                if let Some(topup) = &state.auto_topup {
+                    topup.check(wallet.clone());
+                }
+
+                let body = match bytes {
+                    Ok(bytes) => Body::from(bytes),
+                    Err(e) => {
+                        error!(error = %e, "failed to read upstream response");
+                        return Err(ForwardError::UpstreamRead(e.into()));
+                    }
+                };
+
+                return Ok(response.body(body).unwrap_or_else(|e| {
+                    error!(error = %e, "failed to create response");
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("Error creating response"))
+                        .unwrap()
+                }));
+            }
+
+            let (tx, rx) = mpsc::channel::<Result<Bytes, io::Error>>(state.stream_channel_buffer);
+            let mut stream = paid.response.bytes_stream();
+            let db = state.db.clone();
+            let low_balance_alert = state.low_balance_alert.clone();
+            let auto_topup = state.auto_topup.clone();
+            let paying_wallet = wallet.clone();
+            let idle_timeout = runtime.stream_idle_timeout;
+            let stream_guard = state.active_streams.track();
+            let mut cancel = state.stream_cancels.register();
+            if let Ok(stream_id) = HeaderValue::from_str(&cancel.id) {
+                response_headers.insert(STREAM_ID_HEADER, stream_id);
+            }
+            let mut usage_tracker = state.track_stream_usage.then(UsageTracker::default);
+
+            tokio::spawn(
+                async move {
+                    // The upstream answered, so it has redeemed the token: there is
+                    // nothing left to reclaim when the stream is abandoned.
+                    let returned = paid.returned;
+                    loop {
+                        let item = tokio::select! {
+                            _ = tx.closed() => {
+                                // Dropping the stream closes the upstream connection.
+                                warn!("client disconnected, abandoning upstream stream");
+                                break;
+                            }
+                            _ = cancel.cancelled() => {
+                                warn!(stream_id = %cancel.id, "stream cancelled, abandoning upstream stream");
+                                break;
+                            }
+                            item = stream.next() => item,
+                            // Restarted for every chunk, so this bounds the gap between chunks.
+                            _ = tokio::time::sleep(idle_timeout) => {
+                                warn!(
+                                    idle_ms = idle_timeout.as_millis() as u64,
+                                    "upstream stream went idle, closing it"
+                                );
+                                let _ = tx
+                                    .send(Err(io::Error::new(
+                                        io::ErrorKind::TimedOut,
+                                        "Upstream stream idle timeout",
+                                    )))
+                                    .await;
+                                break;
+                            }
+                        };
+
+                        match item {
+                            Some(Ok(chunk)) => {
+                                if let Some(tracker) = usage_tracker.as_mut() {
+                                    tracker.observe(&chunk);
+                                }
+                                if tx.send(Ok(chunk)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Some(Err(e)) => {
+                                let kind = if e.is_timeout() {
+                                    warn!("upstream stream exceeded the request timeout");
+                                    io::ErrorKind::TimedOut
+                                } else {
+                                    io::ErrorKind::Other
+                                };
+                                let _ = tx
+                                    .send(Err(io::Error::new(
+                                        kind,
+                                        format!("Error reading from upstream: {}", e),
+                                    )))
+                                    .await;
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+
+                    record_spend(
+                        &db,
+                        context.spend_entry(
+                            status,
+                            paid.sent,
+                            returned,
+                            started,
+                            usage_tracker.and_then(|tracker| tracker.usage()),
+                        ),
+                    );
+                    if let Some(hold) = credit_hold {
+                        hold.settle(paid.sent - returned.unwrap_or(0)).await;
+                    }
+                    if let Some(alert) = low_balance_alert {
+                        alert.check(paying_wallet.clone());
+                    }
+                    if let Some(topup) = auto_topup {
+                        topup.check(paying_wallet);
+                    }
+                    drop(cancel);
+                    drop(bulkhead_permit);
+                    drop(stream_guard);
+                    drop(in_flight);
+                }
+                // Child of the request span, so it keeps the request ID on log lines
+                // written after the handler returns and times the stream on its own.
+                .instrument(info_span!("stream_response")),
+            );
+
+            let body = Body::from_stream(ReceiverStream::new(rx));
+
+            Ok(response.body(body).unwrap_or_else(|e| {
+                error!(error = %e, "failed to create streaming response");
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Error creating streaming response"))
+                    .unwrap()
+            }))
+        }
+        Some(Err(PaymentError::Payment(e))) => Err(ForwardError::Payment(e)),
+        Some(Err(PaymentError::InsufficientFunds { required })) => {
+            Err(ForwardError::InsufficientBalance {
+                required,
+                balance: wallet.balance().await.map_or(0, |balance| balance.balance),
+            })
+        }
+        Some(Err(PaymentError::SpendCap { required, cap })) => {
+            Err(ForwardError::SpendCapExceeded { required, cap })
+        }
+        Some(Err(PaymentError::Upstream { error, spent })) => {
+            // Settled rather than dropped, since the upstream may have kept the token.
+            if let Some(hold) = credit_hold {
+                hold.settle(spent).await;
+            }
+            Err(ForwardError::Upstream(error))
+        }
+        None if short_circuited => Err(ForwardError::CircuitOpen),
+        None => Err(ForwardError::NoUpstream),
+    }
+}
+
+/// Fails with a 429 when `amount` would push today's spend past `budget`.
+async fn check_daily_budget(db: &Pool, budget: i64, amount: i64) -> Result<(), ForwardError> {
+    let spent = match spent_today(db).await {
+        Ok(spent) => spent,
+        Err(e) => {
+            warn!(error = %e, "failed to read today's spend, skipping budget check");
+            return Ok(());
+        }
+    };
+    if spent + amount <= budget {
+        return Ok(());
+    }
+
+    let resets_at = (Utc::now().date_naive() + chrono::Days::new(1))
+        .and_time(NaiveTime::MIN)
+        .and_utc();
+    warn!(spent, budget, amount, "daily spend budget exhausted");
+    Err(ForwardError::DailyBudgetExhausted {
+        budget,
+        spent,
+        resets_at,
+    })
+}
+
+/// The server config named by the `X-Upstream` header, or the default one.
+async fn resolve_server_config(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<ServerConfigRecord, ForwardError> {
+    let Some(name) = headers
+        .get(UPSTREAM_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().trim())
+        .filter(|name| !name.is_empty())
+    else {
+        return match state.server_configs.default_config().await {
+            Ok(Some(config)) => Ok(config),
+            Ok(None) => Err(ForwardError::ConfigMissing),
+            Err(e) => {
+                warn!(error = %e, "failed to load server config");
+                Err(ForwardError::ConfigMissing)
+            }
+        };
+    };
+
+    match state.server_configs.config_by_name(name).await {
+        Ok(Some(config)) => Ok(config),
+        Ok(None) => Err(ForwardError::UnknownUpstream(name.to_string())),
+        Err(e) => {
+            warn!(upstream = name, error = %e, "failed to load server config");
+            Err(ForwardError::ConfigMissing)
+        }
+    }
+}
+
+/// Picks the mint to pay with: the client's `X-Mint-Url` if `wallet` can pay
+/// from it, then the mint the upstream accepts, then the wallet default. The
+/// shared wallet pays from the configured mints; a tenant wallet from the mints
+/// it holds a balance at.
+async fn select_mint(
+    state: &AppState,
+    wallet: &dyn PaymentWallet,
+    headers: &HeaderMap,
+    upstream_mint: Option<&str>,
+) -> Result<Option<String>, ForwardError> {
+    let Some(requested) = headers.get(MINT_HEADER) else {
+        return Ok(upstream_mint.map(str::to_string));
+    };
+
+    let requested = requested.to_str().unwrap_or_default().trim();
+    let requested = requested.trim_end_matches('/');
+    let mints = if state.wallets.is_shared(wallet) {
+        state.mints.clone()
+    } else {
+        match wallet.balance().await {
+            Ok(balance) => balance.mints.unwrap_or_default().into_keys().collect(),
+            Err(e) => {
+                warn!(error = %e, "failed to list the mints of the paying wallet");
+                Vec::new()
+            }
+        }
+    };
+    mints
+        .into_iter()
+        .find(|mint| mint.trim_end_matches('/') == requested)
+        .map(Some)
+        .ok_or_else(|| {
+            ForwardError::InvalidRequest(format!("Mint {} is not configured", requested))
+        })
+}
+
+/// The upstream timeout for a request, and whether it comes from a client
+/// deadline. A deadline that has already passed fails the request before
+/// anything is spent.
+fn request_timeout(
+    runtime: &RuntimeSettings,
+    headers: &HeaderMap,
+    is_streaming: bool,
+) -> Result<(Duration, bool), ForwardError> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    let requested = header(TIMEOUT_HEADER)
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Duration::from_millis);
+    let remaining = match (header(DEADLINE_MS_HEADER), header(DEADLINE_HEADER)) {
+        (Some(ms), _) => ms.parse::<i64>().ok(),
+        (None, Some(at)) => chrono::DateTime::parse_from_rfc3339(at)
+            .ok()
+            .map(|at| (at.with_timezone(&Utc) - Utc::now()).num_milliseconds()),
+        (None, None) => None,
+    };
+    let deadline = match remaining {
+        Some(ms) if ms <= 0 => return Err(ForwardError::DeadlineExceeded),
+        Some(ms) => Some(Duration::from_millis(ms as u64)),
+        None => None,
+    };
+
+    let timeout = match (requested, deadline) {
+        (Some(timeout), Some(deadline)) => timeout.min(deadline),
+        (Some(timeout), None) | (None, Some(timeout)) => timeout,
+        (None, None) if is_streaming => return Ok((runtime.streaming_timeout, false)),
+        (None, None) => return Ok((runtime.request_timeout, false)),
+    };
+    Ok((timeout.min(runtime.max_request_timeout), deadline.is_some()))
+}
+
+/// Sends a request to one upstream endpoint, retrying transient failures with
+/// exponential backoff and waiting out one short upstream `Retry-After`. Every
+/// attempt is paid with a freshly minted token.
+async fn send_with_retries(
+    state: &AppState,
+    upstream: &Arc<dyn UpstreamClient>,
+    payment: &PaymentLayer,
+    mut request: reqwest::Request,
+    endpoint_type: EndpointType,
+) -> Result<PaidResponse, PaymentError> {
+    let mut attempt = 0;
+    let mut waited_for_rate_limit = false;
+
+    loop {
+        let next_request = request.try_clone();
+
+        let result = send_with_payment(upstream, payment, request, endpoint_type).await;
+
+        let Some(next_request) = next_request else {
+            return result;
+        };
+
+        let delay = match rate_limit_wait(&result, state.max_retry_after) {
+            Some(wait) if !waited_for_rate_limit => {
+                waited_for_rate_limit = true;
+                info!(
+                    wait_ms = wait.as_millis() as u64,
+                    "upstream rate limited, waiting before retrying"
+                );
+                wait
+            }
+            _ if attempt < state.upstream_retries && is_transient_failure(&result) => {
+                let delay = backoff_delay(state.upstream_retry_backoff, attempt);
+                warn!(
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "transient upstream failure, retrying"
+                );
+                attempt += 1;
+                delay
+            }
+            _ => return result,
+        };
+        tokio::time::sleep(delay).await;
+
+        request = next_request;
+    }
+}
+
+/// How long a 429 asks us to wait, if it is no longer than `max_wait`.
+fn rate_limit_wait(
+    result: &Result<PaidResponse, PaymentError>,
+    max_wait: Duration,
+) -> Option<Duration> {
+    let Ok(paid) = result else {
+        return None;
+    };
+    if paid.response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+
+    let retry_after = paid
+        .response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    let wait = match retry_after.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(retry_after).ok()?;
+            (at.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+        }
+    };
+
+    (wait <= max_wait).then_some(wait)
+}
+
+fn is_transient_failure(result: &Result<PaidResponse, PaymentError>) -> bool {
+    match result {
+        Ok(paid) => matches!(
+            paid.response.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(PaymentError::Upstream { error, .. }) => error.is_connect(),
+        Err(
+            PaymentError::Payment(_)
+            | PaymentError::InsufficientFunds { .. }
+            | PaymentError::SpendCap { .. },
+        ) => false,
+    }
+}
+
+/// Doubles the base delay for every attempt and adds up to 50% random jitter.
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter_ms = rand::random_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter_ms)
+}
+
+/// Sends a single request to one upstream endpoint through the payment layer.
+async fn send_with_payment(
+    upstream: &Arc<dyn UpstreamClient>,
+    payment: &PaymentLayer,
+    request: reqwest::Request,
+    endpoint_type: EndpointType,
+) -> Result<PaidResponse, PaymentError> {
+    let started_at = Instant::now();
+    let result = ServiceBuilder::new()
+        .layer(payment.clone())
+        .service(UpstreamService::new(upstream.clone()))
+        .oneshot(request)
+        .await;
+
+    match &result {
+        Ok(paid) => telemetry::record_upstream_request(
+            endpoint_type,
+            paid.response.status().as_str(),
+            started_at.elapsed(),
+        ),
+        Err(PaymentError::Upstream { error, .. }) => {
+            let status = if error.is_timeout() {
+                "timeout"
+            } else {
+                "error"
+            };
+            telemetry::record_upstream_request(endpoint_type, status, started_at.elapsed());
+            error!(error = %error, "failed to forward request");
+        }
+        Err(
+            PaymentError::Payment(_)
+            | PaymentError::InsufficientFunds { .. }
+            | PaymentError::SpendCap { .. },
+        ) => {}
+    }
+
+    result
+}
+
+/// Which upstream redirects are followed. A followed redirect resends the
+/// request with its payment token and API key, so redirects to another host
+/// are never followed; the 3xx goes back to the client and the token is reclaimed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectMode {
+    /// No redirects are followed.
+    None,
+    /// Redirects within the same scheme, host and port are followed.
+    #[default]
+    SameHost,
+}
+
+/// Hops followed before a redirect chain is handed back as-is.
+const MAX_REDIRECTS: usize = 5;
+
+impl RedirectMode {
+    fn policy(self) -> reqwest::redirect::Policy {
+        reqwest::redirect::Policy::custom(move |attempt| {
+            let from = attempt.previous().last().cloned();
+            let same_host = from.as_ref().is_some_and(|from| {
+                from.scheme() == attempt.url().scheme()
+                    && from.host_str() == attempt.url().host_str()
+                    && from.port_or_known_default() == attempt.url().port_or_known_default()
+            });
+            let from = from.map(|from| from.to_string()).unwrap_or_default();
+            if self == RedirectMode::SameHost
+                && same_host
+                && attempt.previous().len() <= MAX_REDIRECTS
+            {
+                info!(from = %from, to = %attempt.url(), status = %attempt.status(), "following upstream redirect");
+                attempt.follow()
+            } else {
+                warn!(from = %from, to = %attempt.url(), status = %attempt.status(), "not following upstream redirect");
+                attempt.stop()
+            }
+        })
+    }
+}
+
+/// Compressed upstream responses are decoded as they arrive; reqwest drops
+/// their `Content-Encoding` and `Content-Length`, so the forwarded headers
+/// describe the decoded bytes. Encodings it cannot decode pass through as-is.
+pub fn build_http_client(redirects: RedirectMode) -> reqwest::Result<Client> {
+    Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .redirect(redirects.policy())
+        .build()
+}
+
+pub fn build_streaming_http_client(redirects: RedirectMode) -> reqwest::Result<Client> {
+    Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .pool_idle_timeout(None)
+        .redirect(redirects.policy())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::headers::HeaderAllowlist;
+    use crate::model_access::{MaxTokensCeiling, ModelAccess, ModelAliases, ModelPolicy};
+    use crate::payment::PRICE_HEADER;
+    use crate::pricing::{ImagePricing, PricingStrategyKind};
+    use crate::test_support::{
+        FixedServerConfigs, MockUpstream, MockWallet, app_state, body_bytes, body_json, header_str,
+        json_reply, post_json, runtime_settings, server_config, streamed_reply, unreachable_pool,
+        with_change,
+    };
+    use crate::upstream::UpstreamError;
+    use crate::wallets::WalletRegistry;
+    use axum::{
+        Router,
+        routing::{get, post},
+    };
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn forwards_a_chat_completion_and_reports_its_net_cost() {
+        let wallet = MockWallet::new(100);
+        let change_from = wallet.clone();
+        let upstream = MockUpstream::new(move |_| {
+            let reply = json_reply(StatusCode::OK, &json!({ "id": "chatcmpl-1" }));
+            with_change(reply, &change_from, 4)
+        });
+        let app = chat_app(app_state(upstream.clone(), wallet.clone()));
+
+        let response = app
+            .oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_str(&response, COST_HEADER), Some("6"));
+        assert_eq!(body_json(response).await["id"], "chatcmpl-1");
+        let sent = upstream.last_request();
+        assert_eq!(
+            sent.url.as_str(),
+            "http://upstream.test/v1/chat/completions"
+        );
+        assert_eq!(sent.header("authorization"), Some("Bearer upstream-key"));
+        assert_eq!(sent.header("x-payment-sats"), Some("cashuAmock0"));
+        assert_eq!(sent.json()["model"], "gpt-4o");
+        assert_eq!(wallet.current_balance(), 94);
+    }
+
+    fn chat_app(state: AppState) -> Router {
+        Router::new()
+            .route("/v1/chat/completions", post(forward_chat_completions))
+            .with_state(Arc::new(state))
+    }
+
+    fn chat_request() -> Value {
+        json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": "hi" }] })
+    }
+
+    #[tokio::test]
+    async fn answers_a_batch_item_by_item_under_one_batch_id() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "id": "chatcmpl-1" }));
+        let app = Router::new()
+            .route(
+                "/v1/chat/completions/batch",
+                post(forward_chat_completions_batch),
+            )
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+        let mut streamed = chat_request();
+        streamed["stream"] = json!(true);
+
+        let response = app
+            .oneshot(post_json(
+                "/v1/chat/completions/batch",
+                &json!([chat_request(), streamed]),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_str(&response, COST_HEADER), Some("10"));
+        let batch = body_json(response).await;
+        assert!(batch["id"].as_str().unwrap().starts_with("batch_"));
+        assert_eq!(batch["data"][0]["status"], 200);
+        assert_eq!(batch["data"][0]["body"]["id"], "chatcmpl-1");
+        assert_eq!(batch["data"][1]["status"], 400);
+        assert_eq!(batch["total_cost_sats"], 10);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tags_only_requests_forwarded_inside_a_batch() {
+        let inside = BATCH_ID
+            .scope("batch_1".to_string(), async { current_batch() })
+            .await;
+
+        assert_eq!(inside.as_deref(), Some("batch_1"));
+        assert_eq!(current_batch(), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_settings_a_request_arrived_with_across_a_reload() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let state = app_state(upstream.clone(), wallet.clone());
+        let snapshot = Snapshot::current(&state);
+        state.model_policy.replace(ModelPolicy {
+            free_models: ["gpt-4o".to_string()].into(),
+            ..ModelPolicy::default()
+        });
+        state.runtime.replace(RuntimeSettings {
+            max_sats_per_request: 1,
+            ..runtime_settings()
+        });
+
+        let response = forward_request_with_payment_with_body(
+            HeaderMap::new(),
+            &state,
+            snapshot,
+            Method::POST,
+            |base: &str| format!("{}/v1/chat/completions", base),
+            EndpointType::ChatCompletions,
+            fixed_payment_amount(7),
+            Some(chat_request()),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(wallet.sent(), vec![7]);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn restores_the_balance_when_the_upstream_fails() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "error": { "message": "boom" } }),
+        );
+        let app = chat_app(app_state(upstream, wallet.clone()));
+
+        let response = app
+            .oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(header_str(&response, COST_HEADER), Some("0"));
+        assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+        assert_eq!(wallet.current_balance(), 100);
+    }
+
+    #[tokio::test]
+    async fn takes_only_the_change_when_a_failed_upstream_returns_some() {
+        let wallet = MockWallet::new(100);
+        let change_from = wallet.clone();
+        let upstream = MockUpstream::new(move |_| {
+            let reply = json_reply(StatusCode::INTERNAL_SERVER_ERROR, &json!({}));
+            with_change(reply, &change_from, 10)
+        });
+        let app = chat_app(app_state(upstream, wallet.clone()));
+
+        app.oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        // The change refunds the whole payment, so the token itself is not reclaimed.
+        assert_eq!(wallet.received(), vec!["cashuAchange1"]);
+        assert_eq!(wallet.current_balance(), 100);
+    }
+
+    #[tokio::test]
+    async fn streams_a_legacy_completion_as_server_sent_events() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::new(|_| {
+            streamed_reply(&[
+                "data: {\"choices\":[{\"text\":\"hi\"}]}\n\n",
+                "data: [DONE]\n\n",
+            ])
+        });
+        let app = Router::new()
+            .route("/v1/completions", post(forward_completions))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet)));
+
+        let response = app
+            .oneshot(post_json(
+                "/v1/completions",
+                &json!({ "model": "gpt-3.5-turbo-instruct", "prompt": "hi", "stream": true }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_str(&response, "content-type"),
+            Some("text/event-stream")
+        );
+        let body = body_bytes(response).await;
+        assert!(body.ends_with(b"data: [DONE]\n\n"));
+        let sent = upstream.last_request();
+        assert_eq!(sent.url.path(), "/v1/completions");
+        assert_eq!(sent.json()["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn relays_split_tool_call_deltas_byte_for_byte() {
+        const CHUNKS: [&str; 4] = [
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"func",
+            "tion\":{\"arguments\":\"{\\\"city\\\":\"}}]}}]}\n",
+            "\ndata: {\"choices\":[],\"usage\":{\"prompt_tokens\":7,\"completion_tokens\":3}}\n\nda",
+            "ta: [DONE]\n\n",
+        ];
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::new(|_| streamed_reply(&CHUNKS));
+        let mut request = chat_request();
+        request["stream"] = json!(true);
+
+        let response = chat_app(app_state(upstream, wallet))
+            .oneshot(post_json("/v1/chat/completions", &request))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // Usage is read on the side; the bytes themselves are not re-framed.
+        assert_eq!(body_bytes(response).await, CHUNKS.concat().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_endpoint_when_one_refuses_the_connection() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::new(|request| match request.url.host_str() {
+            Some("primary.test") => Err(UpstreamError::Connect("connection refused".to_string())),
+            _ => json_reply(StatusCode::OK, &json!({ "id": "chatcmpl-1" })),
+        });
+        let state = AppState {
+            server_configs: Arc::new(FixedServerConfigs(vec![server_config(
+                "http://primary.test",
+                &["http://fallback.test"],
+            )])),
+            ..app_state(upstream.clone(), wallet.clone())
+        };
+
+        let response = chat_app(state)
+            .oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let hosts: Vec<_> = upstream
+            .requests()
+            .iter()
+            .map(|request| request.url.host_str().unwrap().to_string())
+            .collect();
+        assert_eq!(hosts, ["primary.test", "fallback.test"]);
+        // Each endpoint is paid with its own token; the refused one comes back.
+        assert_eq!(
+            upstream.last_request().header("x-payment-sats"),
+            Some("cashuAmock1")
+        );
+        assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+        assert_eq!(wallet.current_balance(), 90);
+    }
+
+    #[tokio::test]
+    async fn concurrent_model_lists_share_one_payment() {
+        let wallet = MockWallet::new(1000);
+        let change_from = wallet.clone();
+        let upstream = MockUpstream::delayed(Duration::from_millis(200), move |_| {
+            let reply = json_reply(StatusCode::OK, &json!({ "object": "list", "data": [] }));
+            with_change(reply, &change_from, 4)
+        });
+        let app = Router::new()
+            .route("/v1/models", get(forward_list_models))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+
+        let responses = join_all((0..50).map(|_| {
+            let request = axum::http::Request::get("/v1/models")
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        }))
+        .await;
+
+        let mut costs = Vec::new();
+        for response in responses {
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            costs.push(header_str(&response, COST_HEADER).unwrap().to_string());
+            if costs.last().unwrap() == "0" {
+                assert_eq!(header_str(&response, "x-change-sats"), None);
+            }
+        }
+        // The leader reports what it paid, net of change; everyone else paid nothing.
+        costs.sort();
+        assert_eq!(costs.pop().as_deref(), Some("6"));
+        assert!(costs.iter().all(|cost| cost == "0"));
+        assert_eq!(wallet.sent(), vec![10]);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn serves_a_cached_model_list_at_no_cost() {
+        let wallet = MockWallet::new(1000);
+        let change_from = wallet.clone();
+        let upstream = MockUpstream::new(move |_| {
+            let reply = json_reply(StatusCode::OK, &json!({ "object": "list", "data": [] }));
+            with_change(reply, &change_from, 4)
+        });
+        let app = Router::new()
+            .route("/v1/models", get(forward_list_models))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+        let list = || {
+            axum::http::Request::get("/v1/models")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let paid = app.clone().oneshot(list()).await.unwrap();
+        let cached = app.oneshot(list()).await.unwrap();
+
+        assert_eq!(header_str(&paid, COST_HEADER), Some("6"));
+        assert_eq!(cached.status(), StatusCode::OK);
+        assert_eq!(header_str(&cached, COST_HEADER), Some("0"));
+        assert_eq!(header_str(&cached, "x-change-sats"), None);
+        assert_eq!(wallet.sent(), vec![10]);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn never_serves_a_tenant_a_model_list_another_wallet_paid_for() {
+        let shared = MockWallet::new(1000);
+        let tenant = MockWallet::at("http://tenant-wallet", 1000);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "object": "list", "data": [] }));
+        let tenant_wallet = tenant.clone();
+        let state = AppState {
+            wallets: Arc::new(WalletRegistry::with_wallets(shared.clone(), move |_| {
+                tenant_wallet.clone()
+            })),
+            ..app_state(upstream.clone(), shared.clone())
+        };
+        let app = Router::new()
+            .route("/v1/models", get(forward_list_models))
+            .with_state(Arc::new(state));
+        let list = || {
+            axum::http::Request::get("/v1/models")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let tenant_key = auth::AuthenticatedKey {
+            id: "key_tenant".to_string(),
+            name: "tenant".to_string(),
+            rate_limit: None,
+            wallet_url: Some("http://tenant-wallet".to_string()),
+        };
+
+        let shared_paid = app.clone().oneshot(list()).await.unwrap();
+        let tenant_paid = auth::with_key(tenant_key.clone(), app.clone().oneshot(list()))
+            .await
+            .unwrap();
+        let tenant_cached = auth::with_key(tenant_key, app.oneshot(list()))
+            .await
+            .unwrap();
+
+        assert_eq!(header_str(&shared_paid, COST_HEADER), Some("10"));
+        assert_eq!(header_str(&tenant_paid, COST_HEADER), Some("10"));
+        assert_eq!(header_str(&tenant_cached, COST_HEADER), Some("0"));
+        assert_eq!(shared.sent(), vec![10]);
+        assert_eq!(tenant.sent(), vec![10]);
+        assert_eq!(upstream.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_request_priced_above_the_spend_cap_without_paying() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.runtime.replace(RuntimeSettings {
+            max_sats_per_request: 5,
+            ..runtime_settings()
+        });
+
+        let response = chat_app(state)
+            .oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let error = &body_json(response).await["error"];
+        assert_eq!(error["code"], "spend_cap_exceeded");
+        assert_eq!(
+            (error["required"].as_i64(), error["cap"].as_i64()),
+            (Some(10), Some(5))
+        );
+        assert!(wallet.sent().is_empty());
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_an_upstream_price_above_the_spend_cap() {
+        let wallet = MockWallet::new(1000);
+        let upstream = MockUpstream::new(|_| {
+            let mut reply = json_reply(StatusCode::PAYMENT_REQUIRED, &json!({}))?;
+            reply
+                .headers_mut()
+                .insert(PRICE_HEADER, HeaderValue::from(500));
+            Ok(reply)
+        });
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.runtime.replace(RuntimeSettings {
+            max_sats_per_request: 50,
+            ..runtime_settings()
+        });
+
+        let response = chat_app(state)
+            .oneshot(post_json("/v1/chat/completions", &chat_request()))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(
+            body_json(response).await["error"]["code"],
+            "spend_cap_exceeded"
+        );
+        // Only the first payment was minted, and it came back.
+        assert_eq!(wallet.sent(), vec![10]);
+        assert_eq!(upstream.requests().len(), 1);
+        assert_eq!(wallet.current_balance(), 1000);
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_moderation_request() {
+        let wallet = MockWallet::new(100);
+        let upstream_body = json!({
+            "id": "modr-1",
+            "model": "omni-moderation-latest",
+            "results": [{ "flagged": false, "categories": { "violence": false } }],
+        });
+        let upstream = MockUpstream::json(StatusCode::OK, upstream_body.clone());
+        let app = Router::new()
+            .route("/v1/moderations", post(forward_moderations))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+        let request = json!({
+            "input": ["first text", "second text"],
+            "model": "omni-moderation-latest",
+            "user": "user-1",
+        });
+
+        let response = app
+            .oneshot(post_json("/v1/moderations", &request))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_str(&response, COST_HEADER), Some("1"));
+        assert_eq!(body_json(response).await, upstream_body);
+        let sent = upstream.last_request();
+        assert_eq!(sent.url.path(), "/v1/moderations");
+        assert_eq!(sent.json(), request);
+        assert_eq!(wallet.sent(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn passes_the_query_string_to_the_upstream() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "data": [] }));
+        let app = Router::new()
+            .route("/v1/files", get(forward_list_files))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet)));
+
+        let request = axum::http::Request::get("/v1/files?limit=5&after=x")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            upstream.last_request().url.as_str(),
+            "http://upstream.test/v1/files?limit=5&after=x"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_only_allowlisted_client_headers() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let patterns = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let state = AppState {
+            forwarded_headers: HeaderAllowlist::new(
+                &patterns(&["accept", "user-agent", "openai-*", "x-forwarded-*"]),
+                &patterns(&["x-forwarded-for"]),
+            ),
+            ..app_state(upstream.clone(), wallet)
+        };
+        let mut request = post_json("/v1/chat/completions", &chat_request());
+        for (name, value) in [
+            ("authorization", "Bearer client-key"),
+            ("openai-organization", "org-1"),
+            ("openai-beta", "assistants=v2"),
+            ("user-agent", "sdk/1.0"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-for", "203.0.113.7"),
+            ("cookie", "session=1"),
+            ("x-internal-trace", "abc"),
+        ] {
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_static(value));
+        }
+
+        chat_app(state).oneshot(request).await.unwrap();
+
+        let sent = upstream.last_request();
+        assert_eq!(sent.header("openai-organization"), Some("org-1"));
+        assert_eq!(sent.header("openai-beta"), Some("assistants=v2"));
+        assert_eq!(sent.header("user-agent"), Some("sdk/1.0"));
+        assert_eq!(sent.header("x-forwarded-proto"), Some("https"));
+        // The gateway's own key replaces the client's.
+        assert_eq!(sent.header("authorization"), Some("Bearer upstream-key"));
+        for dropped in ["x-forwarded-for", "cookie", "x-internal-trace"] {
+            assert_eq!(sent.header(dropped), None, "{dropped} was forwarded");
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_rerank_request_priced_per_document() {
+        let wallet = MockWallet::new(100);
+        let upstream_body = json!({
+            "results": [
+                { "index": 2, "relevance_score": 0.91 },
+                { "index": 0, "relevance_score": 0.42 },
+            ],
+        });
+        let upstream = MockUpstream::json(StatusCode::OK, upstream_body.clone());
+        let app = Router::new()
+            .route("/v1/rerank", post(forward_rerank))
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+        let request = json!({
+            "model": "rerank-english-v3.0",
+            "query": "capital of France",
+            "documents": ["Berlin", { "text": "Madrid" }, "Paris"],
+            "top_n": 2,
+        });
+
+        let response = app
+            .oneshot(post_json("/v1/rerank", &request))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // Five sats for the request and one for each of the three documents.
+        assert_eq!(header_str(&response, COST_HEADER), Some("8"));
+        assert_eq!(body_json(response).await, upstream_body);
+        let sent = upstream.last_request();
+        assert_eq!(sent.url.path(), "/v1/rerank");
+        assert_eq!(sent.json(), request);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_moderation_without_a_model_once_the_endpoint_is_allowlisted() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "results": [] }));
+        let allow = HashMap::from([(
+            "moderations".to_string(),
+            vec!["omni-moderation-latest".to_string()],
+        )]);
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.model_policy.replace(ModelPolicy {
+            access: ModelAccess::new(&allow, &HashMap::new()),
+            ..ModelPolicy::default()
+        });
+        let app = Router::new()
+            .route("/v1/moderations", post(forward_moderations))
+            .with_state(Arc::new(state));
+
+        let unnamed = app
+            .clone()
+            .oneshot(post_json("/v1/moderations", &json!({ "input": "hi" })))
+            .await
+            .unwrap();
+        let named = app
+            .oneshot(post_json(
+                "/v1/moderations",
+                &json!({ "model": "omni-moderation-latest", "input": "hi" }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(unnamed.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(unnamed).await["error"]["param"], "model");
+        assert_eq!(named.status(), StatusCode::OK);
+        assert_eq!(wallet.sent(), vec![1]);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_denied_model_on_every_typed_endpoint() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let denied = |endpoint: &str| (endpoint.to_string(), vec!["banned".to_string()]);
+        let deny = HashMap::from([
+            denied("completions"),
+            denied("audio_speech"),
+            denied("moderations"),
+            denied("rerank"),
+        ]);
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.model_policy.replace(ModelPolicy {
+            access: ModelAccess::new(&HashMap::new(), &deny),
+            ..ModelPolicy::default()
+        });
+        let app = Router::new()
+            .route("/v1/completions", post(forward_completions))
+            .route("/v1/audio/speech", post(forward_audio_speech))
+            .route("/v1/moderations", post(forward_moderations))
+            .route("/v1/rerank", post(forward_rerank))
+            .with_state(Arc::new(state));
+
+        for (path, body) in [
+            (
+                "/v1/completions",
+                json!({ "model": "banned", "prompt": "hi" }),
+            ),
+            (
+                "/v1/audio/speech",
+                json!({ "model": "banned", "input": "hi", "voice": "alloy" }),
+            ),
+            (
+                "/v1/moderations",
+                json!({ "model": "banned", "input": "hi" }),
+            ),
+            (
+                "/v1/rerank",
+                json!({ "model": "banned", "query": "hi", "documents": ["a"] }),
+            ),
+        ] {
+            let response = app.clone().oneshot(post_json(path, &body)).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+            assert_eq!(
+                body_json(response).await["error"]["code"],
+                "model_not_found"
+            );
+        }
+        assert!(wallet.sent().is_empty());
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_denied_model_before_paying() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let deny = HashMap::from([(
+            "embeddings".to_string(),
+            vec!["text-embedding-3-large".to_string()],
+        )]);
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.model_policy.replace(ModelPolicy {
+            access: ModelAccess::new(&HashMap::new(), &deny),
+            ..ModelPolicy::default()
+        });
+        let app = Router::new()
+            .route("/v1/embeddings", post(forward_embeddings))
+            .with_state(Arc::new(state));
+
+        let denied = app
+            .clone()
+            .oneshot(post_json(
+                "/v1/embeddings",
+                &json!({ "model": "text-embedding-3-large", "input": ["hi"] }),
+            ))
+            .await
+            .unwrap();
+        let allowed = app
+            .oneshot(post_json(
+                "/v1/embeddings",
+                &json!({ "model": "text-embedding-3-small", "input": ["hi"] }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(denied.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_json(denied).await["error"]["code"], "model_not_found");
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(wallet.sent(), vec![10]);
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn times_out_a_slow_upstream_with_a_504_and_reclaims_the_token() {
+        for stream in [false, true] {
+            let wallet = MockWallet::new(100);
+            let upstream = MockUpstream::delayed(Duration::from_millis(500), |_| {
+                json_reply(StatusCode::OK, &json!({ "id": "chatcmpl-1" }))
+            });
+            let state = app_state(upstream, wallet.clone());
+            state.runtime.replace(RuntimeSettings {
+                request_timeout: Duration::from_millis(50),
+                streaming_timeout: Duration::from_millis(50),
+                ..runtime_settings()
+            });
+            let mut request = chat_request();
+            request["stream"] = json!(stream);
+
+            let response = chat_app(state)
+                .oneshot(post_json("/v1/chat/completions", &request))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                response.status(),
+                StatusCode::GATEWAY_TIMEOUT,
+                "stream: {stream}"
+            );
+            let error = body_json(response).await["error"].clone();
+            assert_eq!(error["type"], "gateway_error");
+            assert_eq!(error["code"], "upstream_timeout");
+            assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+            assert_eq!(wallet.current_balance(), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn prices_image_generations_by_size_quality_and_count() {
+        let prices = HashMap::from([
+            ("256x256".to_string(), 4),
+            ("1024x1024".to_string(), 20),
+            ("1024x1024:hd".to_string(), 40),
+        ]);
+        let cases = [
+            (json!({}), 10),
+            (json!({ "size": "256x256" }), 4),
+            (json!({ "size": "256x256", "quality": "hd" }), 4),
+            (json!({ "size": "1024x1024", "quality": "standard" }), 20),
+            (json!({ "size": "1024x1024", "quality": "hd" }), 40),
+            (json!({ "size": "1024x1024", "quality": "hd", "n": 2 }), 80),
+            // Clamped to the test state's four choices.
+            (json!({ "size": "256x256", "n": 9 }), 16),
+            (json!({ "size": "512x512" }), 10),
+        ];
+
+        for (options, expected) in cases {
+            let wallet = MockWallet::new(1000);
+            let upstream = MockUpstream::json(StatusCode::OK, json!({ "data": [] }));
+            let state = app_state(upstream, wallet.clone());
+            state.runtime.replace(RuntimeSettings {
+                image_pricing: ImagePricing::new(prices.clone()),
+                ..runtime_settings()
+            });
+            let app = Router::new()
+                .route("/v1/images/generations", post(forward_image_generations))
+                .with_state(Arc::new(state));
+            let mut request = json!({ "model": "dall-e-3", "prompt": "a lighthouse" });
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(options.as_object().unwrap().clone());
+
+            let response = app
+                .oneshot(post_json("/v1/images/generations", &request))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(wallet.sent(), vec![expected], "{options}");
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_a_paid_patch_to_an_assistant_with_its_partial_body() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(
+            StatusCode::OK,
+            json!({ "id": "asst_1", "object": "assistant", "name": "renamed" }),
+        );
+        let app = Router::new()
+            .route(
+                "/v1/assistants/{assistant_id}",
+                axum::routing::patch(forward_modify_assistant),
+            )
+            .with_state(Arc::new(app_state(upstream.clone(), wallet.clone())));
+        let update = json!({ "name": "renamed" });
+        let request = axum::http::Request::patch("/v1/assistants/asst_1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, update.to_string().len())
+            .body(Body::from(update.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["name"], "renamed");
+        let sent = upstream.last_request();
+        assert_eq!(sent.method, Method::PATCH);
+        assert_eq!(
+            sent.url.as_str(),
+            "http://upstream.test/v1/assistants/asst_1"
+        );
+        assert_eq!(sent.header("content-type"), Some("application/json"));
+        assert_eq!(sent.header("x-payment-sats"), Some("cashuAmock0"));
+        assert_eq!(sent.json(), update);
+        assert_eq!(wallet.sent().len(), 1);
+    }
+
+    fn passthrough_app(state: AppState) -> Router {
+        Router::new()
+            .route("/v1/{*path}", axum::routing::any(forward_passthrough))
+            .with_state(Arc::new(state))
+    }
+
+    #[tokio::test]
+    async fn relays_a_passthrough_path_without_empty_segments() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "id": "vs_1" }));
+        let app = passthrough_app(app_state(upstream.clone(), wallet));
+
+        let response = app
+            .oneshot(
+                axum::http::Request::get("/v1/vector_stores//vs_1/?limit=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            upstream.last_request().url.as_str(),
+            "http://upstream.test/v1/vector_stores/vs_1?limit=2"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_typed_and_relative_paths_on_the_passthrough() {
+        for path in [
+            "/v1//chat/completions",
+            "/v1/Chat/Completions",
+            "/v1/files/file_1/content/",
+            "/v1/threads/../chat/completions",
+            "/v1/threads/%2E%2e/chat/completions",
+            "/v1/threads%2fruns",
+        ] {
+            let wallet = MockWallet::new(100);
+            let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+            let app = passthrough_app(app_state(upstream.clone(), wallet.clone()));
+
+            let response = app.oneshot(post_json(path, &chat_request())).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+            assert!(upstream.requests().is_empty(), "{path} was relayed");
+            assert!(wallet.sent().is_empty(), "{path} was paid for");
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_model_aliases_and_rules_to_a_passthrough_body() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "id": "run_1" }));
+        let deny = HashMap::from([("passthrough".to_string(), vec!["gpt-4".to_string()])]);
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.model_policy.replace(ModelPolicy {
+            access: ModelAccess::new(&HashMap::new(), &deny),
+            aliases: ModelAliases::new(HashMap::from([(
+                "fast".to_string(),
+                "gpt-4o-mini".to_string(),
+            )])),
+            ..ModelPolicy::default()
+        });
+        let app = passthrough_app(state);
+        let run = |model: &str| {
+            let body = json!({ "assistant_id": "asst_1", "model": model }).to_string();
+            axum::http::Request::post("/v1/threads/thread_1/runs")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let denied = app.clone().oneshot(run("gpt-4")).await.unwrap();
+        let aliased = app.oneshot(run("fast")).await.unwrap();
+
+        assert_eq!(denied.status(), StatusCode::NOT_FOUND);
+        assert_eq!(aliased.status(), StatusCode::OK);
+        assert_eq!(upstream.requests().len(), 1);
+        assert_eq!(upstream.last_request().json()["model"], "gpt-4o-mini");
+        assert_eq!(wallet.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn caps_token_limits_and_choices_in_a_passthrough_body() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({ "id": "run_1" }));
+        let state = app_state(upstream.clone(), wallet.clone());
+        state.model_policy.replace(ModelPolicy {
+            max_tokens: MaxTokensCeiling::new(Some(100), HashMap::new()),
+            ..ModelPolicy::default()
+        });
+        let app = passthrough_app(state);
+        let run = |body: Value| {
+            let body = body.to_string();
+            axum::http::Request::post("/v1/threads/thread_1/runs")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let capped = app
+            .clone()
+            .oneshot(run(json!({
+                "model": "gpt-4o",
+                "max_completion_tokens": 5000,
+                "n": 9,
+            })))
+            .await
+            .unwrap();
+        let unset = app
+            .oneshot(run(json!({ "model": "gpt-4o" })))
+            .await
+            .unwrap();
+
+        assert_eq!(capped.status(), StatusCode::OK);
+        assert_eq!(unset.status(), StatusCode::OK);
+        let requests = upstream.requests();
+        assert_eq!(requests[0].json()["max_completion_tokens"], 100);
+        assert_eq!(requests[0].json()["n"], 4);
+        // A limit the client left out is not added to an unknown endpoint's body.
+        assert_eq!(requests[1].json(), json!({ "model": "gpt-4o" }));
+        // Four choices at the passthrough's ten sats, then one request at ten.
+        assert_eq!(wallet.sent(), vec![40, 10]);
+    }
+
+    #[tokio::test]
+    async fn prices_passthrough_requests_under_the_pricing_strategy() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+        let state = app_state(upstream, wallet.clone());
+        state.runtime.replace(RuntimeSettings {
+            pricing: PricingStrategyKind::TokenEstimate.build(unreachable_pool(), 3),
+            pricing_strategy: PricingStrategyKind::TokenEstimate,
+            token_estimate_sats_per_1k_tokens: 3,
+            ..runtime_settings()
+        });
+        let app = passthrough_app(state);
+        let body = json!({ "model": "gpt-4o", "input": "a".repeat(4000) }).to_string();
+
+        let with_body = app
+            .clone()
+            .oneshot(
+                axum::http::Request::post("/v1/threads/thread_1/runs")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let without_body = app
+            .oneshot(
+                axum::http::Request::get("/v1/threads/thread_1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(with_body.status(), StatusCode::OK);
+        assert_eq!(without_body.status(), StatusCode::OK);
+        // A thousand estimated tokens at three sats, then the base price for no body.
+        assert_eq!(wallet.sent(), vec![3, 10]);
+    }
+
+    /// A synthetic upload of `chunks` pieces of 64 KiB, generated only as it
+    /// is read, and the count of pieces generated so far.
+    fn synthetic_upload(chunks: usize) -> (Body, Arc<AtomicUsize>) {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let stream = futures::stream::iter(0..chunks).map(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, io::Error>(Bytes::from(vec![b'x'; 64 * 1024]))
+        });
+        (Body::from_stream(stream), produced)
+    }
+
+    #[tokio::test]
+    async fn relays_a_large_upload_a_chunk_at_a_time() {
+        // 32 MiB, read the way reqwest reads a request body.
+        let (body, produced) = synthetic_upload(512);
+        let relayed = UpstreamBody::relay(
+            HeaderValue::from_static("multipart/form-data; boundary=x"),
+            &HeaderMap::new(),
+            body,
+            64 * 1024 * 1024,
+        );
+
+        let mut chunks = Body::new(relayed.body).into_data_stream();
+        let (mut read, mut bytes, mut most_ahead) = (0, 0, 0);
+        while let Some(chunk) = chunks.next().await {
+            read += 1;
+            bytes += chunk.unwrap().len();
+            most_ahead = most_ahead.max(produced.load(Ordering::SeqCst) - read);
+        }
+
+        assert_eq!(bytes, 32 * 1024 * 1024);
+        // Nothing is generated before the upstream asks for it, so at most
+        // one chunk is ever held.
+        assert_eq!(most_ahead, 0);
+    }
+
+    #[tokio::test]
+    async fn stops_relaying_an_upload_past_the_limit() {
+        let (body, produced) = synthetic_upload(512);
+        let relayed = UpstreamBody::relay(
+            HeaderValue::from_static("multipart/form-data; boundary=x"),
+            &HeaderMap::new(),
+            body,
+            1024 * 1024,
+        );
+
+        let mut chunks = Body::new(relayed.body).into_data_stream();
+        let mut error = None;
+        while let Some(chunk) = chunks.next().await {
+            if let Err(e) = chunk {
+                // reqwest wraps the relay's error in its own body error.
+                let mut cause: &dyn std::error::Error = &e;
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+                error = Some(cause.to_string());
+                break;
+            }
+        }
+
+        let error = error.unwrap();
+        assert!(error.contains("exceeds the 1048576 byte limit"), "{error}");
+        assert_eq!(produced.load(Ordering::SeqCst), 17);
     }
 }
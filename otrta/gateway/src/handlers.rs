@@ -1,25 +1,54 @@
 use crate::{
+    auth::{self, generate_key, hash_key},
+    consolidation::ConsolidationError,
     db::{
         Pool,
-        server_config::{ServerConfigRecord, create_config, get_default_config, update_config},
+        client_api_keys::{create_key, delete_key, get_all_keys},
+        client_credits,
+        model_pricing::{delete_price, get_all_prices, upsert_price},
+        payment_dlq,
+        server_config::{
+            ServerConfigRecord, create_config, delete_config, get_all_configs, get_config_by_name,
+            get_default_config, update_config,
+        },
+        spend_ledger::{self, UsageScope},
+        wallet_topups,
     },
+    error::ForwardError,
     models::*,
+    prepare::prepare,
+    redact::{self, Redacted, redact},
+    reload,
+    settings::Snapshot,
+    topup::{self, TopupError},
 };
 use axum::{
     Json,
-    extract::State,
+    extract::{Path, Query, RawQuery, State},
     http::{HeaderMap, StatusCode},
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use chrono::Utc;
+use secrecy::ExposeSecret;
 use serde_json::{self, json};
-use std::sync::Arc;
-use wallet::{api::CashuWalletApi, models::ServerConfig};
+use std::{sync::Arc, time::Duration};
+use tracing::info;
+use wallet::{
+    api::CashuWalletApi,
+    models::{ServerConfig, default_change_header, default_payment_header},
+};
+
+const READINESS_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_USAGE_DAYS: i64 = 30;
+const MAX_USAGE_GROUPS: i64 = 500;
 
 pub async fn list_openai_models(
     State(state): State<Arc<AppState>>,
+    cache_query: Query<CacheQuery>,
+    raw_query: RawQuery,
     headers: HeaderMap,
 ) -> Response {
-    crate::forward::forward_list_models(State(state), headers).await
+    crate::forward::forward_list_models(State(state), cache_query, raw_query, headers).await
 }
 
 pub async fn redeem_token(
@@ -41,47 +70,670 @@ pub async fn redeem_token(
     })
 }
 
+/// Tops the wallet up from a Cashu token and reports the sats it added.
+pub async fn receive_token(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<Token>,
+) -> Response {
+    let token = payload.token.trim();
+    if !is_cashu_token(token) {
+        return wallet_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_token",
+            "Expected a Cashu token starting with cashuA or cashuB".to_string(),
+        );
+    }
+
+    match state.wallet.receive(Some(token), None, None).await {
+        Ok(response) => {
+            state.consolidator.record_received();
+            Json(ReceiveTokenResponse {
+                received: response.balance - response.initial_balance,
+                balance: response.balance,
+            })
+            .into_response()
+        }
+        Err(e) => wallet_error(
+            StatusCode::BAD_REQUEST,
+            "token_rejected",
+            format!("Token is invalid or already spent: {}", e),
+        ),
+    }
+}
+
+fn is_cashu_token(token: &str) -> bool {
+    token
+        .strip_prefix("cashuA")
+        .or_else(|| token.strip_prefix("cashuB"))
+        .is_some_and(|payload| !payload.is_empty() && payload.bytes().all(redact::is_token_byte))
+}
+
+fn wallet_error(status: StatusCode, code: &str, message: String) -> Response {
+    (
+        status,
+        Json(json!({
+            "error": {
+                "message": redact(&message),
+                "type": "wallet_error",
+                "code": code,
+            }
+        })),
+    )
+        .into_response()
+}
+
+/// Requests a mint quote and returns the bolt11 invoice to pay for it.
+pub async fn create_topup(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TopupRequest>,
+) -> Response {
+    if request.amount <= 0 {
+        return wallet_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_amount",
+            "Top-up amount must be positive".to_string(),
+        );
+    }
+    if let Some(mint) = &request.mint
+        && !state.mints.contains(mint)
+    {
+        return wallet_error(
+            StatusCode::BAD_REQUEST,
+            "unknown_mint",
+            format!("Mint {} is not configured", mint),
+        );
+    }
+
+    match topup::request_topup(
+        &state.wallet,
+        &state.db,
+        request.amount,
+        request.mint.as_deref(),
+        None,
+    )
+    .await
+    {
+        Ok(topup) => (
+            StatusCode::CREATED,
+            Json(topup.to_model(TopupStatus::Pending)),
+        )
+            .into_response(),
+        Err(e) => topup_error(e),
+    }
+}
+
+fn topup_error(error: TopupError) -> Response {
+    match error {
+        TopupError::QuoteFailed(message) => {
+            wallet_error(StatusCode::BAD_GATEWAY, "mint_quote_failed", message)
+        }
+        TopupError::Wallet(e) => wallet_error(
+            StatusCode::BAD_GATEWAY,
+            "wallet_unavailable",
+            format!("Wallet request failed: {}", e),
+        ),
+        TopupError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Checks whether a top-up invoice was paid; the wallet mints the tokens as
+/// part of that check. Safe to poll.
+pub async fn claim_topup(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    let topup = match wallet_topups::get_topup(&state.db, &id).await {
+        Ok(Some(topup)) => topup,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let status = match topup::claim_topup(&state.wallet, &state.db, &topup).await {
+        Ok(status) => status,
+        Err(e) => return topup_error(e),
+    };
+
+    match wallet_topups::get_topup(&state.db, &id).await {
+        Ok(Some(topup)) => Json(topup.to_model(status)).into_response(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Payment tokens still waiting to be reclaimed by the dead-letter worker.
+pub async fn list_dead_letters(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+    let entries = payment_dlq::get_outstanding(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(entries.iter().map(|e| e.to_model()).collect()))
+}
+
 pub async fn get_balance(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
     Json(json!({"balance": state.wallet.balance().await.unwrap().balance.to_string()}))
 }
 
+/// The balance of the wallet the caller pays from.
+pub async fn get_wallet_balance(State(state): State<Arc<AppState>>) -> Response {
+    match state.wallets.for_caller().balance().await {
+        Ok(balance) => Json(json!({"balance": balance.balance, "unit": "sat"})).into_response(),
+        Err(e) => (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "error": {
+                    "message": format!("Failed to query wallet balance: {}", Redacted(&e)),
+                    "type": "wallet_error",
+                }
+            })),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn consolidate_wallet(State(state): State<Arc<AppState>>) -> Response {
+    match state.consolidator.consolidate().await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (
+            match e {
+                ConsolidationError::AlreadyRunning => StatusCode::CONFLICT,
+                ConsolidationError::Wallet(_) => StatusCode::BAD_GATEWAY,
+            },
+            Json(json!({
+                "error": {
+                    "message": format!("Failed to consolidate wallet: {}", Redacted(&e)),
+                    "type": "wallet_error",
+                }
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// Stops a stream started by the caller, named by its `X-Stream-Id`. The
+/// upstream connection is closed; the payment was already redeemed upstream.
+pub async fn cancel_request(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    if state.stream_cancels.cancel(&id) {
+        info!(stream_id = %id, "stream cancelled on request");
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Applies configuration changes now instead of at the next periodic reload.
+pub async fn reload_config(State(state): State<Arc<AppState>>) -> Response {
+    match reload::reload(&state) {
+        Ok(report) => {
+            info!(?report, "configuration reloaded on request");
+            Json(report).into_response()
+        }
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": {
+                    "message": format!("Failed to reload configuration: {}", e),
+                    "type": "configuration_error",
+                }
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// Which build is running, for telling deployments apart.
+pub async fn version() -> Json<serde_json::Value> {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|built_at| built_at.to_rfc3339());
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT_HASH"),
+        "built_at": built_at,
+    }))
+}
+
+/// Liveness: answers as long as the process is serving requests.
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(json!({"status": "ok"}))
+}
+
+/// Readiness: whether the gateway can serve paid requests right now. Answers
+/// 503 with a per-dependency breakdown when any of them is down.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> Response {
+    let config = get_server_config(&state.db).await;
+    let wallet = state.wallet.balance().await;
+
+    let mut checks = serde_json::Map::new();
+    checks.insert(
+        "server_config".to_string(),
+        dependency_check(config.as_ref().ok_or("no server config".to_string())),
+    );
+    checks.insert(
+        "wallet".to_string(),
+        dependency_check(wallet.as_ref().map_err(|e| e.to_string())),
+    );
+    if state.readiness_check_upstream {
+        let upstream = match &config {
+            Some(config) => ping_upstream(&state, config).await,
+            None => Err("no server config".to_string()),
+        };
+        checks.insert("upstream".to_string(), dependency_check(upstream.as_ref()));
+    }
+
+    let ready = checks.values().all(|check| check["status"] == "ok");
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ok" } else { "unavailable" },
+            "checks": checks,
+        })),
+    )
+        .into_response()
+}
+
+fn dependency_check<T>(result: Result<T, impl std::fmt::Display>) -> serde_json::Value {
+    match result {
+        Ok(_) => json!({"status": "ok"}),
+        Err(e) => json!({"status": "error", "error": e.to_string()}),
+    }
+}
+
+/// Any answer short of a server error counts; the models list may well be
+/// behind a paywall, and a 402 still shows the upstream is up.
+async fn ping_upstream(state: &AppState, config: &ServerConfigRecord) -> Result<(), String> {
+    let mut request = state
+        .http_client
+        .get(format!("{}/v1/models", config.endpoint))
+        .timeout(READINESS_UPSTREAM_TIMEOUT);
+    // An empty key would go out as a bare `Bearer`, which some upstreams reject.
+    if !config.api_key.is_empty() {
+        request = request.bearer_auth(&config.api_key);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+
+    if response.status().is_server_error() {
+        return Err(format!("upstream answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// The caller's own spend over a time range, grouped by model, endpoint or day.
+pub async fn get_usage(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    let key = auth::current_key();
+    let scope = UsageScope::Key(key.as_ref().map(|key| key.id.as_str()));
+    usage_report(&state, query, scope).await
+}
+
+/// Spend across every client key, for the operator.
+pub async fn get_all_usage(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    usage_report(&state, query, UsageScope::All).await
+}
+
+async fn usage_report(state: &AppState, query: UsageQuery, scope: UsageScope<'_>) -> Response {
+    let end = query.end.unwrap_or_else(Utc::now);
+    let start = query
+        .start
+        .unwrap_or_else(|| end - chrono::Duration::days(DEFAULT_USAGE_DAYS));
+    let limit = query
+        .limit
+        .unwrap_or(MAX_USAGE_GROUPS)
+        .clamp(1, MAX_USAGE_GROUPS);
+    let offset = query.offset.unwrap_or(0).max(0);
+    if start >= end {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": {
+                    "message": "start must be before end",
+                    "type": "invalid_request_error",
+                    "param": "start",
+                }
+            })),
+        )
+            .into_response();
+    }
+
+    let totals = spend_ledger::usage_totals(&state.db, scope, start, end).await;
+    // One extra row tells whether another page follows.
+    let groups = spend_ledger::usage_by(
+        &state.db,
+        scope,
+        query.group_by,
+        start,
+        end,
+        limit + 1,
+        offset,
+    )
+    .await;
+    let (totals, mut groups) = match (totals, groups) {
+        (Ok(totals), Ok(groups)) => (totals, groups),
+        _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let has_more = groups.len() as i64 > limit;
+    groups.truncate(limit as usize);
+
+    Json(UsageReport {
+        start,
+        end,
+        group_by: query.group_by,
+        totals,
+        groups: groups
+            .into_iter()
+            .map(|(key, usage)| UsageGroup { key, usage })
+            .collect(),
+        limit,
+        offset,
+        has_more,
+    })
+    .into_response()
+}
+
+/// Prices a request the way a forward would, without minting a token or
+/// contacting the upstream.
+pub async fn estimate_cost(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EstimateRequest>,
+) -> Result<Json<EstimateResponse>, ForwardError> {
+    let snapshot = Snapshot::current(&state);
+    let (amount, model) = match request {
+        EstimateRequest::ChatCompletion(mut chat) => {
+            (prepare(&snapshot, &mut chat).await?, chat.model)
+        }
+        EstimateRequest::Embedding(mut embedding) => {
+            (prepare(&snapshot, &mut embedding).await?, embedding.model)
+        }
+        EstimateRequest::ImageGeneration(mut image) => {
+            (prepare(&snapshot, &mut image).await?, image.model)
+        }
+    };
+    let estimated_sats = if snapshot.policy.is_free(&model) {
+        0
+    } else {
+        amount
+    };
+
+    Ok(Json(EstimateResponse {
+        estimated_sats,
+        model,
+    }))
+}
+
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> String {
+    state.metrics.render()
+}
+
+/// Creates or replaces the config named in the body, or the default config
+/// when the body has no name.
 pub async fn update_server_config(
     State(state): State<Arc<AppState>>,
     Json(config): Json<ServerConfig>,
 ) -> Result<Json<ServerConfig>, StatusCode> {
-    let db_config = get_server_config(&state.db.clone()).await;
-    if let Some(c) = db_config {
-        update_config(&state.db.clone(), c.id, &config)
+    let db_config = match config.name.as_deref() {
+        Some(name) => get_config_by_name(&state.db, name)
             .await
-            .unwrap();
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => get_server_config(&state.db.clone()).await,
+    };
+    let record = if let Some(c) = db_config {
+        update_config(&state.db.clone(), c.id, &config).await
     } else {
-        create_config(&state.db.clone(), &config).await.unwrap();
+        create_config(&state.db.clone(), &config).await
     }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let config = get_server_config(&state.db.clone()).await.unwrap();
-    Ok(Json(ServerConfig {
-        endpoint: config.endpoint,
-        api_key: config.api_key,
-    }))
+    Ok(Json(masked_config(record.to_model())))
 }
 
+pub async fn list_server_configs(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ServerConfig>>, StatusCode> {
+    let configs = get_all_configs(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(
+        configs
+            .iter()
+            .map(|c| masked_config(c.to_model()))
+            .collect(),
+    ))
+}
+
+pub async fn delete_server_config(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> StatusCode {
+    let config = match get_config_by_name(&state.db, &name).await {
+        Ok(Some(config)) => config,
+        Ok(None) => return StatusCode::NOT_FOUND,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    match delete_config(&state.db, &config.id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The default server config with its API key masked, or an empty one.
 pub async fn get_current_server_config(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<ServerConfig>, StatusCode> {
     let config = get_server_config(&state.db.clone()).await;
     if let Some(c) = config {
-        return Ok(Json(ServerConfig {
-            endpoint: c.endpoint,
-            api_key: c.api_key,
-        }));
+        return Ok(Json(masked_config(c.to_model())));
     }
 
-    return Ok(Json(ServerConfig {
+    Ok(Json(ServerConfig {
+        name: None,
         endpoint: "".to_string(),
         api_key: "".to_string(),
-    }));
+        fallback_endpoints: Vec::new(),
+        payment_header: default_payment_header(),
+        change_header: default_change_header(),
+        mint_url: None,
+    }))
+}
+
+/// The default server config with its API key masked.
+pub async fn get_admin_config(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ServerConfig>, StatusCode> {
+    match get_server_config(&state.db).await {
+        Some(config) => Ok(Json(masked_config(config.to_model()))),
+        None => Err(StatusCode::NOT_FOUND),
+    }
 }
 
+/// Changes the default server config's endpoints or API key, creating the
+/// config when there is none, then drops the models cached from the old upstream.
+pub async fn update_admin_config(
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<ServerConfigUpdate>,
+) -> Result<Json<ServerConfig>, StatusCode> {
+    let existing = get_server_config(&state.db).await;
+    let mut config = match &existing {
+        Some(record) => record.to_model(),
+        None if update.endpoint.is_some() => ServerConfig {
+            name: None,
+            endpoint: String::new(),
+            api_key: String::new(),
+            fallback_endpoints: Vec::new(),
+            payment_header: default_payment_header(),
+            change_header: default_change_header(),
+            mint_url: None,
+        },
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+    if let Some(endpoint) = update.endpoint {
+        config.endpoint = endpoint;
+    }
+    if let Some(api_key) = update.api_key {
+        config.api_key = api_key.expose_secret().to_string();
+    }
+    if let Some(fallback_endpoints) = update.fallback_endpoints {
+        config.fallback_endpoints = fallback_endpoints;
+    }
+    if std::iter::once(&config.endpoint)
+        .chain(&config.fallback_endpoints)
+        .any(|endpoint| reqwest::Url::parse(endpoint).is_err())
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let record = match existing {
+        Some(record) => update_config(&state.db, record.id, &config).await,
+        None => create_config(&state.db, &config).await,
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    info!(config = ?record.to_model(), "server config updated by admin");
+    state.models_cache.clear().await;
+
+    Ok(Json(masked_config(record.to_model())))
+}
+
+fn masked_config(config: ServerConfig) -> ServerConfig {
+    ServerConfig {
+        api_key: redact::mask_secret(&config.api_key),
+        ..config
+    }
+}
+
+pub async fn list_model_prices(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ModelPrice>>, StatusCode> {
+    let prices = get_all_prices(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(prices.iter().map(|p| p.to_model()).collect()))
+}
+
+/// Sets the price of one model. Prices are only charged while the pricing
+/// strategy is `per_model`; under any other strategy they are stored but unused.
+pub async fn update_model_price(
+    State(state): State<Arc<AppState>>,
+    Json(price): Json<ModelPrice>,
+) -> Result<Json<ModelPrice>, StatusCode> {
+    if price.model.is_empty() || price.price_sats < 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let record = upsert_price(&state.db, &price.model, price.price_sats)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(record.to_model()))
+}
+
+pub async fn delete_model_price(
+    State(state): State<Arc<AppState>>,
+    Path(model): Path<String>,
+) -> StatusCode {
+    match delete_price(&state.db, &model).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn list_client_api_keys(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ClientApiKey>>, StatusCode> {
+    let keys = get_all_keys(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(keys.iter().map(|k| k.to_model()).collect()))
+}
+
+pub async fn create_client_api_key(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<CreateClientApiKey>,
+) -> Result<Json<CreatedClientApiKey>, StatusCode> {
+    if payload.name.is_empty() || payload.rate_limit.is_some_and(|limit| limit < 1) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let key = generate_key();
+    let record = create_key(
+        &state.db,
+        &payload.name,
+        &hash_key(&key),
+        payload.rate_limit,
+        payload.wallet_url.as_deref(),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(CreatedClientApiKey {
+        api_key: record.to_model(),
+        key,
+    }))
+}
+
+pub async fn delete_client_api_key(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match delete_key(&state.db, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn get_client_credits(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<ClientCredits>, StatusCode> {
+    let balance_sats = client_credits::get_balance(&state.db, &id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ClientCredits {
+        key_id: id,
+        balance_sats,
+    }))
+}
+
+pub async fn add_client_credits(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(payload): Json<AddClientCredits>,
+) -> Result<Json<ClientCredits>, StatusCode> {
+    if payload.amount < 1 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let balance_sats = client_credits::add_credits(&state.db, &id, payload.amount)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_foreign_key_violation() => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    info!(key = %id, amount = payload.amount, balance = balance_sats, "added client credits");
+
+    Ok(Json(ClientCredits {
+        key_id: id,
+        balance_sats,
+    }))
+}
+
+/// The default (unnamed) server config.
 pub async fn get_server_config(db: &Pool) -> Option<ServerConfigRecord> {
     if let Ok(c) = get_default_config(db).await {
         return c;
@@ -89,3 +741,54 @@ pub async fn get_server_config(db: &Pool) -> Option<ServerConfigRecord> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_access::{ModelAccess, ModelAliases, ModelPolicy};
+    use crate::test_support::{MockUpstream, MockWallet, app_state, body_json, post_json};
+    use axum::{Router, routing::post};
+    use std::collections::{HashMap, HashSet};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn estimates_under_the_same_policy_as_the_forward() {
+        let state = app_state(
+            MockUpstream::json(StatusCode::OK, json!({})),
+            MockWallet::new(100),
+        );
+        state.model_policy.replace(ModelPolicy {
+            access: ModelAccess::new(
+                &HashMap::new(),
+                &HashMap::from([("chat_completions".to_string(), vec!["o1".to_string()])]),
+            ),
+            aliases: ModelAliases::new(HashMap::from([(
+                "gpt-4".to_string(),
+                "gpt-4o".to_string(),
+            )])),
+            free_models: HashSet::from(["gpt-4o-mini".to_string()]),
+            ..ModelPolicy::default()
+        });
+        let app = Router::new()
+            .route("/estimate", post(estimate_cost))
+            .with_state(Arc::new(state));
+        let estimate = |model: &str| {
+            app.clone().oneshot(post_json(
+                "/estimate",
+                &json!({
+                    "model": model,
+                    "messages": [{ "role": "user", "content": "hi" }],
+                    "n": 9,
+                }),
+            ))
+        };
+
+        let aliased = body_json(estimate("gpt-4").await.unwrap()).await;
+        let free = body_json(estimate("gpt-4o-mini").await.unwrap()).await;
+        let denied = estimate("o1").await.unwrap();
+
+        assert_eq!(aliased, json!({ "estimated_sats": 40, "model": "gpt-4o" }));
+        assert_eq!(free["estimated_sats"], 0);
+        assert_eq!(denied.status(), StatusCode::NOT_FOUND);
+    }
+}
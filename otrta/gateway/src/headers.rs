@@ -0,0 +1,146 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header};
+use tracing::warn;
+
+/// Headers that only apply to a single connection and must not be forwarded.
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// The headers a proxy may pass on: everything except the hop-by-hop set and
+/// any header the `Connection` header names.
+pub fn end_to_end_headers(
+    headers: &HeaderMap,
+) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+    let connection_listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    headers.iter().filter(move |(name, _)| {
+        !HOP_BY_HOP_HEADERS.contains(name)
+            && !connection_listed
+                .iter()
+                .any(|listed| listed == name.as_str())
+    })
+}
+
+/// Client headers that are never forwarded, whatever the allowlist says. The
+/// gateway sets its own credentials and body framing, and negotiates the
+/// upstream encoding itself so it can read decompressed bodies.
+const NEVER_FORWARDED: [HeaderName; 6] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::HOST,
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::ACCEPT_ENCODING,
+];
+
+/// Header names to match. Entries match a header name exactly, or by prefix
+/// when they end in `*`.
+#[derive(Clone, Debug)]
+struct HeaderPatterns {
+    names: Vec<HeaderName>,
+    prefixes: Vec<String>,
+}
+
+impl HeaderPatterns {
+    fn new(entries: &[String]) -> Self {
+        let mut names = Vec::new();
+        let mut prefixes = Vec::new();
+
+        for entry in entries {
+            let entry = entry.trim().to_ascii_lowercase();
+            if let Some(prefix) = entry.strip_suffix('*') {
+                prefixes.push(prefix.to_string());
+            } else {
+                match HeaderName::from_bytes(entry.as_bytes()) {
+                    Ok(name) => names.push(name),
+                    Err(_) => warn!(header = %entry, "ignoring invalid header name"),
+                }
+            }
+        }
+
+        Self { names, prefixes }
+    }
+
+    fn matches(&self, name: &HeaderName) -> bool {
+        self.names.contains(name)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| name.as_str().starts_with(prefix.as_str()))
+    }
+}
+
+/// Which client request headers are passed on to the upstream: those on the
+/// allowlist, minus any the operator strips for privacy. Stripping wins, so a
+/// broad allowlist entry such as `x-*` can still keep `x-forwarded-for` back.
+#[derive(Clone, Debug)]
+pub struct HeaderAllowlist {
+    allowed: HeaderPatterns,
+    stripped: HeaderPatterns,
+}
+
+impl HeaderAllowlist {
+    pub fn new(allowed: &[String], stripped: &[String]) -> Self {
+        Self {
+            allowed: HeaderPatterns::new(allowed),
+            stripped: HeaderPatterns::new(stripped),
+        }
+    }
+
+    pub fn allows(&self, name: &HeaderName) -> bool {
+        !NEVER_FORWARDED.contains(name)
+            && !self.stripped.matches(name)
+            && self.allowed.matches(name)
+    }
+
+    /// The end-to-end headers of a client request that may reach the upstream.
+    pub fn filter<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)> {
+        end_to_end_headers(headers).filter(|(name, _)| self.allows(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwards_allowlisted_headers_and_drops_the_rest() {
+        let allowlist =
+            HeaderAllowlist::new(&["OpenAI-Organization".into(), "x-stainless-*".into()], &[]);
+        let mut headers = HeaderMap::new();
+        headers.insert("openai-organization", HeaderValue::from_static("org-1"));
+        headers.insert("x-stainless-lang", HeaderValue::from_static("js"));
+        headers.insert("x-secret", HeaderValue::from_static("s3cret"));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer client"),
+        );
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("x-stainless-lang"),
+        );
+
+        let forwarded: Vec<&str> = allowlist
+            .filter(&headers)
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        assert_eq!(forwarded, vec!["openai-organization"]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Rewrites image generation responses so clients get content that outlives
+//! the upstream's expiring URLs.
+
+use axum::{
+    body::{Body, to_bytes},
+    http::header,
+    response::Response,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How URL-form images in an image generation response reach the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageUrlMode {
+    /// Forward the upstream's response unchanged.
+    #[default]
+    Passthrough,
+    /// Download each image and inline it as `b64_json`. Every image crosses
+    /// the gateway twice, so this costs bandwidth and memory per request.
+    B64Json,
+}
+
+/// Downloads the images an upstream response links to and inlines them.
+/// Images that cannot be fetched keep their URL.
+pub struct ImageInliner {
+    http_client: reqwest::Client,
+    timeout: Duration,
+    max_image_bytes: usize,
+}
+
+impl ImageInliner {
+    pub fn new(http_client: reqwest::Client, timeout: Duration, max_image_bytes: usize) -> Self {
+        Self {
+            http_client,
+            timeout,
+            max_image_bytes,
+        }
+    }
+
+    pub async fn inline(&self, response: Response) -> Response {
+        if !response.status().is_success() {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        let bytes = match to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(error = %e, "failed to read image generation response");
+                return Response::from_parts(parts, Body::empty());
+            }
+        };
+        let Ok(mut generated) = serde_json::from_slice::<Value>(&bytes) else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+
+        if let Some(images) = generated.get_mut("data").and_then(Value::as_array_mut) {
+            join_all(images.iter_mut().map(|image| self.inline_image(image))).await;
+        }
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Body::from(generated.to_string()))
+    }
+
+    async fn inline_image(&self, image: &mut Value) {
+        let Some(url) = image.get("url").and_then(Value::as_str).map(str::to_string) else {
+            return;
+        };
+        match self.download(&url).await {
+            Ok(data) => {
+                debug!(url, bytes = data.len(), "inlined generated image");
+                if let Some(image) = image.as_object_mut() {
+                    image.remove("url");
+                    image.insert("b64_json".to_string(), Value::String(STANDARD.encode(data)));
+                }
+            }
+            Err(e) => warn!(url, error = %e, "failed to download generated image, keeping its URL"),
+        }
+    }
+
+    async fn download(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let mut response = self
+            .http_client
+            .get(url)
+            .timeout(self.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > self.max_image_bytes {
+                anyhow::bail!("image exceeds {} bytes", self.max_image_bytes);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
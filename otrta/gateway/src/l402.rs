@@ -0,0 +1,116 @@
+use crate::wallets::PaymentWallet;
+use axum::http::{HeaderMap, header};
+use tracing::{info, warn};
+
+/// An `L402 macaroon="...", invoice="..."` challenge from a 402 response.
+#[derive(Clone, Debug)]
+pub struct L402Challenge {
+    pub macaroon: String,
+    pub invoice: String,
+}
+
+impl L402Challenge {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(Self::parse)
+    }
+
+    fn parse(challenge: &str) -> Option<Self> {
+        let (scheme, params) = challenge.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("L402") && !scheme.eq_ignore_ascii_case("LSAT") {
+            return None;
+        }
+
+        let mut macaroon = None;
+        let mut invoice = None;
+        for param in params.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "macaroon" => macaroon = Some(value),
+                "invoice" => invoice = Some(value),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            macaroon: macaroon?,
+            invoice: invoice?,
+        })
+    }
+
+    pub fn authorization(&self, preimage: &str) -> String {
+        format!("L402 {}:{}", self.macaroon, preimage)
+    }
+}
+
+/// Pays the challenge invoice and returns the sats paid together with the
+/// `Authorization` value to retry with. Invoices without an amount, or above
+/// `max_sats`, are refused.
+pub async fn pay_challenge(
+    wallet: &dyn PaymentWallet,
+    challenge: &L402Challenge,
+    max_sats: i64,
+) -> Option<(i64, String)> {
+    let Some(amount) = bolt11_amount_sats(&challenge.invoice) else {
+        warn!("refusing to pay L402 invoice without an amount");
+        return None;
+    };
+    if amount > max_sats {
+        warn!(
+            amount,
+            max_sats, "L402 invoice exceeds the retry payment cap"
+        );
+        return None;
+    }
+
+    match wallet.pay_invoice(&challenge.invoice).await {
+        Ok(payment) => match payment.preimage {
+            Some(preimage) => {
+                info!(amount, "paid L402 invoice");
+                Some((amount, challenge.authorization(&preimage)))
+            }
+            None => {
+                warn!(error = ?payment.error_message, "L402 invoice payment returned no preimage");
+                None
+            }
+        },
+        Err(e) => {
+            warn!(error = %e, "failed to pay L402 invoice");
+            None
+        }
+    }
+}
+
+/// Reads the amount encoded in a bolt11 invoice's human-readable part, rounded
+/// up to whole sats.
+pub fn bolt11_amount_sats(invoice: &str) -> Option<i64> {
+    let invoice = invoice.trim().to_ascii_lowercase();
+    let hrp = &invoice[..invoice.rfind('1')?];
+    let amount = hrp
+        .strip_prefix("ln")?
+        .trim_start_matches(|c: char| c.is_ascii_alphabetic());
+
+    let (digits, multiplier) = match amount.chars().last()? {
+        c if c.is_ascii_digit() => (amount, None),
+        c => (&amount[..amount.len() - 1], Some(c)),
+    };
+    let value: i64 = digits.parse().ok()?;
+
+    // Tenths of a millisatoshi per unit, the smallest step a bolt11 amount can express.
+    let tenth_msat_per_unit: i64 = match multiplier {
+        None => 1_000_000_000_000,
+        Some('m') => 1_000_000_000,
+        Some('u') => 1_000_000,
+        Some('n') => 1_000,
+        Some('p') => 1,
+        Some(_) => return None,
+    };
+    let tenth_msat = value.checked_mul(tenth_msat_per_unit)?;
+    Some((tenth_msat + 9_999) / 10_000)
+}
@@ -1,7 +1,43 @@
+pub mod alerts;
+pub mod anthropic;
+pub mod auth;
+pub mod balance_log;
+pub mod body_limit;
+pub mod bulkhead;
+pub mod cache;
+pub mod cancel;
+pub mod circuit_breaker;
 pub mod connection;
+pub mod consolidation;
+pub mod credits;
 pub mod db;
+pub mod dlq;
 pub mod error;
+pub mod extract;
 pub mod forward;
 pub mod handlers;
+pub mod headers;
+pub mod images;
+pub mod l402;
+pub mod model_access;
 pub mod models;
+pub mod ollama;
+pub mod payment;
+pub mod prepare;
+pub mod pricing;
+pub mod rate_limit;
+pub mod redact;
+pub mod reload;
+pub mod request_id;
+pub mod settings;
+pub mod shadow;
+pub mod shutdown;
+pub mod sse;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+pub mod topup;
+pub mod upstream;
 pub mod wallet;
+pub mod wallets;
+pub mod warmup;
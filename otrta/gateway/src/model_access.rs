@@ -0,0 +1,249 @@
+use crate::{connection::ApplicationSettings, error::ForwardError, pricing::EndpointType};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+const MAX_COMPLETION_TOKENS: &str = "max_completion_tokens";
+
+/// Which models each endpoint may be asked for. Rules are keyed by
+/// [`EndpointType::as_str`]; an endpoint without rules accepts every model.
+#[derive(Debug, Default, PartialEq)]
+pub struct ModelAccess {
+    allow: HashMap<String, HashSet<String>>,
+    deny: HashMap<String, HashSet<String>>,
+}
+
+impl ModelAccess {
+    pub fn new(allow: &HashMap<String, Vec<String>>, deny: &HashMap<String, Vec<String>>) -> Self {
+        let collect = |rules: &HashMap<String, Vec<String>>| {
+            rules
+                .iter()
+                .map(|(endpoint, models)| (endpoint.clone(), models.iter().cloned().collect()))
+                .collect()
+        };
+        Self {
+            allow: collect(allow),
+            deny: collect(deny),
+        }
+    }
+
+    /// A denied model is refused even when it is also allowed.
+    pub fn permits(&self, endpoint: EndpointType, model: &str) -> bool {
+        let endpoint = endpoint.as_str();
+        let denied = self
+            .deny
+            .get(endpoint)
+            .is_some_and(|models| models.contains(model));
+        let allowed = self
+            .allow
+            .get(endpoint)
+            .is_none_or(|models| models.is_empty() || models.contains(model));
+        allowed && !denied
+    }
+
+    /// Fails with a `model_not_found` 404 so no token is minted for the request.
+    pub fn check(&self, endpoint: EndpointType, model: &str) -> Result<(), ForwardError> {
+        if self.permits(endpoint, model) {
+            return Ok(());
+        }
+        warn!(
+            endpoint = endpoint.as_str(),
+            model, "rejecting request for a model outside the allowlist"
+        );
+        Err(ForwardError::ModelNotFound(model.to_string()))
+    }
+
+    /// For a request that leaves the model to the upstream. Refused once the
+    /// endpoint has an allowlist, since the upstream's default may not be on it.
+    pub fn check_unnamed(&self, endpoint: EndpointType) -> Result<(), ForwardError> {
+        if self
+            .allow
+            .get(endpoint.as_str())
+            .is_none_or(|models| models.is_empty())
+        {
+            return Ok(());
+        }
+        warn!(
+            endpoint = endpoint.as_str(),
+            "rejecting request without a model on an allowlisted endpoint"
+        );
+        Err(ForwardError::InvalidBody {
+            message: "`model` is required: this endpoint only serves allowlisted models"
+                .to_string(),
+            param: Some("model".to_string()),
+        })
+    }
+}
+
+/// Renames requested models before they are priced, checked or forwarded, so
+/// clients hardcoding a name like `gpt-4` reach the model the operator picked.
+#[derive(Debug, Default, PartialEq)]
+pub struct ModelAliases(HashMap<String, String>);
+
+impl ModelAliases {
+    pub fn new(aliases: HashMap<String, String>) -> Self {
+        Self(aliases)
+    }
+
+    pub fn apply(&self, model: &mut String) {
+        if let Some(target) = self.0.get(model.as_str()) {
+            info!(requested = %model, model = %target, "remapping model alias");
+            *model = target.clone();
+        }
+    }
+}
+
+/// Caps how many tokens a chat or completion request may ask for, per model
+/// with a global fallback. Requests without a limit are given the ceiling.
+#[derive(Debug, Default, PartialEq)]
+pub struct MaxTokensCeiling {
+    default: Option<u32>,
+    per_model: HashMap<String, u32>,
+}
+
+impl MaxTokensCeiling {
+    pub fn new(default: Option<u32>, per_model: HashMap<String, u32>) -> Self {
+        Self { default, per_model }
+    }
+
+    pub fn ceiling(&self, model: &str) -> Option<u32> {
+        self.per_model.get(model).copied().or(self.default)
+    }
+
+    /// Applies the ceiling to `max_tokens`, or to `max_completion_tokens` in
+    /// `extra` when the client used the newer field, so only one is ever set.
+    pub fn clamp(
+        &self,
+        model: &str,
+        max_tokens: &mut Option<u32>,
+        extra: &mut HashMap<String, Value>,
+    ) {
+        let Some(ceiling) = self.ceiling(model) else {
+            return;
+        };
+
+        if let Some(requested) = extra.get_mut(MAX_COMPLETION_TOKENS) {
+            if requested.as_u64().is_none_or(|n| n > u64::from(ceiling)) {
+                info!(model, requested = %requested, ceiling, "clamping max_completion_tokens");
+                *requested = Value::from(ceiling);
+            }
+            return;
+        }
+
+        if max_tokens.is_none_or(|n| n > ceiling) {
+            info!(model, requested = ?max_tokens, ceiling, "clamping max_tokens");
+            *max_tokens = Some(ceiling);
+        }
+    }
+
+    /// Clamps the token limits a free-form body sets, for requests relayed
+    /// without a typed handler. A missing limit is left out rather than set,
+    /// since the endpoint may not accept one.
+    pub fn clamp_body(&self, model: &str, body: &mut serde_json::Map<String, Value>) {
+        let Some(ceiling) = self.ceiling(model) else {
+            return;
+        };
+        for key in [MAX_COMPLETION_TOKENS, "max_tokens"] {
+            if let Some(requested) = body.get_mut(key)
+                && requested.as_u64().is_none_or(|n| n > u64::from(ceiling))
+            {
+                info!(model, key, requested = %requested, ceiling, "clamping token limit");
+                *requested = Value::from(ceiling);
+            }
+        }
+    }
+}
+
+/// Every per-model rule from the configuration file, swapped as one on reload.
+#[derive(Debug, Default, PartialEq)]
+pub struct ModelPolicy {
+    pub access: ModelAccess,
+    pub aliases: ModelAliases,
+    pub max_tokens: MaxTokensCeiling,
+    /// Models forwarded without payment.
+    pub free_models: HashSet<String>,
+}
+
+impl ModelPolicy {
+    pub fn from_settings(settings: &ApplicationSettings) -> Self {
+        Self {
+            access: ModelAccess::new(&settings.model_allowlist, &settings.model_denylist),
+            aliases: ModelAliases::new(settings.model_aliases.clone()),
+            max_tokens: MaxTokensCeiling::new(
+                settings.max_tokens_ceiling,
+                settings.max_tokens_ceilings.clone(),
+            ),
+            free_models: settings.free_models.iter().cloned().collect(),
+        }
+    }
+
+    pub fn is_free(&self, model: &str) -> bool {
+        self.free_models.contains(model)
+    }
+}
+
+/// The model policy in force. Requests take a snapshot, so a reload never
+/// changes the rules halfway through one.
+#[derive(Debug, Default)]
+pub struct ModelPolicies(RwLock<Arc<ModelPolicy>>);
+
+impl ModelPolicies {
+    pub fn new(policy: ModelPolicy) -> Self {
+        Self(RwLock::new(Arc::new(policy)))
+    }
+
+    pub fn current(&self) -> Arc<ModelPolicy> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, policy: ModelPolicy) {
+        *self.0.write().unwrap() = Arc::new(policy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(endpoint: EndpointType, models: &[&str]) -> HashMap<String, Vec<String>> {
+        HashMap::from([(
+            endpoint.as_str().to_string(),
+            models.iter().map(|model| model.to_string()).collect(),
+        )])
+    }
+
+    #[test]
+    fn an_allowlist_admits_only_its_models_on_its_endpoint() {
+        let access = ModelAccess::new(
+            &rules(EndpointType::ChatCompletions, &["gpt-4o-mini"]),
+            &HashMap::new(),
+        );
+
+        assert!(access.permits(EndpointType::ChatCompletions, "gpt-4o-mini"));
+        assert!(!access.permits(EndpointType::ChatCompletions, "gpt-4o"));
+        assert!(access.permits(EndpointType::Embeddings, "gpt-4o"));
+    }
+
+    #[test]
+    fn a_denylist_refuses_its_models_even_when_allowed() {
+        let access = ModelAccess::new(
+            &rules(EndpointType::ChatCompletions, &["gpt-4o", "o1"]),
+            &rules(EndpointType::ChatCompletions, &["o1"]),
+        );
+
+        assert!(access.permits(EndpointType::ChatCompletions, "gpt-4o"));
+        assert!(!access.permits(EndpointType::ChatCompletions, "o1"));
+        let error = access
+            .check(EndpointType::ChatCompletions, "o1")
+            .unwrap_err();
+        assert!(matches!(error, ForwardError::ModelNotFound(model) if model == "o1"));
+    }
+
+    #[test]
+    fn no_rules_admit_every_model() {
+        let access = ModelAccess::default();
+
+        assert!(access.permits(EndpointType::ImageGenerations, "dall-e-3"));
+    }
+}
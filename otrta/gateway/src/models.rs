@@ -1,8 +1,34 @@
+use crate::alerts::LowBalanceAlert;
+use crate::bulkhead::Bulkhead;
+use crate::cache::{EmbeddingsCache, ResponseCache, SingleFlight};
+use crate::cancel::StreamCancels;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::consolidation::Consolidator;
+use crate::credits::CreditMode;
+use crate::db::server_config::ServerConfigSource;
+use crate::headers::HeaderAllowlist;
+use crate::images::ImageInliner;
+use crate::model_access::ModelPolicies;
+use crate::payment::WalletRetry;
+use crate::rate_limit::RateLimiter;
+use crate::settings::LiveSettings;
+use crate::shadow::ShadowTraffic;
+use crate::shutdown::ActiveStreams;
+use crate::topup::AutoTopup;
+use crate::upstream::UpstreamClient;
+use crate::wallets::WalletRegistry;
 use chrono::{DateTime, Utc};
+use metrics_exporter_prometheus::PrometheusHandle;
+use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use wallet::api::CashuWalletClient;
+use wallet::{
+    api::CashuWalletClient,
+    models::{ChatCompletionRequest, EmbeddingRequest, ImageGenerationRequest},
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum UserRole {
@@ -205,6 +231,257 @@ pub struct TokenRedeemResponse {
     pub message: Option<String>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiveTokenResponse {
+    pub received: i64,
+    pub balance: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopupRequest {
+    pub amount: i64,
+    /// One of the configured mints; the wallet's default mint when unset.
+    #[serde(default)]
+    pub mint: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopupStatus {
+    Pending,
+    Claimed,
+    Failed,
+}
+
+/// A lightning invoice that mints `amount` sats into the wallet once paid.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Topup {
+    pub id: String,
+    pub amount: i64,
+    pub mint_url: Option<String>,
+    pub invoice: String,
+    pub status: TopupStatus,
+    pub created_at: DateTime<Utc>,
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterStatus {
+    /// Retried in the background until it is reclaimed.
+    Pending,
+    /// No longer retried: the wallet refused it for good or it ran out of attempts.
+    Abandoned,
+}
+
+/// A payment token the wallet could not take back yet, retried in the background.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    /// Masked with [`crate::redact::mask_token`]; the token itself never leaves the database.
+    pub token: String,
+    pub wallet_url: Option<String>,
+    pub last_error: String,
+    pub attempts: i32,
+    pub status: DeadLetterStatus,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub abandoned_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGroupBy {
+    #[default]
+    Model,
+    Endpoint,
+    Day,
+}
+
+impl UsageGroupBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsageGroupBy::Model => "model",
+            UsageGroupBy::Endpoint => "endpoint",
+            UsageGroupBy::Day => "day",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageQuery {
+    /// Inclusive; 30 days before `end` when unset.
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    /// Exclusive; now when unset.
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub group_by: UsageGroupBy,
+    #[serde(default)]
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub offset: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageSummary {
+    pub requests: i64,
+    pub sats_sent: i64,
+    pub sats_change: i64,
+    pub net_sats: i64,
+    /// Only counted for responses that reported usage.
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+}
+
+impl UsageSummary {
+    pub fn new(
+        requests: i64,
+        sats_sent: i64,
+        sats_change: i64,
+        prompt_tokens: Option<i64>,
+        completion_tokens: Option<i64>,
+    ) -> Self {
+        Self {
+            requests,
+            sats_sent,
+            sats_change,
+            net_sats: sats_sent - sats_change,
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageGroup {
+    pub key: String,
+    #[serde(flatten)]
+    pub usage: UsageSummary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub group_by: UsageGroupBy,
+    pub totals: UsageSummary,
+    pub groups: Vec<UsageGroup>,
+    pub limit: i64,
+    pub offset: i64,
+    pub has_more: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheQuery {
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub model: String,
+    pub price_sats: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientApiKey {
+    pub id: String,
+    pub name: String,
+    pub is_active: bool,
+    pub rate_limit: Option<i32>,
+    pub wallet_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreateClientApiKey {
+    pub name: String,
+    /// Requests allowed per rate-limit window; the global default when unset.
+    #[serde(default)]
+    pub rate_limit: Option<i32>,
+    /// Wallet backend the key pays from; the shared wallet when unset.
+    #[serde(default)]
+    pub wallet_url: Option<String>,
+}
+
+/// Returned once when a key is created; the plaintext key is not stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedClientApiKey {
+    #[serde(flatten)]
+    pub api_key: ClientApiKey,
+    pub key: String,
+}
+
+/// Fields of the default server config `PUT /admin/config` changes; the
+/// rest keep their current values.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ServerConfigUpdate {
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub api_key: Option<SecretString>,
+    #[serde(default)]
+    pub fallback_endpoints: Option<Vec<String>>,
+}
+
+/// One answer of `POST /v1/chat/completions/batch`, in request order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub index: usize,
+    pub status: u16,
+    pub cost_sats: i64,
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchResponse {
+    /// Tags the ledger entry of every item.
+    pub id: String,
+    pub object: String,
+    pub data: Vec<BatchItem>,
+    pub total_cost_sats: i64,
+}
+
+/// A client key's prepaid credit balance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientCredits {
+    pub key_id: String,
+    pub balance_sats: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddClientCredits {
+    pub amount: i64,
+}
+
+/// Any of the request bodies `POST /estimate` can price.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EstimateRequest {
+    ChatCompletion(ChatCompletionRequest),
+    Embedding(EmbeddingRequest),
+    ImageGeneration(ImageGenerationRequest),
+}
+
+impl EstimateRequest {
+    pub fn model(&self) -> &str {
+        match self {
+            EstimateRequest::ChatCompletion(request) => &request.model,
+            EstimateRequest::Embedding(request) => &request.model,
+            EstimateRequest::ImageGeneration(request) => &request.model,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EstimateResponse {
+    pub estimated_sats: i64,
+    pub model: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
     pub username: Option<String>,
@@ -230,6 +507,8 @@ pub struct RegisterResponse {
 
 pub struct AppState {
     pub db: sqlx::PgPool,
+    /// The upstream configs forwarded requests are sent to, normally `db`.
+    pub server_configs: Arc<dyn ServerConfigSource>,
     pub users: RwLock<HashMap<String, User>>,
     pub organizations: RwLock<HashMap<String, Organization>>,
     pub api_keys: RwLock<HashMap<String, ApiKey>>,
@@ -237,4 +516,44 @@ pub struct AppState {
     pub providers: RwLock<HashMap<String, Provider>>,
     pub credits: RwLock<HashMap<String, Credit>>,
     pub wallet: CashuWalletClient,
+    /// Used to build upstream requests and for the gateway's own calls.
+    pub http_client: reqwest::Client,
+    pub upstream: Arc<dyn UpstreamClient>,
+    pub streaming_upstream: Arc<dyn UpstreamClient>,
+    pub metrics: PrometheusHandle,
+    pub max_batch_requests: usize,
+    pub wallets: Arc<WalletRegistry>,
+    pub low_balance_alert: Option<Arc<LowBalanceAlert>>,
+    pub consolidator: Arc<Consolidator>,
+    pub auto_topup: Option<Arc<AutoTopup>>,
+    pub track_stream_usage: bool,
+    /// Upstream chunks buffered per stream before a slow client pushes back.
+    pub stream_channel_buffer: usize,
+    pub stream_request_body_bytes: Option<u64>,
+    pub max_request_body_bytes: usize,
+    pub max_file_upload_bytes: usize,
+    pub model_policy: ModelPolicies,
+    pub runtime: LiveSettings,
+    pub image_inliner: Option<ImageInliner>,
+    pub readiness_check_upstream: bool,
+    pub circuit_breakers: CircuitBreakers,
+    pub upstream_retries: u32,
+    pub upstream_retry_backoff: Duration,
+    pub max_retry_after: Duration,
+    pub wallet_retry: WalletRetry,
+    pub l402_enabled: bool,
+    pub gateway_auth_enabled: bool,
+    /// SHA-256 of the key the management API requires; the API is closed without one.
+    pub admin_key_hash: Option<String>,
+    pub rate_limiter: RateLimiter,
+    pub forwarded_headers: HeaderAllowlist,
+    pub shadow: Option<ShadowTraffic>,
+    pub mints: Vec<String>,
+    pub active_streams: ActiveStreams,
+    pub stream_cancels: StreamCancels,
+    pub bulkhead: Option<Bulkhead>,
+    pub credit_mode: CreditMode,
+    pub models_cache: ResponseCache,
+    pub embeddings_cache: Option<EmbeddingsCache>,
+    pub models_in_flight: SingleFlight,
 }
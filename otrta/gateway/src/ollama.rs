@@ -0,0 +1,359 @@
+//! Translation between Ollama's `/api/chat` and `/api/generate` and the
+//! OpenAI chat completions format the upstream speaks.
+
+use crate::sse::{SseEvent, SseParser, StreamTranslator, translate_stream};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderValue, header},
+    response::Response,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use wallet::models::{ChatCompletionRequest, ChatMessage};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OllamaOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// Base64-encoded images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    /// Ollama streams unless told otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+}
+
+/// Which Ollama endpoint a response is rendered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OllamaShape {
+    Chat,
+    Generate,
+}
+
+impl OllamaChatRequest {
+    pub fn is_streaming(&self) -> bool {
+        self.stream.unwrap_or(true)
+    }
+
+    pub fn to_chat_completion(&self) -> ChatCompletionRequest {
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| to_chat_message(&message.role, &message.content, &message.images))
+            .collect();
+        chat_completion(
+            &self.model,
+            messages,
+            self.is_streaming(),
+            self.format.as_ref(),
+            self.options.as_ref(),
+        )
+    }
+}
+
+impl OllamaGenerateRequest {
+    pub fn is_streaming(&self) -> bool {
+        self.stream.unwrap_or(true)
+    }
+
+    pub fn to_chat_completion(&self) -> ChatCompletionRequest {
+        let system = self
+            .system
+            .as_ref()
+            .map(|system| to_chat_message("system", system, &None));
+        let messages = system
+            .into_iter()
+            .chain(std::iter::once(to_chat_message(
+                "user",
+                &self.prompt,
+                &self.images,
+            )))
+            .collect();
+        chat_completion(
+            &self.model,
+            messages,
+            self.is_streaming(),
+            self.format.as_ref(),
+            self.options.as_ref(),
+        )
+    }
+}
+
+fn to_chat_message(role: &str, content: &str, images: &Option<Vec<String>>) -> ChatMessage {
+    let content = match images.as_deref() {
+        Some(images) if !images.is_empty() => {
+            let mut parts = vec![json!({"type": "text", "text": content})];
+            parts.extend(images.iter().map(|image| {
+                json!({
+                    "type": "image_url",
+                    "image_url": {"url": format!("data:image/png;base64,{}", image)},
+                })
+            }));
+            Value::Array(parts)
+        }
+        _ => Value::String(content.to_string()),
+    };
+    ChatMessage {
+        role: role.to_string(),
+        content,
+        tool_calls: None,
+    }
+}
+
+fn chat_completion(
+    model: &str,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    format: Option<&Value>,
+    options: Option<&OllamaOptions>,
+) -> ChatCompletionRequest {
+    let options = options.cloned().unwrap_or_default();
+    let mut extra = HashMap::new();
+    if format.and_then(Value::as_str) == Some("json") {
+        extra.insert(
+            "response_format".to_string(),
+            json!({"type": "json_object"}),
+        );
+    }
+    if stream {
+        // Lets the final stream chunk carry the token counts Ollama reports.
+        extra.insert("stream_options".to_string(), json!({"include_usage": true}));
+    }
+
+    ChatCompletionRequest {
+        model: model.to_string(),
+        messages,
+        temperature: options.temperature,
+        top_p: options.top_p,
+        n: None,
+        max_tokens: options.num_predict,
+        stream: Some(stream),
+        stop: options.stop,
+        presence_penalty: None,
+        frequency_penalty: None,
+        logprobs: None,
+        top_logprobs: None,
+        tools: None,
+        tool_choice: None,
+        extra,
+    }
+}
+
+/// One line of Ollama output carrying `text`, shaped for the endpoint.
+fn line(shape: OllamaShape, model: &Value, text: &str, done: bool) -> Value {
+    let mut line = json!({
+        "model": model,
+        "created_at": Utc::now().to_rfc3339(),
+        "done": done,
+    });
+    match shape {
+        OllamaShape::Chat => line["message"] = json!({"role": "assistant", "content": text}),
+        OllamaShape::Generate => line["response"] = json!(text),
+    }
+    line
+}
+
+fn finish(line: &mut Value, finish_reason: Option<&str>, usage: Option<&Value>) {
+    line["done_reason"] = json!(match finish_reason {
+        Some("length") => "length",
+        _ => "stop",
+    });
+    if let Some(usage) = usage {
+        line["prompt_eval_count"] = usage.get("prompt_tokens").cloned().unwrap_or(json!(0));
+        line["eval_count"] = usage.get("completion_tokens").cloned().unwrap_or(json!(0));
+    }
+}
+
+/// Converts a buffered chat completion into a single Ollama response.
+pub fn to_response(shape: OllamaShape, completion: &Value) -> Value {
+    let text = completion
+        .pointer("/choices/0/message/content")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let model = completion.get("model").cloned().unwrap_or(Value::Null);
+    let mut response = line(shape, &model, text, true);
+    finish(
+        &mut response,
+        completion
+            .pointer("/choices/0/finish_reason")
+            .and_then(Value::as_str),
+        completion.get("usage"),
+    );
+    response
+}
+
+/// Rewrites an OpenAI-shaped error body into Ollama's `{"error": ...}`.
+pub fn to_error(body: &[u8]) -> Value {
+    let message = serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|value| {
+            value
+                .pointer("/error/message")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| String::from_utf8_lossy(body).into_owned());
+    json!({ "error": message })
+}
+
+/// Re-renders a gateway response for an Ollama client.
+pub async fn translate_response(
+    response: Response,
+    shape: OllamaShape,
+    streaming: bool,
+) -> Response {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    if streaming && parts.status.is_success() {
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        let lines = translate_stream(body.into_data_stream(), NdjsonStream::new(shape));
+        return Response::from_parts(parts, Body::from_stream(lines));
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => Bytes::from(format!("Error reading from upstream: {}", e)),
+    };
+    let translated = if parts.status.is_success() {
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(completion) => to_response(shape, &completion),
+            Err(_) => to_error(&bytes),
+        }
+    } else {
+        to_error(&bytes)
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(translated.to_string()))
+}
+
+/// Turns OpenAI stream chunks into Ollama's newline-delimited JSON.
+struct NdjsonStream {
+    shape: OllamaShape,
+    parser: SseParser,
+    model: Value,
+    finish_reason: Option<String>,
+    usage: Option<Value>,
+    finished: bool,
+}
+
+impl NdjsonStream {
+    fn new(shape: OllamaShape) -> Self {
+        Self {
+            shape,
+            parser: SseParser::default(),
+            model: Value::Null,
+            finish_reason: None,
+            usage: None,
+            finished: false,
+        }
+    }
+}
+
+impl NdjsonStream {
+    fn translate_events(&mut self, events: Vec<SseEvent>) -> String {
+        let mut out = String::new();
+        for event in events {
+            let chunk = match event {
+                SseEvent::Data(chunk) => chunk,
+                SseEvent::Done => {
+                    out.push_str(&self.finish());
+                    continue;
+                }
+            };
+            if let Some(model) = chunk.get("model") {
+                self.model = model.clone();
+            }
+            if let Some(reason) = chunk
+                .pointer("/choices/0/finish_reason")
+                .and_then(Value::as_str)
+            {
+                self.finish_reason = Some(reason.to_string());
+            }
+            if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+                self.usage = Some(usage.clone());
+            }
+            if let Some(text) = chunk
+                .pointer("/choices/0/delta/content")
+                .and_then(Value::as_str)
+                .filter(|text| !text.is_empty())
+            {
+                out.push_str(&line(self.shape, &self.model, text, false).to_string());
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+impl StreamTranslator for NdjsonStream {
+    fn push(&mut self, chunk: &[u8]) -> String {
+        let events = self.parser.push(chunk);
+        self.translate_events(events)
+    }
+
+    fn finish(&mut self) -> String {
+        let events = self.parser.finish();
+        let mut out = self.translate_events(events);
+        if self.finished {
+            return out;
+        }
+        self.finished = true;
+
+        let mut last = line(self.shape, &self.model, "", true);
+        finish(
+            &mut last,
+            self.finish_reason.as_deref(),
+            self.usage.as_ref(),
+        );
+        out.push_str(&format!("{}\n", last));
+        out
+    }
+}
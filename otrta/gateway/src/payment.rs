@@ -0,0 +1,864 @@
+use crate::{
+    db::{Pool, payment_dlq},
+    l402::{self, L402Challenge},
+    redact::{Redacted, redact},
+    telemetry,
+    upstream::UpstreamError,
+    wallets::PaymentWallet,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service, ServiceExt};
+use tracing::{error, info, instrument, warn};
+use wallet::api::{WalletHttpError, WalletLockTimeout};
+
+pub const PRICE_HEADER: &str = "X-PRICE-SATS";
+pub const COST_HEADER: &str = "X-COST-SATS";
+
+/// Names of the headers carrying the payment token and the returned change.
+#[derive(Clone, Debug)]
+pub struct PaymentHeaders {
+    pub payment: HeaderName,
+    pub change: HeaderName,
+}
+
+impl Default for PaymentHeaders {
+    fn default() -> Self {
+        Self {
+            payment: HeaderName::from_static("x-payment-sats"),
+            change: HeaderName::from_static("x-change-sats"),
+        }
+    }
+}
+
+impl PaymentHeaders {
+    /// Parses configured header names, keeping the default for any that are invalid.
+    pub fn from_names(payment: &str, change: &str) -> Self {
+        let defaults = Self::default();
+        Self {
+            payment: parse_header_name(payment).unwrap_or(defaults.payment),
+            change: parse_header_name(change).unwrap_or(defaults.change),
+        }
+    }
+}
+
+fn parse_header_name(name: &str) -> Option<HeaderName> {
+    match HeaderName::from_bytes(name.trim().as_bytes()) {
+        Ok(header_name) => Some(header_name),
+        Err(_) => {
+            warn!(header = name, "invalid payment header name, using default");
+            None
+        }
+    }
+}
+
+/// An upstream response together with the token that paid for it.
+pub struct PaidResponse {
+    pub response: reqwest::Response,
+    /// `None` when the request went out unpaid or under an L402 authorization.
+    pub token: Option<String>,
+    /// Sats paid for the request, net of the change from any earlier attempt.
+    pub sent: i64,
+    /// Sats that came back to the wallet as change or a reclaimed token.
+    pub returned: Option<i64>,
+}
+
+impl PaidResponse {
+    /// Sats the request actually cost once the change is taken into account.
+    pub fn net_spent(&self) -> i64 {
+        self.sent - self.returned.unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub enum PaymentError {
+    Payment(anyhow::Error),
+    /// The wallet does not hold enough to mint the payment.
+    InsufficientFunds {
+        required: i64,
+    },
+    /// The upstream could not be reached or failed midway. `spent` is what the
+    /// payment cost once any unredeemed token was reclaimed.
+    Upstream {
+        error: UpstreamError,
+        spent: i64,
+    },
+    /// The request would cost more than the per-request spend cap allows.
+    SpendCap {
+        required: i64,
+        cap: i64,
+    },
+}
+
+/// How often a wallet call that failed transiently is tried again.
+#[derive(Clone, Copy, Debug)]
+pub struct WalletRetry {
+    pub retries: u32,
+    pub backoff: Duration,
+    /// Ceiling on a wait the wallet asks for with `Retry-After`.
+    pub max_wait: Duration,
+}
+
+impl Default for WalletRetry {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::ZERO,
+            max_wait: Duration::ZERO,
+        }
+    }
+}
+
+enum WalletFailure {
+    /// The wallet is busy, e.g. swapping proofs, or could not be reached.
+    Transient(Option<Duration>),
+    InsufficientFunds,
+    Fatal,
+}
+
+/// What a wallet says about a token it will never accept, whatever its status code.
+const REJECTED_TOKEN_PHRASES: [&str; 5] = [
+    "already spent",
+    "already redeemed",
+    "unknown mint",
+    "invalid token",
+    "could not decode",
+];
+
+fn classify_wallet_error(error: &anyhow::Error) -> WalletFailure {
+    if let Some(e) = error.downcast_ref::<WalletHttpError>() {
+        let detail = e.detail.to_lowercase();
+        if ["balance too low", "insufficient", "not enough"]
+            .iter()
+            .any(|phrase| detail.contains(phrase))
+        {
+            return WalletFailure::InsufficientFunds;
+        }
+        if REJECTED_TOKEN_PHRASES
+            .iter()
+            .any(|phrase| detail.contains(phrase))
+        {
+            return WalletFailure::Fatal;
+        }
+        return match e.status {
+            408 | 409 | 423 | 425 | 429 | 500..=599 => {
+                WalletFailure::Transient(e.retry_after.map(Duration::from_secs))
+            }
+            _ => WalletFailure::Fatal,
+        };
+    }
+    if error.is::<WalletLockTimeout>() {
+        return WalletFailure::Transient(None);
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_connect() || e.is_timeout() => WalletFailure::Transient(None),
+        _ => WalletFailure::Fatal,
+    }
+}
+
+/// Whether a failed wallet call may succeed when tried again later. A token
+/// that is already spent or comes from a mint the wallet does not know never will.
+pub fn is_transient_wallet_error(error: &anyhow::Error) -> bool {
+    matches!(classify_wallet_error(error), WalletFailure::Transient(_))
+}
+
+/// Runs a wallet call, trying it again with backoff while it fails transiently.
+async fn retry_wallet<T, F, Fut>(
+    retry: WalletRetry,
+    operation: &str,
+    mut call: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        let error = match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let WalletFailure::Transient(retry_after) = classify_wallet_error(&error) else {
+            return Err(error);
+        };
+        if attempt >= retry.retries {
+            return Err(error);
+        }
+        let delay = retry_after
+            .map(|wait| wait.min(retry.max_wait))
+            .unwrap_or_else(|| retry.backoff.saturating_mul(2u32.saturating_pow(attempt)));
+        warn!(
+            operation,
+            attempt = attempt + 1,
+            delay_ms = delay.as_millis() as u64,
+            error = %Redacted(&error),
+            "wallet busy, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Attaches an ecash payment to every outbound request and settles change on
+/// the way back.
+#[derive(Clone)]
+pub struct PaymentLayer {
+    wallet: Arc<dyn PaymentWallet>,
+    headers: PaymentHeaders,
+    amount: i64,
+    max_retry_payment_sats: i64,
+    max_sats_per_request: Option<i64>,
+    l402_enabled: bool,
+    mint: Option<String>,
+    dead_letters: Option<Pool>,
+    free_tier: bool,
+    wallet_retry: WalletRetry,
+}
+
+impl PaymentLayer {
+    pub fn new(
+        wallet: Arc<dyn PaymentWallet>,
+        headers: PaymentHeaders,
+        amount: i64,
+        max_retry_payment_sats: i64,
+    ) -> Self {
+        Self {
+            wallet,
+            headers,
+            amount,
+            max_retry_payment_sats,
+            max_sats_per_request: None,
+            l402_enabled: false,
+            mint: None,
+            dead_letters: None,
+            free_tier: false,
+            wallet_retry: WalletRetry::default(),
+        }
+    }
+
+    /// Refuses to pay more than `cap` sats for a request, including when an
+    /// upstream asks for more on a 402.
+    pub fn with_spend_cap(mut self, cap: i64) -> Self {
+        self.max_sats_per_request = Some(cap);
+        self
+    }
+
+    /// Answers L402 challenges by paying the invoice over lightning.
+    pub fn with_l402(mut self, enabled: bool) -> Self {
+        self.l402_enabled = enabled;
+        self
+    }
+
+    /// Mints payment tokens from `mint` instead of the wallet's default mint.
+    pub fn with_mint(mut self, mint: Option<String>) -> Self {
+        self.mint = mint;
+        self
+    }
+
+    /// Queues tokens that cannot be reclaimed for the dead-letter worker.
+    pub fn with_dead_letters(mut self, pool: Pool) -> Self {
+        self.dead_letters = Some(pool);
+        self
+    }
+
+    /// Sends requests without minting a token, for upstreams that serve the
+    /// model unpaid. An upstream 402 is passed back rather than paid.
+    pub fn with_free_tier(mut self, free_tier: bool) -> Self {
+        self.free_tier = free_tier;
+        self
+    }
+
+    /// Retries minting and receiving change while the wallet is busy.
+    pub fn with_wallet_retry(mut self, retry: WalletRetry) -> Self {
+        self.wallet_retry = retry;
+        self
+    }
+}
+
+impl<S> Layer<S> for PaymentLayer {
+    type Service = PaymentService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PaymentService {
+            inner,
+            wallet: self.wallet.clone(),
+            headers: self.headers.clone(),
+            amount: self.amount,
+            max_retry_payment_sats: self.max_retry_payment_sats,
+            max_sats_per_request: self.max_sats_per_request,
+            l402_enabled: self.l402_enabled,
+            mint: self.mint.clone(),
+            dead_letters: self.dead_letters.clone(),
+            free_tier: self.free_tier,
+            wallet_retry: self.wallet_retry,
+        }
+    }
+}
+
+/// Mints the payment token, retries once if the upstream asks for more, and
+/// either receives the change or reclaims the token depending on the outcome.
+#[derive(Clone)]
+pub struct PaymentService<S> {
+    inner: S,
+    wallet: Arc<dyn PaymentWallet>,
+    headers: PaymentHeaders,
+    amount: i64,
+    max_retry_payment_sats: i64,
+    max_sats_per_request: Option<i64>,
+    l402_enabled: bool,
+    mint: Option<String>,
+    dead_letters: Option<Pool>,
+    free_tier: bool,
+    wallet_retry: WalletRetry,
+}
+
+fn check_spend_cap(cap: Option<i64>, required: i64) -> Result<(), PaymentError> {
+    match cap {
+        Some(cap) if required > cap => {
+            warn!(
+                required,
+                cap, "refusing to pay more than the per-request spend cap"
+            );
+            Err(PaymentError::SpendCap { required, cap })
+        }
+        _ => Ok(()),
+    }
+}
+
+impl<S> Service<reqwest::Request> for PaymentService<S>
+where
+    S: Service<reqwest::Request, Response = reqwest::Response, Error = UpstreamError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    type Response = PaidResponse;
+    type Error = PaymentError;
+    type Future = BoxFuture<'static, Result<PaidResponse, PaymentError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_ready(cx)
+            .map_err(|error| PaymentError::Upstream { error, spent: 0 })
+    }
+
+    fn call(&mut self, request: reqwest::Request) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let wallet = self.wallet.clone();
+        let headers = self.headers.clone();
+        let amount = self.amount;
+        let max_retry_payment_sats = self.max_retry_payment_sats;
+        let max_sats_per_request = self.max_sats_per_request;
+        let l402_enabled = self.l402_enabled;
+        let mint = self.mint.clone();
+        let dead_letters = self.dead_letters.clone();
+        let free_tier = self.free_tier;
+        let wallet_retry = self.wallet_retry;
+
+        Box::pin(async move {
+            if free_tier {
+                let response = inner
+                    .call(request)
+                    .await
+                    .map_err(|error| PaymentError::Upstream { error, spent: 0 })?;
+                return Ok(PaidResponse {
+                    response,
+                    token: None,
+                    sent: 0,
+                    returned: Some(0),
+                });
+            }
+            check_spend_cap(max_sats_per_request, amount)?;
+            let minted = mint_token(
+                wallet.as_ref(),
+                amount,
+                mint.as_deref(),
+                dead_letters.as_ref(),
+                wallet_retry,
+            )
+            .await?;
+            let mut sent = amount;
+            let retry_request = request.try_clone();
+            let mut send_result = inner
+                .call(with_payment(request, &headers.payment, &minted))
+                .await;
+            // The token the latest attempt carried, and so the one left to settle.
+            let mut token = Some(minted);
+
+            if let Ok(resp) = &send_result
+                && resp.status() == StatusCode::PAYMENT_REQUIRED
+                && let Some(mut retry_request) = retry_request
+            {
+                let retry_cap = max_sats_per_request.map_or(max_retry_payment_sats, |cap| {
+                    cap.min(max_retry_payment_sats)
+                });
+                let l402_challenge = l402_enabled
+                    .then(|| L402Challenge::from_headers(resp.headers()))
+                    .flatten();
+
+                if let Some(challenge) = l402_challenge {
+                    if let Some((paid, authorization)) =
+                        l402::pay_challenge(wallet.as_ref(), &challenge, retry_cap).await
+                        && let Ok(authorization) = HeaderValue::from_str(&authorization)
+                    {
+                        info!("retrying with L402 authorization");
+                        let reclaimed = settle_payment(
+                            wallet.as_ref(),
+                            dead_letters.as_ref(),
+                            &headers.change,
+                            wallet_retry,
+                            resp.status(),
+                            resp.headers(),
+                            token.as_deref(),
+                        )
+                        .await;
+                        sent += paid - reclaimed.unwrap_or(0);
+                        // The token is settled; the retry pays with the invoice alone.
+                        token = None;
+                        retry_request
+                            .headers_mut()
+                            .insert(header::AUTHORIZATION, authorization);
+                        send_result = match inner.ready().await {
+                            Ok(ready) => ready.call(retry_request).await,
+                            Err(e) => Err(e),
+                        };
+                    }
+                } else if let Some(required) = required_payment_amount(resp.headers())
+                    && let Err(e) = check_spend_cap(max_sats_per_request, required)
+                {
+                    settle_payment(
+                        wallet.as_ref(),
+                        dead_letters.as_ref(),
+                        &headers.change,
+                        wallet_retry,
+                        resp.status(),
+                        resp.headers(),
+                        token.as_deref(),
+                    )
+                    .await;
+                    return Err(e);
+                } else if let Some(required) = required_payment_amount(resp.headers())
+                    && required <= max_retry_payment_sats
+                    && let Ok(retry_token) = mint_token(
+                        wallet.as_ref(),
+                        required,
+                        mint.as_deref(),
+                        dead_letters.as_ref(),
+                        wallet_retry,
+                    )
+                    .await
+                {
+                    info!(required, "upstream requires a higher payment, retrying");
+                    let reclaimed = settle_payment(
+                        wallet.as_ref(),
+                        dead_letters.as_ref(),
+                        &headers.change,
+                        wallet_retry,
+                        resp.status(),
+                        resp.headers(),
+                        token.as_deref(),
+                    )
+                    .await;
+                    sent += required - reclaimed.unwrap_or(0);
+                    send_result = match inner.ready().await {
+                        Ok(ready) => {
+                            ready
+                                .call(with_payment(retry_request, &headers.payment, &retry_token))
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    token = Some(retry_token);
+                }
+            }
+
+            match send_result {
+                Ok(response) => {
+                    let returned = settle_payment(
+                        wallet.as_ref(),
+                        dead_letters.as_ref(),
+                        &headers.change,
+                        wallet_retry,
+                        response.status(),
+                        response.headers(),
+                        token.as_deref(),
+                    )
+                    .await;
+                    Ok(PaidResponse {
+                        response,
+                        token,
+                        sent,
+                        returned,
+                    })
+                }
+                Err(error) => {
+                    let reclaimed = match &token {
+                        Some(token) => {
+                            reclaim_token(wallet.as_ref(), dead_letters.as_ref(), token).await
+                        }
+                        None => None,
+                    };
+                    Err(PaymentError::Upstream {
+                        error,
+                        spent: sent - reclaimed.unwrap_or(0),
+                    })
+                }
+            }
+        })
+    }
+}
+
+#[instrument(name = "mint_payment", skip(wallet, dead_letters, retry))]
+async fn mint_token(
+    wallet: &dyn PaymentWallet,
+    amount: i64,
+    mint: Option<&str>,
+    dead_letters: Option<&Pool>,
+    retry: WalletRetry,
+) -> Result<String, PaymentError> {
+    match retry_wallet(retry, "send", || wallet.send(amount, mint)).await {
+        Ok(token) => {
+            telemetry::record_sats_sent(amount);
+            if let Err(e) = HeaderValue::from_str(&token.token) {
+                reclaim_token(wallet, dead_letters, &token.token).await;
+                return Err(PaymentError::Payment(anyhow::anyhow!(
+                    "wallet returned a token that is not a valid header value: {}",
+                    e
+                )));
+            }
+            Ok(token.token)
+        }
+        Err(e) => {
+            error!(error = %Redacted(&e), "failed to generate payment token");
+            match classify_wallet_error(&e) {
+                WalletFailure::InsufficientFunds => {
+                    Err(PaymentError::InsufficientFunds { required: amount })
+                }
+                _ => Err(PaymentError::Payment(e)),
+            }
+        }
+    }
+}
+
+fn with_payment(
+    mut request: reqwest::Request,
+    payment_header: &HeaderName,
+    token: &str,
+) -> reqwest::Request {
+    if let Ok(value) = HeaderValue::from_str(token) {
+        request.headers_mut().insert(payment_header.clone(), value);
+    }
+    request
+}
+
+/// Receives the change an upstream returned, or reclaims the whole token, if
+/// the request carried one, when the upstream rejected the request without
+/// returning any. Returns the sats
+/// that made it back into the wallet. Receiving change is retried while the
+/// wallet is busy; a token that cannot be reclaimed goes to the dead-letter queue.
+#[instrument(name = "receive_change", skip_all, fields(status = %status))]
+pub async fn settle_payment(
+    wallet: &dyn PaymentWallet,
+    dead_letters: Option<&Pool>,
+    change_header: &HeaderName,
+    retry: WalletRetry,
+    status: StatusCode,
+    headers: &HeaderMap,
+    token: Option<&str>,
+) -> Option<i64> {
+    if let Some(change_sats) = headers.get(change_header) {
+        let Ok(change_token) = change_sats.to_str() else {
+            warn!("ignoring change token with non-ASCII characters");
+            return None;
+        };
+
+        match retry_wallet(retry, "receive", || wallet.receive(change_token)).await {
+            Ok(res) => {
+                let change = res.balance - res.initial_balance;
+                telemetry::record_change_received(change);
+                info!(balance = res.balance, "received change");
+                Some(change)
+            }
+            Err(e) => {
+                warn!(error = %Redacted(&e), "failed to receive change");
+                None
+            }
+        }
+    } else if let Some(token) = token
+        && !status.is_success()
+    {
+        reclaim_token(wallet, dead_letters, token).await
+    } else {
+        None
+    }
+}
+
+/// Puts the sats of a token the upstream did not redeem back into the wallet,
+/// queueing the token for another try when the wallet cannot take it now. A
+/// token the wallet refuses for good, e.g. because it was already spent, is
+/// not queued.
+pub async fn reclaim_token(
+    wallet: &dyn PaymentWallet,
+    dead_letters: Option<&Pool>,
+    token: &str,
+) -> Option<i64> {
+    match wallet.receive(token).await {
+        Ok(res) => {
+            info!(balance = res.balance, "reclaimed unused token");
+            Some(res.balance - res.initial_balance)
+        }
+        Err(e) if !is_transient_wallet_error(&e) => {
+            warn!(error = %Redacted(&e), "wallet refused the unused token, not queueing it");
+            None
+        }
+        Err(e) => {
+            warn!(error = %Redacted(&e), "failed to reclaim unused token");
+            if let Some(pool) = dead_letters
+                && let Err(db_error) = payment_dlq::insert_entry(
+                    pool,
+                    token,
+                    wallet.base_url(),
+                    &redact(&e.to_string()),
+                )
+                .await
+            {
+                error!(error = %db_error, "failed to queue unreclaimed token");
+            }
+            None
+        }
+    }
+}
+
+/// Reads the price an upstream demands from a 402 response, either from
+/// `X-PRICE-SATS` or from an `amount=` parameter in `WWW-Authenticate`.
+pub fn required_payment_amount(headers: &HeaderMap) -> Option<i64> {
+    if let Some(price) = headers.get(PRICE_HEADER) {
+        return price.to_str().ok()?.trim().parse().ok();
+    }
+
+    let challenge = headers.get(header::WWW_AUTHENTICATE)?.to_str().ok()?;
+    challenge
+        .split([' ', ','])
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("amount"))
+        .and_then(|(_, value)| value.trim_matches('"').parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockUpstream, MockWallet, json_reply, with_change};
+    use crate::upstream::UpstreamService;
+    use serde_json::json;
+
+    fn paid_request() -> reqwest::Request {
+        reqwest::Client::new()
+            .post("http://upstream.test/v1/chat/completions")
+            .body("{}")
+            .build()
+            .unwrap()
+    }
+
+    async fn send(
+        wallet: &Arc<MockWallet>,
+        upstream: &Arc<MockUpstream>,
+        amount: i64,
+    ) -> Result<PaidResponse, PaymentError> {
+        send_with_retry(wallet, upstream, amount, WalletRetry::default()).await
+    }
+
+    async fn send_with_retry(
+        wallet: &Arc<MockWallet>,
+        upstream: &Arc<MockUpstream>,
+        amount: i64,
+        retry: WalletRetry,
+    ) -> Result<PaidResponse, PaymentError> {
+        let wallet: Arc<dyn PaymentWallet> = wallet.clone();
+        PaymentLayer::new(wallet, PaymentHeaders::default(), amount, 50)
+            .with_wallet_retry(retry)
+            .layer(UpstreamService::new(Arc::new(upstream.clone())))
+            .oneshot(paid_request())
+            .await
+    }
+
+    fn retry_once() -> WalletRetry {
+        WalletRetry {
+            retries: 1,
+            backoff: Duration::from_millis(1),
+            max_wait: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn pays_with_a_minted_token_and_receives_the_change() {
+        let wallet = MockWallet::new(100);
+        let change_from = wallet.clone();
+        let upstream = MockUpstream::new(move |_| {
+            with_change(json_reply(StatusCode::OK, &json!({})), &change_from, 3)
+        });
+
+        let paid = send(&wallet, &upstream, 10).await.unwrap();
+
+        assert_eq!(paid.response.status(), StatusCode::OK);
+        assert_eq!(
+            upstream.last_request().header("x-payment-sats"),
+            Some("cashuAmock0")
+        );
+        assert_eq!(
+            (paid.sent, paid.returned, paid.net_spent()),
+            (10, Some(3), 7)
+        );
+        assert_eq!(wallet.current_balance(), 93);
+    }
+
+    #[tokio::test]
+    async fn pays_the_higher_price_an_upstream_asks_for() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::new(|request| match request.header("x-payment-sats") {
+            Some("cashuAmock0") => {
+                let mut reply = json_reply(StatusCode::PAYMENT_REQUIRED, &json!({}))?;
+                reply
+                    .headers_mut()
+                    .insert(PRICE_HEADER, HeaderValue::from(25));
+                Ok(reply)
+            }
+            _ => json_reply(StatusCode::OK, &json!({ "ok": true })),
+        });
+
+        let paid = send(&wallet, &upstream, 10).await.unwrap();
+
+        assert_eq!(paid.response.status(), StatusCode::OK);
+        assert_eq!(wallet.sent(), vec![10, 25]);
+        // The refused first token is reclaimed, so only the retry is paid for.
+        assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+        assert_eq!(paid.net_spent(), 25);
+        assert_eq!(wallet.current_balance(), 75);
+    }
+
+    #[tokio::test]
+    async fn reclaims_the_token_when_the_upstream_cannot_be_reached() {
+        let wallet = MockWallet::new(100);
+        let upstream =
+            MockUpstream::new(|_| Err(UpstreamError::Connect("connection refused".to_string())));
+
+        let error = send(&wallet, &upstream, 10).await.err().unwrap();
+
+        assert!(matches!(error, PaymentError::Upstream { error, spent: 0 } if error.is_connect()));
+        assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+        assert_eq!(wallet.current_balance(), 100);
+    }
+
+    #[tokio::test]
+    async fn settles_the_token_once_when_retrying_under_l402() {
+        let wallet = MockWallet::new(100);
+        let upstream = MockUpstream::new(|request| match request.header("authorization") {
+            Some(_) => json_reply(StatusCode::OK, &json!({})),
+            None => {
+                let mut reply = json_reply(StatusCode::PAYMENT_REQUIRED, &json!({}))?;
+                reply.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"L402 macaroon="mac", invoice="lnbc200n1invoice""#),
+                );
+                Ok(reply)
+            }
+        });
+        let wallet_dyn: Arc<dyn PaymentWallet> = wallet.clone();
+
+        let paid = PaymentLayer::new(wallet_dyn, PaymentHeaders::default(), 10, 50)
+            .with_l402(true)
+            .layer(UpstreamService::new(Arc::new(upstream.clone())))
+            .oneshot(paid_request())
+            .await
+            .unwrap();
+
+        assert_eq!(paid.response.status(), StatusCode::OK);
+        assert_eq!(
+            upstream.last_request().header("authorization"),
+            Some("L402 mac:mockpreimage")
+        );
+        assert_eq!(paid.token, None);
+        assert_eq!(wallet.received(), vec!["cashuAmock0"]);
+        assert_eq!(paid.net_spent(), 20);
+        assert_eq!(wallet.current_balance(), 80);
+    }
+
+    #[tokio::test]
+    async fn counts_a_token_the_upstream_redeemed_before_failing_as_spent() {
+        let wallet = MockWallet::new(100);
+        let redeemed_by = wallet.clone();
+        let upstream = MockUpstream::new(move |request| {
+            redeemed_by.spend(request.header("x-payment-sats").unwrap());
+            Err(UpstreamError::Connect("connection reset".to_string()))
+        });
+
+        let error = send(&wallet, &upstream, 10).await.err().unwrap();
+
+        assert!(matches!(error, PaymentError::Upstream { spent: 10, .. }));
+        assert_eq!(wallet.current_balance(), 90);
+    }
+
+    #[tokio::test]
+    async fn skips_a_change_header_that_is_not_valid_utf8() {
+        let wallet = MockWallet::new(100);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-change-sats",
+            HeaderValue::from_bytes(b"cashuA\xff\xfe").unwrap(),
+        );
+
+        let returned = settle_payment(
+            wallet.as_ref(),
+            None,
+            &PaymentHeaders::default().change,
+            WalletRetry::default(),
+            StatusCode::OK,
+            &headers,
+            Some("cashuAmock0"),
+        )
+        .await;
+
+        assert_eq!(returned, None);
+        assert!(wallet.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_a_send_while_the_wallet_is_busy() {
+        let wallet = MockWallet::new(100);
+        wallet.fail_next_sends(1);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+
+        let paid = send_with_retry(&wallet, &upstream, 10, retry_once())
+            .await
+            .unwrap();
+
+        assert_eq!(paid.response.status(), StatusCode::OK);
+        assert_eq!(wallet.sent(), vec![10]);
+        assert_eq!(
+            upstream.last_request().header("x-payment-sats"),
+            Some("cashuAmock0")
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_fast_when_the_wallet_cannot_cover_the_payment() {
+        let wallet = MockWallet::new(5);
+        let upstream = MockUpstream::json(StatusCode::OK, json!({}));
+
+        let error = send_with_retry(&wallet, &upstream, 10, retry_once())
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            error,
+            PaymentError::InsufficientFunds { required: 10 }
+        ));
+        assert!(upstream.requests().is_empty());
+    }
+}
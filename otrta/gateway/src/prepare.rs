@@ -0,0 +1,241 @@
+//! The model policy and pricing applied to a typed request before it is paid
+//! for. Every typed endpoint and the estimate go through [`prepare`], so a
+//! rule added there reaches all of them.
+
+use crate::{
+    error::ForwardError,
+    pricing::{DEFAULT_PAYMENT_AMOUNT, EndpointType, price_request, scale_by_choices},
+    settings::{RuntimeSettings, Snapshot},
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use wallet::models::{
+    ChatCompletionRequest, CompletionRequest, EmbeddingRequest, ImageGenerationRequest,
+    ModerationRequest, RerankRequest, ResponsesRequest, SpeechRequest,
+};
+
+/// The parts of a typed request the model policy and pricing read or rewrite.
+pub trait ModelRequest: Serialize + Sync {
+    const ENDPOINT: EndpointType;
+
+    /// The requested model, `None` when the request leaves it to the upstream.
+    fn model_mut(&mut self) -> Option<&mut String>;
+
+    /// The token limit the ceiling caps, with the fields that may hold
+    /// `max_completion_tokens`.
+    fn token_limit(&mut self) -> Option<(&mut Option<u32>, &mut HashMap<String, Value>)> {
+        None
+    }
+
+    /// How many choices the request asks for; each is paid for.
+    fn choices(&mut self) -> Option<&mut Option<u32>> {
+        None
+    }
+
+    /// The price when the pricing strategy has none of its own.
+    fn base_price(_runtime: &RuntimeSettings) -> i64 {
+        DEFAULT_PAYMENT_AMOUNT
+    }
+
+    /// The strategy's price adjusted for what one choice of the request asks for.
+    fn adjust_price(&self, amount: i64, _runtime: &RuntimeSettings) -> i64 {
+        amount
+    }
+}
+
+/// Resolves the model alias, refuses a model outside the allow or denylist and
+/// caps the token limit, then prices the request under the snapshot's strategy
+/// and clamps and pays for each choice. Returns the amount to pay; free-tier
+/// models are waived later, when the request is forwarded.
+pub async fn prepare<T: ModelRequest>(
+    snapshot: &Snapshot,
+    request: &mut T,
+) -> Result<i64, ForwardError> {
+    let policy = &snapshot.policy;
+    let runtime = &snapshot.runtime;
+    let model = match request.model_mut() {
+        Some(model) => {
+            policy.aliases.apply(model);
+            policy.access.check(T::ENDPOINT, model)?;
+            Some(model.clone())
+        }
+        None => {
+            policy.access.check_unnamed(T::ENDPOINT)?;
+            None
+        }
+    };
+    if let (Some(model), Some((max_tokens, extra))) = (&model, request.token_limit()) {
+        policy.max_tokens.clamp(model, max_tokens, extra);
+    }
+
+    let amount = price_request(
+        runtime.pricing.as_ref(),
+        T::ENDPOINT,
+        model.as_deref(),
+        T::base_price(runtime),
+        request,
+    )
+    .await;
+    let amount = request.adjust_price(amount, runtime);
+    Ok(match request.choices() {
+        Some(n) => scale_by_choices(amount, n, runtime.max_choices_per_request),
+        None => amount,
+    })
+}
+
+impl ModelRequest for ChatCompletionRequest {
+    const ENDPOINT: EndpointType = EndpointType::ChatCompletions;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+
+    fn token_limit(&mut self) -> Option<(&mut Option<u32>, &mut HashMap<String, Value>)> {
+        Some((&mut self.max_tokens, &mut self.extra))
+    }
+
+    fn choices(&mut self) -> Option<&mut Option<u32>> {
+        Some(&mut self.n)
+    }
+}
+
+impl ModelRequest for CompletionRequest {
+    const ENDPOINT: EndpointType = EndpointType::Completions;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+
+    fn token_limit(&mut self) -> Option<(&mut Option<u32>, &mut HashMap<String, Value>)> {
+        Some((&mut self.max_tokens, &mut self.extra))
+    }
+
+    fn choices(&mut self) -> Option<&mut Option<u32>> {
+        Some(&mut self.n)
+    }
+}
+
+impl ModelRequest for ResponsesRequest {
+    const ENDPOINT: EndpointType = EndpointType::Responses;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+
+    fn token_limit(&mut self) -> Option<(&mut Option<u32>, &mut HashMap<String, Value>)> {
+        Some((&mut self.max_output_tokens, &mut self.extra))
+    }
+}
+
+impl ModelRequest for EmbeddingRequest {
+    const ENDPOINT: EndpointType = EndpointType::Embeddings;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+}
+
+impl ModelRequest for ImageGenerationRequest {
+    const ENDPOINT: EndpointType = EndpointType::ImageGenerations;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+
+    fn choices(&mut self) -> Option<&mut Option<u32>> {
+        Some(&mut self.n)
+    }
+
+    fn adjust_price(&self, amount: i64, runtime: &RuntimeSettings) -> i64 {
+        runtime.image_pricing.price(
+            amount,
+            self.size.as_deref(),
+            self.extra.get("quality").and_then(Value::as_str),
+        )
+    }
+}
+
+impl ModelRequest for SpeechRequest {
+    const ENDPOINT: EndpointType = EndpointType::AudioSpeech;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+}
+
+impl ModelRequest for ModerationRequest {
+    const ENDPOINT: EndpointType = EndpointType::Moderations;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        self.model.as_mut()
+    }
+
+    fn base_price(runtime: &RuntimeSettings) -> i64 {
+        runtime.moderation_payment_sats
+    }
+}
+
+impl ModelRequest for RerankRequest {
+    const ENDPOINT: EndpointType = EndpointType::Rerank;
+
+    fn model_mut(&mut self) -> Option<&mut String> {
+        Some(&mut self.model)
+    }
+
+    fn base_price(runtime: &RuntimeSettings) -> i64 {
+        runtime.rerank_payment_sats
+    }
+
+    /// Rerank cost grows with the number of documents scored, so a
+    /// per-document charge comes on top of the strategy's price.
+    fn adjust_price(&self, amount: i64, runtime: &RuntimeSettings) -> i64 {
+        let documents = i64::try_from(self.documents.len()).unwrap_or(i64::MAX);
+        amount.saturating_add(
+            runtime
+                .rerank_payment_sats_per_document
+                .saturating_mul(documents),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_access::{MaxTokensCeiling, ModelAliases, ModelPolicy};
+    use crate::test_support::runtime_settings;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn clamps_and_prices_an_aliased_model_under_its_target() {
+        let snapshot = Snapshot {
+            policy: Arc::new(ModelPolicy {
+                aliases: ModelAliases::new(HashMap::from([(
+                    "gpt-4".to_string(),
+                    "gpt-4o".to_string(),
+                )])),
+                max_tokens: MaxTokensCeiling::new(
+                    None,
+                    HashMap::from([("gpt-4o".to_string(), 100)]),
+                ),
+                ..ModelPolicy::default()
+            }),
+            runtime: Arc::new(runtime_settings()),
+        };
+        let mut request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-4",
+            "messages": [{ "role": "user", "content": "hi" }],
+            "max_tokens": 500,
+            "n": 9,
+        }))
+        .unwrap();
+
+        let amount = prepare(&snapshot, &mut request).await.unwrap();
+
+        assert_eq!(request.model, "gpt-4o");
+        assert_eq!(request.max_tokens, Some(100));
+        assert_eq!(request.n, Some(4));
+        assert_eq!(amount, 4 * DEFAULT_PAYMENT_AMOUNT);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::db::{Pool, model_pricing};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Amount of sats attached to a forwarded request when no better estimate exists.
+pub const DEFAULT_PAYMENT_AMOUNT: i64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointType {
+    ChatCompletions,
+    Completions,
+    Responses,
+    Embeddings,
+    ImageGenerations,
+    AudioTranscriptions,
+    AudioSpeech,
+    Moderations,
+    Rerank,
+    Files,
+    Models,
+    Passthrough,
+}
+
+impl EndpointType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndpointType::ChatCompletions => "chat_completions",
+            EndpointType::Completions => "completions",
+            EndpointType::Responses => "responses",
+            EndpointType::Embeddings => "embeddings",
+            EndpointType::ImageGenerations => "image_generations",
+            EndpointType::AudioTranscriptions => "audio_transcriptions",
+            EndpointType::AudioSpeech => "audio_speech",
+            EndpointType::Moderations => "moderations",
+            EndpointType::Rerank => "rerank",
+            EndpointType::Files => "files",
+            EndpointType::Models => "models",
+            EndpointType::Passthrough => "passthrough",
+        }
+    }
+}
+
+/// Resolves the amount of sats to send for a request against a given endpoint.
+pub trait PaymentAmount<T> {
+    fn resolve(&self, endpoint: EndpointType, request: Option<&T>) -> i64;
+}
+
+impl<T, F> PaymentAmount<T> for F
+where
+    F: Fn(EndpointType, Option<&T>) -> i64,
+{
+    fn resolve(&self, endpoint: EndpointType, request: Option<&T>) -> i64 {
+        self(endpoint, request)
+    }
+}
+
+pub fn default_payment_amount<T>(_endpoint: EndpointType, _request: Option<&T>) -> i64 {
+    DEFAULT_PAYMENT_AMOUNT
+}
+
+/// A resolver that always returns `amount`, for prices looked up ahead of time.
+pub fn fixed_payment_amount<T>(amount: i64) -> impl Fn(EndpointType, Option<&T>) -> i64 {
+    move |_endpoint, _request| amount
+}
+
+/// Looks up the configured price for `model`, falling back to `fallback` when
+/// the model isn't listed or the lookup fails.
+pub async fn model_payment_amount_or(db: &Pool, model: &str, fallback: i64) -> i64 {
+    match model_pricing::get_price(db, model).await {
+        Ok(Some(price)) => price,
+        Ok(None) => fallback,
+        Err(e) => {
+            warn!(model, error = %e, "failed to look up model price, using default");
+            fallback
+        }
+    }
+}
+
+/// What a [`PricingStrategy`] knows about the request it prices.
+pub struct RequestMeta<'a> {
+    pub model: Option<&'a str>,
+    /// The endpoint's own price when nothing more specific applies: the
+    /// global default, or a setting such as `moderation_payment_sats`.
+    pub base_price: i64,
+    /// The JSON body as it will be sent upstream, when there is one.
+    pub body: Option<&'a Value>,
+}
+
+/// Decides how many sats a request pays the upstream. The active strategy
+/// lives in `AppState`; operators with their own policy implement this.
+#[async_trait]
+pub trait PricingStrategy: Send + Sync {
+    async fn price(&self, endpoint: EndpointType, request: &RequestMeta<'_>) -> i64;
+
+    /// Whether [`RequestMeta::body`] is used. Bodies are only serialized for
+    /// strategies that look at them.
+    fn reads_body(&self) -> bool {
+        false
+    }
+}
+
+/// Prices a typed request body with `strategy`.
+pub async fn price_request<T: Serialize>(
+    strategy: &dyn PricingStrategy,
+    endpoint: EndpointType,
+    model: Option<&str>,
+    base_price: i64,
+    request: &T,
+) -> i64 {
+    let body = strategy
+        .reads_body()
+        .then(|| serde_json::to_value(request).ok())
+        .flatten();
+    let meta = RequestMeta {
+        model,
+        base_price,
+        body: body.as_ref(),
+    };
+    strategy.price(endpoint, &meta).await
+}
+
+/// Clamps a request's `n` to `max` and scales `amount` by it, since every
+/// choice is generated separately. A request without `n` pays for one.
+pub fn scale_by_choices(amount: i64, n: &mut Option<u32>, max: u32) -> i64 {
+    let Some(requested) = *n else {
+        return amount;
+    };
+    let choices = requested.clamp(1, max.max(1));
+    if choices != requested {
+        info!(requested, choices, "clamping n");
+        *n = Some(choices);
+    }
+    amount.saturating_mul(i64::from(choices))
+}
+
+/// Per-image prices for image generations, keyed by `size` (`1024x1024`) or
+/// by size and quality (`1024x1024:hd`), the more specific key winning.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImagePricing {
+    prices: HashMap<String, i64>,
+}
+
+impl ImagePricing {
+    pub fn new(prices: HashMap<String, i64>) -> Self {
+        Self { prices }
+    }
+
+    /// The price of one image, or `base_price` when the table has no entry
+    /// for the requested size and quality.
+    pub fn price(&self, base_price: i64, size: Option<&str>, quality: Option<&str>) -> i64 {
+        let Some(size) = size else {
+            return base_price;
+        };
+        quality
+            .and_then(|quality| self.prices.get(&format!("{}:{}", size, quality)))
+            .or_else(|| self.prices.get(size))
+            .copied()
+            .unwrap_or(base_price)
+    }
+}
+
+/// The built-in strategies, picked with the `pricing_strategy` setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PricingStrategyKind {
+    /// Every request pays its endpoint's base price.
+    #[default]
+    FlatRate,
+    /// The model's price from the `model_pricing` table, else the base price.
+    PerModel,
+    /// Scales with the prompt size and requested completion length.
+    TokenEstimate,
+}
+
+impl PricingStrategyKind {
+    pub fn build(self, db: Pool, sats_per_1k_tokens: i64) -> Arc<dyn PricingStrategy> {
+        match self {
+            PricingStrategyKind::FlatRate => Arc::new(FlatRate),
+            PricingStrategyKind::PerModel => Arc::new(PerModel { db }),
+            PricingStrategyKind::TokenEstimate => Arc::new(TokenEstimate { sats_per_1k_tokens }),
+        }
+    }
+}
+
+pub struct FlatRate;
+
+#[async_trait]
+impl PricingStrategy for FlatRate {
+    async fn price(&self, _endpoint: EndpointType, request: &RequestMeta<'_>) -> i64 {
+        request.base_price
+    }
+}
+
+pub struct PerModel {
+    pub db: Pool,
+}
+
+#[async_trait]
+impl PricingStrategy for PerModel {
+    async fn price(&self, _endpoint: EndpointType, request: &RequestMeta<'_>) -> i64 {
+        match request.model {
+            Some(model) => model_payment_amount_or(&self.db, model, request.base_price).await,
+            None => request.base_price,
+        }
+    }
+}
+
+/// Charges `sats_per_1k_tokens` for the estimated prompt tokens plus the
+/// completion tokens the request allows, and at least one sat. Requests
+/// without a JSON body pay their base price.
+pub struct TokenEstimate {
+    pub sats_per_1k_tokens: i64,
+}
+
+/// Rough characters per token for English text.
+const CHARS_PER_TOKEN: u64 = 4;
+
+#[async_trait]
+impl PricingStrategy for TokenEstimate {
+    async fn price(&self, _endpoint: EndpointType, request: &RequestMeta<'_>) -> i64 {
+        let Some(body) = request.body else {
+            return request.base_price;
+        };
+        let completion = ["max_tokens", "max_completion_tokens", "max_output_tokens"]
+            .iter()
+            .find_map(|key| body.get(key).and_then(Value::as_u64))
+            .unwrap_or(0);
+        let tokens = estimate_prompt_tokens(body).saturating_add(completion);
+        let sats = tokens
+            .saturating_mul(self.sats_per_1k_tokens.max(0) as u64)
+            .div_ceil(1000);
+        i64::try_from(sats).unwrap_or(i64::MAX).max(1)
+    }
+
+    fn reads_body(&self) -> bool {
+        true
+    }
+}
+
+/// Counts the text a request body carries, leaving out the model name and
+/// inline `data:` URLs, whose base64 says nothing about token count.
+pub fn estimate_prompt_tokens(body: &Value) -> u64 {
+    fn text_chars(value: &Value) -> u64 {
+        match value {
+            Value::String(text) if text.starts_with("data:") => 0,
+            Value::String(text) => text.chars().count() as u64,
+            Value::Array(items) => items.iter().map(text_chars).sum(),
+            Value::Object(fields) => fields
+                .iter()
+                .filter(|(key, _)| key.as_str() != "model")
+                .map(|(_, value)| text_chars(value))
+                .sum(),
+            _ => 0,
+        }
+    }
+    text_chars(body).div_ceil(CHARS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_image_generations_by_size_quality_and_count() {
+        let pricing = ImagePricing::new(HashMap::from([
+            ("256x256".to_string(), 4),
+            ("1024x1024".to_string(), 20),
+            ("1024x1024:hd".to_string(), 40),
+        ]));
+        let cases = [
+            (None, None, None, 10),
+            (Some("256x256"), None, None, 4),
+            (Some("256x256"), Some("hd"), None, 4),
+            (Some("1024x1024"), Some("standard"), None, 20),
+            (Some("1024x1024"), Some("hd"), None, 40),
+            (Some("1024x1024"), Some("hd"), Some(2), 80),
+            // Clamped to four choices.
+            (Some("256x256"), None, Some(9), 16),
+            (Some("512x512"), None, None, 10),
+        ];
+
+        for (size, quality, n, expected) in cases {
+            let mut n = n;
+            let amount = scale_by_choices(pricing.price(10, size, quality), &mut n, 4);
+
+            assert_eq!(amount, expected, "{size:?} {quality:?}");
+        }
+    }
+}
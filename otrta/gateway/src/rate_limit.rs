@@ -0,0 +1,98 @@
+use crate::{auth::AuthenticatedKey, models::AppState, telemetry};
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets keyed by client API key. Each bucket holds up to `capacity`
+/// requests and refills at `capacity` per `window`.
+pub struct RateLimiter {
+    default_capacity: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default_capacity: u32, window: Duration) -> Self {
+        Self {
+            default_capacity,
+            window,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long until one is available.
+    pub fn acquire(&self, key: &str, capacity: Option<u32>) -> Result<(), Duration> {
+        let capacity = f64::from(capacity.unwrap_or(self.default_capacity).max(1));
+        let refill_per_sec = capacity / self.window.as_secs_f64().max(f64::EPSILON);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_sec,
+            ))
+        }
+    }
+}
+
+/// Throttles authenticated clients before their request reaches a handler, so
+/// throttled requests never spend sats.
+pub async fn enforce(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let Some(key) = request.extensions().get::<AuthenticatedKey>() else {
+        return next.run(request).await;
+    };
+
+    if let Err(retry_after) = state.rate_limiter.acquire(&key.id, key.rate_limit) {
+        warn!(key = %key.name, "client API key is rate limited");
+        telemetry::record_rate_limited();
+
+        let retry_after_secs = retry_after.as_secs().max(1);
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "error": {
+                    "message": format!(
+                        "Rate limit exceeded, retry after {} seconds",
+                        retry_after_secs
+                    ),
+                    "type": "rate_limit_error",
+                    "param": null,
+                    "code": "rate_limit_exceeded",
+                }
+            })),
+        )
+            .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+        return response;
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,84 @@
+//! Keeps credentials and ecash out of logs and client-facing error messages.
+//! A Cashu token is a bearer instrument: anyone who reads one can spend it.
+
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+
+const MASK: &str = "[REDACTED]";
+const TOKEN_PREFIXES: [&str; 2] = ["cashuA", "cashuB"];
+const BEARER_PREFIX: &str = "Bearer ";
+/// Characters of a token kept by [`mask_token`], enough to show its version.
+const TOKEN_MASK_PREFIX_CHARS: usize = 10;
+
+/// Characters of a serialized token's base64 payload.
+pub fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'+' | b'/' | b'=')
+}
+
+/// `text` with every Cashu token and bearer credential masked.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut out = String::new();
+    let mut rest = text;
+    let mut redacted = false;
+    while let Some((start, prefix)) = next_secret(rest) {
+        let payload = &rest[start + prefix.len()..];
+        let len = payload.bytes().take_while(|&b| is_token_byte(b)).count();
+        if len == 0 {
+            out.push_str(&rest[..start + prefix.len()]);
+        } else {
+            out.push_str(&rest[..start]);
+            if prefix == BEARER_PREFIX {
+                out.push_str(BEARER_PREFIX);
+            }
+            out.push_str(MASK);
+            redacted = true;
+        }
+        rest = &payload[len..];
+    }
+    if !redacted {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// A credential reduced to its last four characters, enough to tell keys
+/// apart without exposing them.
+pub fn mask_secret(secret: &str) -> String {
+    let chars = secret.chars().count();
+    if chars == 0 {
+        return String::new();
+    }
+    if chars <= 8 {
+        return MASK.to_string();
+    }
+    let tail: String = secret.chars().skip(chars - 4).collect();
+    format!("{}{}", MASK, tail)
+}
+
+/// A token reduced to its first characters and a digest of the whole, enough
+/// to match it against wallet logs without handing out something spendable.
+pub fn mask_token(token: &str) -> String {
+    let prefix: String = token.chars().take(TOKEN_MASK_PREFIX_CHARS).collect();
+    let digest = hex::encode(Sha256::digest(token.as_bytes()));
+    format!("{}...sha256:{}", prefix, &digest[..16])
+}
+
+fn next_secret(text: &str) -> Option<(usize, &'static str)> {
+    TOKEN_PREFIXES
+        .iter()
+        .chain(std::iter::once(&BEARER_PREFIX))
+        .filter_map(|prefix| text.find(prefix).map(|start| (start, *prefix)))
+        .min_by_key(|(start, _)| *start)
+}
+
+/// Displays a value with [`redact`] applied, for log fields and error
+/// messages: `warn!(error = %Redacted(&e), ...)`.
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&redact(&self.0.to_string()))
+    }
+}
@@ -0,0 +1,117 @@
+//! Picks up configuration changes without a restart.
+//!
+//! The server config, model prices and client API keys already come from the
+//! database on every request. What the process holds on to is the model
+//! policy from the configuration files (allowlists, aliases, token ceilings,
+//! free models) and the runtime settings (pricing strategy, spend caps and
+//! timeouts), so those are what a reload refreshes, and only when they
+//! changed. The cached `/v1/models` listing depends on the upstream alone and
+//! is dropped when the server config is edited instead. Settings that shape
+//! long-lived resources, such as the listener, the HTTP clients or the
+//! database pool, still need a restart.
+
+use crate::{
+    connection::{ApplicationSettings, get_configuration},
+    model_access::ModelPolicy,
+    models::AppState,
+    settings::RuntimeSettings,
+};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, warn};
+
+/// Which parts of the configuration a reload swapped in.
+#[derive(Debug, Serialize)]
+pub struct ReloadReport {
+    pub model_policy: bool,
+    pub runtime_settings: bool,
+}
+
+/// Re-reads the configuration files and environment and swaps in the model
+/// policy and runtime settings that differ from the ones in force. A
+/// configuration that fails to parse leaves the running one in place.
+pub fn reload(state: &AppState) -> Result<ReloadReport, config::ConfigError> {
+    let settings = get_configuration()?;
+    Ok(apply(state, &settings.application))
+}
+
+fn apply(state: &AppState, settings: &ApplicationSettings) -> ReloadReport {
+    let policy = ModelPolicy::from_settings(settings);
+    let model_policy = *state.model_policy.current() != policy;
+    if model_policy {
+        state.model_policy.replace(policy);
+    }
+
+    let runtime = RuntimeSettings::from_settings(settings, state.db.clone());
+    let runtime_settings = *state.runtime.current() != runtime;
+    if runtime_settings {
+        state.runtime.replace(runtime);
+    }
+
+    ReloadReport {
+        model_policy,
+        runtime_settings,
+    }
+}
+
+/// Reloads every `interval` until the process exits.
+pub fn spawn(state: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick fires at once and there is nothing new to load yet.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match reload(&state) {
+                Ok(report) => debug!(?report, "reloaded configuration"),
+                Err(e) => {
+                    warn!(error = %e, "failed to reload configuration, keeping the current one")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockUpstream, MockWallet, app_state};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    fn base_settings() -> ApplicationSettings {
+        config::Config::builder()
+            .add_source(config::File::from_str(
+                include_str!("../configuration/base.yaml"),
+                config::FileFormat::Yaml,
+            ))
+            .add_source(config::File::from_str(
+                include_str!("../configuration/production.yaml"),
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .get("application")
+            .unwrap()
+    }
+
+    #[test]
+    fn swaps_in_only_the_settings_that_changed() {
+        let state = app_state(
+            MockUpstream::json(StatusCode::OK, json!({})),
+            MockWallet::new(100),
+        );
+        let mut settings = base_settings();
+
+        apply(&state, &settings);
+        let unchanged = apply(&state, &settings);
+        let runtime = state.runtime.current();
+        settings.free_models.push("gpt-4o-mini".to_string());
+        let policy_only = apply(&state, &settings);
+
+        assert!(!unchanged.model_policy && !unchanged.runtime_settings);
+        assert!(policy_only.model_policy && !policy_only.runtime_settings);
+        assert!(Arc::ptr_eq(&runtime, &state.runtime.current()));
+        assert!(state.model_policy.current().is_free("gpt-4o-mini"));
+    }
+}
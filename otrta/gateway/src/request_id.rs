@@ -0,0 +1,53 @@
+//! Correlation IDs that follow a request through the gateway to the upstream.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{Instrument, info_span};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client-supplied ID that is kept instead of replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The correlation ID of the current request, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Reads the request's ID header, if it holds a usable ID.
+pub fn from_headers(headers: &HeaderMap) -> Option<&HeaderValue> {
+    headers.get(REQUEST_ID_HEADER).filter(|value| {
+        !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value
+                .to_str()
+                .is_ok_and(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+    })
+}
+
+/// Keeps the client's `X-Request-ID` or assigns a fresh UUID, runs the rest of
+/// the request inside a span carrying it, and echoes it on the response.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let id = match from_headers(request.headers()) {
+        Some(value) => value.clone(),
+        None => {
+            let value = HeaderValue::try_from(Uuid::new_v4().to_string())
+                .expect("a UUID is a valid header value");
+            request
+                .headers_mut()
+                .insert(REQUEST_ID_HEADER, value.clone());
+            value
+        }
+    };
+    let id_str = id.to_str().unwrap_or_default().to_string();
+    request.extensions_mut().insert(RequestId(id_str.clone()));
+
+    let span = info_span!("request", request_id = %id_str);
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    response
+}
@@ -0,0 +1,111 @@
+use crate::{
+    connection::ApplicationSettings,
+    db::Pool,
+    model_access::ModelPolicy,
+    models::AppState,
+    pricing::{ImagePricing, PricingStrategy, PricingStrategyKind},
+};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The prices, spend caps and timeouts from the configuration file, swapped
+/// as one on reload.
+pub struct RuntimeSettings {
+    pub pricing: Arc<dyn PricingStrategy>,
+    /// What `pricing` was built from, so a reload can tell whether it changed.
+    pub pricing_strategy: PricingStrategyKind,
+    pub token_estimate_sats_per_1k_tokens: i64,
+    pub image_pricing: ImagePricing,
+    pub moderation_payment_sats: i64,
+    pub rerank_payment_sats: i64,
+    pub rerank_payment_sats_per_document: i64,
+    pub max_retry_payment_sats: i64,
+    pub max_sats_per_request: i64,
+    pub max_choices_per_request: u32,
+    pub daily_budget_sats: Option<i64>,
+    pub request_timeout: Duration,
+    pub streaming_timeout: Duration,
+    pub max_request_timeout: Duration,
+    pub stream_idle_timeout: Duration,
+}
+
+impl RuntimeSettings {
+    pub fn from_settings(settings: &ApplicationSettings, db: Pool) -> Self {
+        Self {
+            pricing: settings
+                .pricing_strategy
+                .build(db, settings.token_estimate_sats_per_1k_tokens),
+            pricing_strategy: settings.pricing_strategy,
+            token_estimate_sats_per_1k_tokens: settings.token_estimate_sats_per_1k_tokens,
+            image_pricing: ImagePricing::new(settings.image_prices.clone()),
+            moderation_payment_sats: settings.moderation_payment_sats,
+            rerank_payment_sats: settings.rerank_payment_sats,
+            rerank_payment_sats_per_document: settings.rerank_payment_sats_per_document,
+            max_retry_payment_sats: settings.max_retry_payment_sats,
+            max_sats_per_request: settings.max_sats_per_request,
+            max_choices_per_request: settings.max_choices_per_request,
+            daily_budget_sats: settings.daily_budget_sats,
+            request_timeout: Duration::from_millis(settings.request_timeout_ms),
+            streaming_timeout: Duration::from_millis(settings.streaming_timeout_ms),
+            max_request_timeout: Duration::from_millis(settings.max_request_timeout_ms),
+            stream_idle_timeout: Duration::from_millis(settings.stream_idle_timeout_ms),
+        }
+    }
+}
+
+/// Compares everything but the built strategy, which follows from
+/// `pricing_strategy` and `token_estimate_sats_per_1k_tokens`.
+impl PartialEq for RuntimeSettings {
+    fn eq(&self, other: &Self) -> bool {
+        self.pricing_strategy == other.pricing_strategy
+            && self.token_estimate_sats_per_1k_tokens == other.token_estimate_sats_per_1k_tokens
+            && self.image_pricing == other.image_pricing
+            && self.moderation_payment_sats == other.moderation_payment_sats
+            && self.rerank_payment_sats == other.rerank_payment_sats
+            && self.rerank_payment_sats_per_document == other.rerank_payment_sats_per_document
+            && self.max_retry_payment_sats == other.max_retry_payment_sats
+            && self.max_sats_per_request == other.max_sats_per_request
+            && self.max_choices_per_request == other.max_choices_per_request
+            && self.daily_budget_sats == other.daily_budget_sats
+            && self.request_timeout == other.request_timeout
+            && self.streaming_timeout == other.streaming_timeout
+            && self.max_request_timeout == other.max_request_timeout
+            && self.stream_idle_timeout == other.stream_idle_timeout
+    }
+}
+
+/// The runtime settings in force. Like the model policy, requests take a
+/// snapshot, so a reload never reprices or re-times one already underway.
+pub struct LiveSettings(RwLock<Arc<RuntimeSettings>>);
+
+impl LiveSettings {
+    pub fn new(settings: RuntimeSettings) -> Self {
+        Self(RwLock::new(Arc::new(settings)))
+    }
+
+    pub fn current(&self) -> Arc<RuntimeSettings> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, settings: RuntimeSettings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+/// The model policy and runtime settings one request is handled under, read
+/// once when it arrives and passed down with it, so a reload landing midway
+/// cannot alias a model under one configuration and price it under another.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub policy: Arc<ModelPolicy>,
+    pub runtime: Arc<RuntimeSettings>,
+}
+
+impl Snapshot {
+    pub fn current(state: &AppState) -> Self {
+        Self {
+            policy: state.model_policy.current(),
+            runtime: state.runtime.current(),
+        }
+    }
+}
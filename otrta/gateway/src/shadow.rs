@@ -0,0 +1,119 @@
+use crate::{
+    payment::{PaymentError, PaymentHeaders, PaymentLayer},
+    pricing::EndpointType,
+    telemetry,
+    upstream::{UpstreamClient, UpstreamService},
+    wallets::PaymentWallet,
+};
+use axum::http::header;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::{debug, warn};
+
+/// Mirrors a sample of requests to a second endpoint so a provider can be
+/// compared on real traffic. Shadow responses are discarded; only their
+/// status and latency are recorded.
+pub struct ShadowTraffic {
+    endpoint: String,
+    api_key: Option<String>,
+    sample_rate: f64,
+    payment_sats: Option<i64>,
+    timeout: Duration,
+    wallet: Arc<dyn PaymentWallet>,
+    http_client: reqwest::Client,
+    upstream: Arc<dyn UpstreamClient>,
+}
+
+impl ShadowTraffic {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        endpoint: String,
+        api_key: Option<String>,
+        sample_rate: f64,
+        payment_sats: Option<i64>,
+        timeout: Duration,
+        wallet: Arc<dyn PaymentWallet>,
+        http_client: reqwest::Client,
+        upstream: Arc<dyn UpstreamClient>,
+    ) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            payment_sats,
+            timeout,
+            wallet,
+            http_client,
+            upstream,
+        }
+    }
+
+    /// Sends a copy of `body` to the shadow endpoint in the background when the
+    /// request is sampled. Never blocks or fails the primary request.
+    pub fn mirror(&self, path: &str, endpoint_type: EndpointType, body: &impl serde::Serialize) {
+        if rand::random::<f64>() >= self.sample_rate {
+            return;
+        }
+
+        let mut builder = self
+            .http_client
+            .post(format!("{}{}", self.endpoint, path))
+            .timeout(self.timeout)
+            .json(body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", api_key));
+        }
+        let request = match builder.build() {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(error = %e, "failed to build shadow request");
+                return;
+            }
+        };
+
+        let upstream = self.upstream.clone();
+        let payment = self.payment_sats.map(|sats| {
+            PaymentLayer::new(self.wallet.clone(), PaymentHeaders::default(), sats, 0)
+                .with_spend_cap(sats)
+        });
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let status = match payment {
+                Some(payment) => ServiceBuilder::new()
+                    .layer(payment)
+                    .service(UpstreamService::new(upstream))
+                    .oneshot(request)
+                    .await
+                    .map(|paid| paid.response)
+                    .map_err(|e| match e {
+                        PaymentError::Upstream { error, .. } => error.to_string(),
+                        PaymentError::Payment(e) => e.to_string(),
+                        PaymentError::InsufficientFunds { required } => {
+                            format!("wallet cannot cover the {} sat shadow payment", required)
+                        }
+                        PaymentError::SpendCap { required, cap } => {
+                            format!("shadow requires {} sats, above {}", required, cap)
+                        }
+                    }),
+                None => upstream.send(request).await.map_err(|e| e.to_string()),
+            };
+
+            let status = match status {
+                Ok(response) => {
+                    let status = response.status();
+                    // Drain the body so the latency covers the whole response.
+                    let _ = response.bytes().await;
+                    status.as_u16().to_string()
+                }
+                Err(e) => {
+                    debug!(error = %e, "shadow request failed");
+                    "error".to_string()
+                }
+            };
+            let latency = started.elapsed();
+            debug!(endpoint = endpoint_type.as_str(), status = %status, ?latency, "shadow request finished");
+            telemetry::record_shadow_request(endpoint_type, &status, latency);
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use crate::telemetry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::info;
+
+/// Counts streaming responses that are still relaying upstream data, mirrored
+/// to the `gateway_active_streams` gauge.
+#[derive(Clone, Default)]
+pub struct ActiveStreams(Arc<AtomicUsize>);
+
+impl ActiveStreams {
+    pub fn track(&self) -> StreamGuard {
+        let active = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+        telemetry::record_active_streams(active);
+        StreamGuard(self.0.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks one stream as active until dropped.
+pub struct StreamGuard(Arc<AtomicUsize>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let active = self.0.fetch_sub(1, Ordering::SeqCst) - 1;
+        telemetry::record_active_streams(active);
+    }
+}
+
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler.")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutdown signal received, draining in-flight requests");
+}
@@ -0,0 +1,224 @@
+use axum::body::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use serde_json::Value;
+
+/// One `data:` payload of an OpenAI-style event stream.
+pub enum SseEvent {
+    Data(Value),
+    Done,
+}
+
+/// Splits an event stream into its `data:` payloads as chunks arrive. Upstream
+/// chunk boundaries fall anywhere, often mid-way through a tool call delta, so
+/// partial lines are held back, and an event spread over several `data:` lines
+/// is joined until it parses or a blank line ends it. Only streams that are
+/// rewritten go through here; untouched streams are relayed byte for byte.
+#[derive(Default)]
+pub struct SseParser {
+    pending: Vec<u8>,
+    data: String,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.pending.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.push_line(&String::from_utf8_lossy(&line), &mut events);
+        }
+        events
+    }
+
+    /// Parses whatever is left once the upstream ends, for streams whose last
+    /// event lacks its trailing newline.
+    pub fn finish(&mut self) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        let rest = std::mem::take(&mut self.pending);
+        self.push_line(&String::from_utf8_lossy(&rest), &mut events);
+        self.dispatch(&mut events);
+        events
+    }
+
+    fn push_line(&mut self, line: &str, events: &mut Vec<SseEvent>) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+        let Some(data) = line.strip_prefix("data:") else {
+            return;
+        };
+        if !self.data.is_empty() {
+            self.data.push('\n');
+        }
+        self.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+        let data = self.data.trim();
+        if data == "[DONE]" || serde_json::from_str::<Value>(data).is_ok() {
+            self.dispatch(events);
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<SseEvent>) {
+        let data = std::mem::take(&mut self.data);
+        let data = data.trim();
+        if data == "[DONE]" {
+            events.push(SseEvent::Done);
+        } else if let Ok(value) = serde_json::from_str(data) {
+            events.push(SseEvent::Data(value));
+        }
+    }
+}
+
+/// Token counts from a completion's `usage` object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+impl TokenUsage {
+    /// Reads chat-style `prompt_tokens`/`completion_tokens`, or the Responses
+    /// API's `input_tokens`/`output_tokens`.
+    pub fn from_usage(usage: &Value) -> Option<Self> {
+        let count = |keys: [&str; 2]| keys.iter().find_map(|key| usage.get(key)?.as_i64());
+        let prompt_tokens = count(["prompt_tokens", "input_tokens"]);
+        let completion_tokens = count(["completion_tokens", "output_tokens"]);
+        if prompt_tokens.is_none() && completion_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            prompt_tokens: prompt_tokens.unwrap_or(0),
+            completion_tokens: completion_tokens.unwrap_or(0),
+        })
+    }
+
+    /// Reads `usage` from a buffered, non-streamed JSON response body.
+    /// Responses API bodies carry it at the top level too.
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let value: Value = serde_json::from_slice(body).ok()?;
+        Self::from_usage(value.get("usage")?)
+    }
+}
+
+/// Watches a passing event stream for the `usage` object most providers send
+/// in the final chunk, without touching the bytes themselves.
+#[derive(Default)]
+pub struct UsageTracker {
+    parser: SseParser,
+    usage: Option<TokenUsage>,
+}
+
+impl UsageTracker {
+    pub fn observe(&mut self, chunk: &[u8]) {
+        for event in self.parser.push(chunk) {
+            // Responses API streams report usage inside `response.completed`.
+            if let SseEvent::Data(data) = event
+                && let Some(usage) = data
+                    .get("usage")
+                    .or_else(|| data.pointer("/response/usage"))
+                    .and_then(TokenUsage::from_usage)
+            {
+                self.usage = Some(usage);
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+}
+
+/// Rewrites a streamed body chunk by chunk.
+pub trait StreamTranslator: Send + 'static {
+    fn push(&mut self, chunk: &[u8]) -> String;
+
+    /// Emits whatever closes the translated stream; called once the upstream ends.
+    fn finish(&mut self) -> String;
+}
+
+/// Runs every chunk of `upstream` through `translator`, skipping chunks that
+/// translate to nothing.
+pub fn translate_stream<S, E, T>(upstream: S, translator: T) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Unpin + 'static,
+    T: StreamTranslator,
+{
+    stream::unfold(
+        (upstream, translator, false),
+        |(mut upstream, mut translator, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match upstream.next().await {
+                    Some(Ok(chunk)) => {
+                        let out = translator.push(&chunk);
+                        if !out.is_empty() {
+                            return Some((Ok(Bytes::from(out)), (upstream, translator, false)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (upstream, translator, true))),
+                    None => {
+                        let out = translator.finish();
+                        return (!out.is_empty())
+                            .then(|| (Ok(Bytes::from(out)), (upstream, translator, true)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data(events: Vec<SseEvent>) -> Vec<Value> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                SseEvent::Data(value) => value,
+                SseEvent::Done => json!("[DONE]"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reassembles_events_split_at_any_byte() {
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,",
+            "\"function\":{\"name\":\"get_weather\",\"arguments\":\"{\\\"city\\\":\"}}]}}]}\r\n\r\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,",
+            "\"function\":{\"arguments\":\"\\\"Zürich\\\"}\"}}]}}]}\n\n",
+            "data: [DONE]\n\n",
+        )
+        .as_bytes();
+        let whole = data(SseParser::default().push(stream));
+        assert_eq!(whole.len(), 3);
+        assert_eq!(
+            whole[1]["choices"][0]["delta"]["tool_calls"][0]["function"]["arguments"],
+            "\"Zürich\"}"
+        );
+
+        // Splits land inside `data:`, the JSON, the `\r\n` pair and `ü`.
+        for split in 1..stream.len() {
+            let mut parser = SseParser::default();
+            let mut events = data(parser.push(&stream[..split]));
+            events.extend(data(parser.push(&stream[split..])));
+            events.extend(data(parser.finish()));
+            assert_eq!(events, whole, "split at byte {split}");
+        }
+    }
+
+    #[test]
+    fn joins_an_event_spread_over_several_data_lines() {
+        let mut parser = SseParser::default();
+        let mut events = data(parser.push(b"data: {\"choices\":\ndata: []}\n"));
+        events.extend(data(parser.push(b"\ndata: {\"id\":1}")));
+        events.extend(data(parser.finish()));
+
+        assert_eq!(events, vec![json!({ "choices": [] }), json!({ "id": 1 })]);
+    }
+}
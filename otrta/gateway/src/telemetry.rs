@@ -0,0 +1,157 @@
+use crate::pricing::EndpointType;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::{global, propagation::Injector, trace::TracerProvider as _};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use std::time::Duration;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+
+const UPSTREAM_LATENCY: &str = "gateway_upstream_latency_seconds";
+const SHADOW_LATENCY: &str = "gateway_shadow_latency_seconds";
+const REQUESTS_IN_FLIGHT: &str = "gateway_requests_in_flight";
+const ACTIVE_STREAMS: &str = "gateway_active_streams";
+
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(UPSTREAM_LATENCY.to_string()), LATENCY_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(SHADOW_LATENCY.to_string()), LATENCY_BUCKETS)?
+        .install_recorder()
+}
+
+pub type OtelLayer<S> = OpenTelemetryLayer<S, SdkTracer>;
+
+/// Flushes buffered spans when dropped at the end of `main`.
+pub struct TracerGuard(SdkTracerProvider);
+
+impl Drop for TracerGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("failed to flush OpenTelemetry spans: {}", e);
+        }
+    }
+}
+
+/// Builds the layer exporting spans over OTLP/HTTP, when `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set. The exporter reads the rest of the standard `OTEL_*` variables itself.
+pub fn install_tracer<S>() -> Result<Option<(OtelLayer<S>, TracerGuard)>, ExporterBuildError>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "gateway".to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("gateway"));
+    Ok(Some((layer, TracerGuard(provider))))
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Writes the current span's trace context into `headers` as `traceparent`.
+/// Does nothing unless [`install_tracer`] set up a propagator.
+pub fn inject_trace_context(headers: &mut HeaderMap) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Counts a request towards `gateway_requests_in_flight` for its endpoint
+/// until the returned guard is dropped, which for a streamed response is when
+/// its relay task finishes.
+pub fn track_in_flight(endpoint: EndpointType) -> InFlightGuard {
+    gauge!(REQUESTS_IN_FLIGHT, "endpoint" => endpoint.as_str()).increment(1.0);
+    InFlightGuard(endpoint)
+}
+
+pub struct InFlightGuard(EndpointType);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        gauge!(REQUESTS_IN_FLIGHT, "endpoint" => self.0.as_str()).decrement(1.0);
+    }
+}
+
+pub fn record_active_streams(count: usize) {
+    gauge!(ACTIVE_STREAMS).set(count as f64);
+}
+
+pub fn record_wallet_balance(wallet_url: &str, balance: i64) {
+    gauge!("gateway_wallet_balance_sats", "wallet" => wallet_url.to_string()).set(balance as f64);
+}
+
+pub fn record_sats_sent(amount: i64) {
+    counter!("gateway_sats_sent_total").increment(amount.max(0) as u64);
+}
+
+pub fn record_change_received(amount: i64) {
+    counter!("gateway_sats_change_received_total").increment(amount.max(0) as u64);
+}
+
+pub fn record_cache_hit() {
+    counter!("gateway_models_cache_hits_total").increment(1);
+}
+
+pub fn record_cache_miss() {
+    counter!("gateway_models_cache_misses_total").increment(1);
+}
+
+pub fn record_embeddings_cache_hit(sats_saved: i64) {
+    counter!("gateway_embeddings_cache_hits_total").increment(1);
+    counter!("gateway_embeddings_cache_sats_saved_total").increment(sats_saved.max(0) as u64);
+}
+
+pub fn record_embeddings_cache_miss() {
+    counter!("gateway_embeddings_cache_misses_total").increment(1);
+}
+
+pub fn record_rate_limited() {
+    counter!("gateway_rate_limited_total").increment(1);
+}
+
+pub fn record_upstream_request(endpoint: EndpointType, status: &str, latency: Duration) {
+    counter!(
+        "gateway_requests_total",
+        "endpoint" => endpoint.as_str(),
+        "status" => status.to_string()
+    )
+    .increment(1);
+    histogram!(UPSTREAM_LATENCY, "endpoint" => endpoint.as_str()).record(latency.as_secs_f64());
+}
+
+pub fn record_shadow_request(endpoint: EndpointType, status: &str, latency: Duration) {
+    counter!(
+        "gateway_shadow_requests_total",
+        "endpoint" => endpoint.as_str(),
+        "status" => status.to_string()
+    )
+    .increment(1);
+    histogram!(SHADOW_LATENCY, "endpoint" => endpoint.as_str()).record(latency.as_secs_f64());
+}
@@ -0,0 +1,494 @@
+//! Stand-ins for the upstream, the wallet and the database, so the forward
+//! and payment paths can be exercised without network access.
+
+use crate::{
+    cache::{ResponseCache, SingleFlight},
+    cancel::StreamCancels,
+    circuit_breaker::CircuitBreakers,
+    consolidation::Consolidator,
+    credits::CreditMode,
+    db::server_config::{ServerConfigRecord, ServerConfigSource},
+    headers::HeaderAllowlist,
+    l402,
+    model_access::{ModelPolicies, ModelPolicy},
+    models::AppState,
+    payment::WalletRetry,
+    pricing::{ImagePricing, PricingStrategyKind},
+    rate_limit::RateLimiter,
+    settings::{LiveSettings, RuntimeSettings},
+    shutdown::ActiveStreams,
+    upstream::{UpstreamClient, UpstreamError},
+    wallets::{PaymentWallet, WalletRegistry},
+};
+use axum::http::Request;
+use axum::response::Response;
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+};
+use futures::future::BoxFuture;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use wallet::api::{
+    BalanceResponse, CashuWalletClient, PaymentResponse, PaymentResult, ReceiveResponse,
+    SendResponse, WalletHttpError,
+};
+
+/// A request as the mock upstream received it.
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub url: reqwest::Url,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl RecordedRequest {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("request body is JSON")
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+pub type Reply = Result<axum::http::Response<reqwest::Body>, UpstreamError>;
+type Handler = Box<dyn Fn(&RecordedRequest) -> Reply + Send + Sync>;
+
+/// An upstream that answers every request with `handler` and keeps what it
+/// was sent. A reply slower than the request's timeout fails as reqwest's would.
+pub struct MockUpstream {
+    handler: Handler,
+    delay: Duration,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+impl MockUpstream {
+    pub fn new(handler: impl Fn(&RecordedRequest) -> Reply + Send + Sync + 'static) -> Arc<Self> {
+        Self::delayed(Duration::ZERO, handler)
+    }
+
+    pub fn delayed(
+        delay: Duration,
+        handler: impl Fn(&RecordedRequest) -> Reply + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            handler: Box::new(handler),
+            delay,
+            requests: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Answers every request with `status` and `body`.
+    pub fn json(status: StatusCode, body: Value) -> Arc<Self> {
+        Self::new(move |_| json_reply(status, &body))
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn last_request(&self) -> RecordedRequest {
+        self.requests().pop().expect("the upstream was called")
+    }
+
+    async fn record(
+        &self,
+        mut request: reqwest::Request,
+    ) -> Result<RecordedRequest, UpstreamError> {
+        let body = match request.body_mut().take() {
+            Some(body) => axum::body::to_bytes(axum::body::Body::new(body), usize::MAX)
+                .await
+                .map_err(|e| UpstreamError::Connect(e.to_string()))?,
+            None => Bytes::new(),
+        };
+        Ok(RecordedRequest {
+            method: request.method().clone(),
+            url: request.url().clone(),
+            headers: request.headers().clone(),
+            body,
+        })
+    }
+}
+
+impl UpstreamClient for Arc<MockUpstream> {
+    fn send(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'static, Result<reqwest::Response, UpstreamError>> {
+        let upstream = self.clone();
+        Box::pin(async move {
+            let timeout = request.timeout().copied();
+            let recorded = upstream.record(request).await?;
+            upstream.requests.lock().unwrap().push(recorded.clone());
+            if let Some(timeout) = timeout.filter(|timeout| *timeout < upstream.delay) {
+                tokio::time::sleep(timeout).await;
+                return Err(UpstreamError::Timeout);
+            }
+            tokio::time::sleep(upstream.delay).await;
+            (upstream.handler)(&recorded).map(reqwest::Response::from)
+        })
+    }
+}
+
+pub fn json_reply(status: StatusCode, body: &Value) -> Reply {
+    Ok(axum::http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(reqwest::Body::from(body.to_string()))
+        .unwrap())
+}
+
+/// A 200 whose body arrives as `chunks`, one read at a time, with no
+/// content type of its own.
+pub fn streamed_reply(chunks: &[&'static str]) -> Reply {
+    let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+        .iter()
+        .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+        .collect();
+    Ok(axum::http::Response::builder()
+        .status(StatusCode::OK)
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .unwrap())
+}
+
+/// `reply` with a change token worth `amount` from `wallet` attached, as an
+/// upstream that was overpaid sends it.
+pub fn with_change(reply: Reply, wallet: &MockWallet, amount: i64) -> Reply {
+    let mut reply = reply?;
+    let token = HeaderValue::from_str(&wallet.change_token(amount)).unwrap();
+    reply.headers_mut().insert("x-change-sats", token);
+    Ok(reply)
+}
+
+/// A wallet that lives in memory. Tokens it mints are `cashuAmock<n>`, and
+/// it takes back any token it minted or handed out with [`Self::change_token`].
+/// It pays any invoice that carries an amount, with the preimage `mockpreimage`.
+pub struct MockWallet {
+    base_url: String,
+    balance: AtomicI64,
+    minted: AtomicUsize,
+    busy_sends: AtomicU32,
+    tokens: Mutex<HashMap<String, i64>>,
+    sent: Mutex<Vec<i64>>,
+    received: Mutex<Vec<String>>,
+}
+
+impl MockWallet {
+    pub fn new(balance: i64) -> Arc<Self> {
+        Self::at("http://mock-wallet", balance)
+    }
+
+    /// A wallet identified by `base_url`, as a tenant's wallet is.
+    pub fn at(base_url: &str, balance: i64) -> Arc<Self> {
+        Arc::new(Self {
+            base_url: base_url.to_string(),
+            balance: AtomicI64::new(balance),
+            minted: AtomicUsize::new(0),
+            busy_sends: AtomicU32::new(0),
+            tokens: Mutex::new(HashMap::new()),
+            sent: Mutex::new(Vec::new()),
+            received: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// A token worth `amount` the wallet will accept, as an upstream returns change.
+    pub fn change_token(&self, amount: i64) -> String {
+        let token = format!("cashuAchange{}", self.minted.fetch_add(1, Ordering::SeqCst));
+        self.tokens.lock().unwrap().insert(token.clone(), amount);
+        token
+    }
+
+    /// Redeems `token` without crediting this wallet, as an upstream keeping the payment does.
+    pub fn spend(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+
+    /// Makes the next `count` sends fail with a 503, as while the wallet swaps proofs.
+    pub fn fail_next_sends(&self, count: u32) {
+        self.busy_sends.store(count, Ordering::SeqCst);
+    }
+
+    pub fn current_balance(&self) -> i64 {
+        self.balance.load(Ordering::SeqCst)
+    }
+
+    /// Amounts of the tokens minted so far, in order.
+    pub fn sent(&self) -> Vec<i64> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Tokens received so far, change and reclaimed payments alike.
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+impl PaymentWallet for MockWallet {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn balance(&self) -> BoxFuture<'_, anyhow::Result<BalanceResponse>> {
+        Box::pin(async move {
+            Ok(BalanceResponse {
+                balance: self.current_balance(),
+                keysets: None,
+                mints: None,
+            })
+        })
+    }
+
+    fn send<'a>(
+        &'a self,
+        amount: i64,
+        _mint: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<SendResponse>> {
+        Box::pin(async move {
+            let busy = self
+                .busy_sends
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if busy.is_ok() {
+                return Err(WalletHttpError {
+                    status: 503,
+                    retry_after: None,
+                    detail: "wallet busy".to_string(),
+                }
+                .into());
+            }
+            if self.current_balance() < amount {
+                return Err(WalletHttpError {
+                    status: 400,
+                    retry_after: None,
+                    detail: "balance too low".to_string(),
+                }
+                .into());
+            }
+            let balance = self.balance.fetch_sub(amount, Ordering::SeqCst) - amount;
+            let token = format!("cashuAmock{}", self.minted.fetch_add(1, Ordering::SeqCst));
+            self.tokens.lock().unwrap().insert(token.clone(), amount);
+            self.sent.lock().unwrap().push(amount);
+            Ok(SendResponse {
+                balance,
+                token,
+                npub: None,
+            })
+        })
+    }
+
+    fn receive<'a>(&'a self, token: &'a str) -> BoxFuture<'a, anyhow::Result<ReceiveResponse>> {
+        Box::pin(async move {
+            let Some(amount) = self.tokens.lock().unwrap().remove(token) else {
+                return Err(WalletHttpError {
+                    status: 400,
+                    retry_after: None,
+                    detail: "token already spent".to_string(),
+                }
+                .into());
+            };
+            self.received.lock().unwrap().push(token.to_string());
+            let initial_balance = self.balance.fetch_add(amount, Ordering::SeqCst);
+            Ok(ReceiveResponse {
+                initial_balance,
+                balance: initial_balance + amount,
+            })
+        })
+    }
+
+    fn pay_invoice<'a>(
+        &'a self,
+        bolt11: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<PaymentResponse>> {
+        Box::pin(async move {
+            let amount = l402::bolt11_amount_sats(bolt11).filter(|amount| {
+                self.balance
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| {
+                        (balance >= *amount).then(|| balance - amount)
+                    })
+                    .is_ok()
+            });
+            Ok(match amount {
+                Some(_) => PaymentResponse {
+                    result: PaymentResult::Success,
+                    checking_id: None,
+                    fee: None,
+                    preimage: Some("mockpreimage".to_string()),
+                    error_message: None,
+                },
+                None => PaymentResponse {
+                    result: PaymentResult::Failed,
+                    checking_id: None,
+                    fee: None,
+                    preimage: None,
+                    error_message: Some(
+                        "invoice has no amount or the balance is too low".to_string(),
+                    ),
+                },
+            })
+        })
+    }
+}
+
+/// Server configs held in memory instead of the `server_config` table.
+pub struct FixedServerConfigs(pub Vec<ServerConfigRecord>);
+
+impl ServerConfigSource for FixedServerConfigs {
+    fn default_config(&self) -> BoxFuture<'_, Result<Option<ServerConfigRecord>, sqlx::Error>> {
+        let config = self.0.iter().find(|config| config.name.is_none()).cloned();
+        Box::pin(async move { Ok(config) })
+    }
+
+    fn config_by_name<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<ServerConfigRecord>, sqlx::Error>> {
+        let config = self
+            .0
+            .iter()
+            .find(|config| config.name.as_deref() == Some(name))
+            .cloned();
+        Box::pin(async move { Ok(config) })
+    }
+}
+
+pub const UPSTREAM_URL: &str = "http://upstream.test";
+
+pub fn server_config(endpoint: &str, fallback_endpoints: &[&str]) -> ServerConfigRecord {
+    ServerConfigRecord {
+        id: "test".to_string(),
+        name: None,
+        endpoint: endpoint.to_string(),
+        api_key: "upstream-key".to_string(),
+        fallback_endpoints: fallback_endpoints.iter().map(|e| e.to_string()).collect(),
+        payment_header: "x-payment-sats".to_string(),
+        change_header: "x-change-sats".to_string(),
+        mint_url: None,
+        created_at: chrono::Utc::now(),
+        updated_at: None,
+    }
+}
+
+/// A pool that never connects: queries fail fast, so ledger writes and
+/// dead-letter inserts are logged and dropped.
+pub fn unreachable_pool() -> sqlx::PgPool {
+    sqlx::postgres::PgPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(50))
+        .connect_lazy("postgres://gateway@127.0.0.1:1/gateway")
+        .unwrap()
+}
+
+/// A gateway forwarding to `upstream` at [`UPSTREAM_URL`] and paying from
+/// `wallet`, with the limits of `configuration/base.yaml` and no retries.
+/// Tests set the fields they care about with struct update syntax.
+pub fn app_state(upstream: Arc<MockUpstream>, wallet: Arc<MockWallet>) -> AppState {
+    let db = unreachable_pool();
+    let admin_wallet = CashuWalletClient::new("http://127.0.0.1:1");
+    let tenant_wallet = wallet.clone();
+    let upstream: Arc<dyn UpstreamClient> = Arc::new(upstream);
+    AppState {
+        db: db.clone(),
+        server_configs: Arc::new(FixedServerConfigs(vec![server_config(UPSTREAM_URL, &[])])),
+        users: RwLock::new(HashMap::new()),
+        organizations: RwLock::new(HashMap::new()),
+        api_keys: RwLock::new(HashMap::new()),
+        models: RwLock::new(HashMap::new()),
+        providers: RwLock::new(HashMap::new()),
+        credits: RwLock::new(HashMap::new()),
+        wallets: Arc::new(WalletRegistry::with_wallets(wallet, move |_| {
+            tenant_wallet.clone()
+        })),
+        consolidator: Arc::new(Consolidator::new(
+            admin_wallet.clone(),
+            db.clone(),
+            u64::MAX,
+        )),
+        wallet: admin_wallet,
+        http_client: reqwest::Client::new(),
+        upstream: upstream.clone(),
+        streaming_upstream: upstream,
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+        max_batch_requests: 20,
+        low_balance_alert: None,
+        auto_topup: None,
+        track_stream_usage: true,
+        stream_channel_buffer: 16,
+        stream_request_body_bytes: None,
+        max_request_body_bytes: 1024 * 1024,
+        max_file_upload_bytes: 1024 * 1024,
+        model_policy: ModelPolicies::new(ModelPolicy::default()),
+        runtime: LiveSettings::new(runtime_settings()),
+        image_inliner: None,
+        readiness_check_upstream: false,
+        circuit_breakers: CircuitBreakers::new(5, Duration::from_secs(30)),
+        upstream_retries: 0,
+        upstream_retry_backoff: Duration::ZERO,
+        max_retry_after: Duration::ZERO,
+        wallet_retry: WalletRetry::default(),
+        l402_enabled: false,
+        gateway_auth_enabled: false,
+        admin_key_hash: None,
+        rate_limiter: RateLimiter::new(0, Duration::from_secs(60)),
+        forwarded_headers: HeaderAllowlist::new(&[], &[]),
+        shadow: None,
+        mints: Vec::new(),
+        active_streams: ActiveStreams::default(),
+        stream_cancels: StreamCancels::default(),
+        bulkhead: None,
+        credit_mode: CreditMode::default(),
+        models_cache: ResponseCache::new(Duration::from_secs(60)),
+        embeddings_cache: None,
+        models_in_flight: SingleFlight::new(),
+    }
+}
+
+/// Flat pricing at the gateway's defaults, caps well above the prices tests
+/// pay, and timeouts no test waits out.
+pub fn runtime_settings() -> RuntimeSettings {
+    RuntimeSettings {
+        pricing: PricingStrategyKind::FlatRate.build(unreachable_pool(), 0),
+        pricing_strategy: PricingStrategyKind::FlatRate,
+        token_estimate_sats_per_1k_tokens: 0,
+        image_pricing: ImagePricing::default(),
+        moderation_payment_sats: 1,
+        rerank_payment_sats: 5,
+        rerank_payment_sats_per_document: 1,
+        max_retry_payment_sats: 100,
+        max_sats_per_request: 1000,
+        max_choices_per_request: 4,
+        daily_budget_sats: None,
+        request_timeout: Duration::from_secs(5),
+        streaming_timeout: Duration::from_secs(5),
+        max_request_timeout: Duration::from_secs(10),
+        stream_idle_timeout: Duration::from_secs(5),
+    }
+}
+
+pub fn post_json(uri: &str, body: &Value) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub async fn body_bytes(response: Response) -> Bytes {
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+}
+
+pub async fn body_json(response: Response) -> Value {
+    serde_json::from_slice(&body_bytes(response).await).expect("response body is JSON")
+}
+
+pub fn header_str<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
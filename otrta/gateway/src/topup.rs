@@ -0,0 +1,238 @@
+use crate::{
+    db::{
+        Pool,
+        wallet_topups::{self, WalletTopupRecord},
+    },
+    models::TopupStatus,
+    wallets::PaymentWallet,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use wallet::api::{CashuWalletApi, CashuWalletClient, PaymentResult};
+
+/// How often and how long an automatic refill polls for its invoice to be paid.
+const CLAIM_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const CLAIM_POLL_ATTEMPTS: u32 = 30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TopupError {
+    #[error("{0}")]
+    QuoteFailed(String),
+    #[error(transparent)]
+    Wallet(#[from] anyhow::Error),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Requests a mint quote for `amount` sats and records the invoice to pay.
+/// The top-up refills the tenant wallet at `wallet_url`, or the shared
+/// `wallet` when there is none.
+pub async fn request_topup(
+    wallet: &CashuWalletClient,
+    db: &Pool,
+    amount: i64,
+    mint: Option<&str>,
+    wallet_url: Option<&str>,
+) -> Result<WalletTopupRecord, TopupError> {
+    let wallet = refilled_wallet(wallet, wallet_url);
+    let invoice = wallet.create_invoice(amount, mint).await?;
+    let Some(payment_request) = invoice.payment_request.filter(|_| invoice.ok) else {
+        return Err(TopupError::QuoteFailed(
+            invoice
+                .error_message
+                .unwrap_or_else(|| "Mint did not return an invoice".to_string()),
+        ));
+    };
+
+    Ok(wallet_topups::create_topup(db, amount, mint, wallet_url, &payment_request).await?)
+}
+
+/// Checks whether a top-up invoice was paid; the wallet it refills mints the
+/// tokens as part of that check.
+pub async fn claim_topup(
+    wallet: &CashuWalletClient,
+    db: &Pool,
+    topup: &WalletTopupRecord,
+) -> Result<TopupStatus, TopupError> {
+    if topup.claimed_at.is_some() {
+        return Ok(TopupStatus::Claimed);
+    }
+
+    let state = refilled_wallet(wallet, topup.wallet_url.as_deref())
+        .invoice_state(Some(&topup.payment_request), topup.mint_url.as_deref())
+        .await?;
+    Ok(match state.result {
+        PaymentResult::Success => {
+            if wallet_topups::mark_claimed(db, &topup.id).await? {
+                info!(topup = %topup.id, amount = topup.amount, "lightning top-up minted");
+            }
+            TopupStatus::Claimed
+        }
+        PaymentResult::Failed => TopupStatus::Failed,
+        PaymentResult::Pending | PaymentResult::Unknown => TopupStatus::Pending,
+    })
+}
+
+fn refilled_wallet(shared: &CashuWalletClient, wallet_url: Option<&str>) -> CashuWalletClient {
+    match wallet_url {
+        Some(wallet_url) => shared.for_url(wallet_url),
+        None => shared.clone(),
+    }
+}
+
+/// Refills a wallet over lightning when a forward leaves its balance below
+/// `reserve`. The invoice is posted to `payer_url` when one is configured;
+/// otherwise it waits for the operator to pay it. Tenant wallets are refilled
+/// from their default mint, and each wallet is throttled on its own.
+pub struct AutoTopup {
+    reserve: i64,
+    amount: i64,
+    mint: Option<String>,
+    payer_url: Option<String>,
+    interval: Duration,
+    wallet: CashuWalletClient,
+    db: Pool,
+    http_client: reqwest::Client,
+    refilling: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    last_attempt: Mutex<HashMap<String, Instant>>,
+}
+
+impl AutoTopup {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        reserve: i64,
+        amount: i64,
+        mint: Option<String>,
+        payer_url: Option<String>,
+        interval: Duration,
+        wallet: CashuWalletClient,
+        db: Pool,
+        http_client: reqwest::Client,
+    ) -> Self {
+        Self {
+            reserve,
+            amount,
+            mint,
+            payer_url,
+            interval,
+            wallet,
+            db,
+            http_client,
+            refilling: Mutex::new(HashMap::new()),
+            last_attempt: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the balance of `wallet` in the background after a forward has
+    /// spent sats from it.
+    pub fn check(self: &Arc<Self>, wallet: Arc<dyn PaymentWallet>) {
+        let topup = Arc::clone(self);
+        tokio::spawn(async move { topup.check_now(wallet.as_ref()).await });
+    }
+
+    async fn check_now(&self, wallet: &dyn PaymentWallet) {
+        let wallet_url = wallet.base_url();
+        // Only one refill per wallet at a time; others skip rather than queue up.
+        let refilling = self
+            .refilling
+            .lock()
+            .unwrap()
+            .entry(wallet_url.to_string())
+            .or_default()
+            .clone();
+        let Ok(_refilling) = refilling.try_lock() else {
+            return;
+        };
+        let balance = match wallet.balance().await {
+            Ok(balance) => balance.balance,
+            Err(e) => {
+                warn!(error = %e, "failed to query wallet balance for auto top-up");
+                return;
+            }
+        };
+        if balance >= self.reserve || !self.claim_slot(wallet_url) {
+            return;
+        }
+
+        let (mint, tenant) = if wallet_url == self.wallet.base_url() {
+            (self.mint.as_deref(), None)
+        } else {
+            (None, Some(wallet_url))
+        };
+        let topup = match request_topup(&self.wallet, &self.db, self.amount, mint, tenant).await {
+            Ok(topup) => topup,
+            Err(e) => {
+                warn!(wallet = wallet_url, error = %e, "failed to request auto top-up invoice");
+                return;
+            }
+        };
+        info!(
+            topup = %topup.id,
+            wallet = wallet_url,
+            balance,
+            reserve = self.reserve,
+            amount = self.amount,
+            "balance below reserve, requested lightning top-up"
+        );
+
+        let Some(payer_url) = &self.payer_url else {
+            return;
+        };
+        if !self.pay(payer_url, &topup).await {
+            return;
+        }
+
+        for _ in 0..CLAIM_POLL_ATTEMPTS {
+            tokio::time::sleep(CLAIM_POLL_INTERVAL).await;
+            match claim_topup(&self.wallet, &self.db, &topup).await {
+                Ok(TopupStatus::Pending) => continue,
+                Ok(TopupStatus::Claimed) => return,
+                Ok(TopupStatus::Failed) => {
+                    warn!(topup = %topup.id, "auto top-up invoice payment failed");
+                    return;
+                }
+                Err(e) => warn!(topup = %topup.id, error = %e, "failed to check auto top-up"),
+            }
+        }
+        warn!(topup = %topup.id, "auto top-up invoice still unpaid, leaving it to be claimed later");
+    }
+
+    async fn pay(&self, payer_url: &str, topup: &WalletTopupRecord) -> bool {
+        let payload = json!({
+            "event": "topup_invoice",
+            "id": topup.id,
+            "wallet": topup.wallet_url.as_deref().unwrap_or(self.wallet.base_url()),
+            "invoice": topup.payment_request,
+            "amount": topup.amount,
+            "unit": "sat",
+        });
+        match self.http_client.post(payer_url).json(&payload).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                warn!(status = %response.status(), "lightning payer rejected the top-up invoice");
+                false
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to send top-up invoice to the lightning payer");
+                false
+            }
+        }
+    }
+
+    /// Records a refill attempt for the wallet at `wallet_url` unless one was
+    /// already made for it within the interval.
+    fn claim_slot(&self, wallet_url: &str) -> bool {
+        let mut last_attempt = self.last_attempt.lock().unwrap();
+        if last_attempt
+            .get(wallet_url)
+            .is_some_and(|attempt| attempt.elapsed() < self.interval)
+        {
+            return false;
+        }
+        last_attempt.insert(wallet_url.to_string(), Instant::now());
+        true
+    }
+}
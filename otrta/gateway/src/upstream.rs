@@ -0,0 +1,107 @@
+use crate::telemetry;
+use axum::http::StatusCode;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::{Instrument, info_span};
+
+/// Why no response came back from the upstream. Kept apart from reqwest's
+/// error so a client other than reqwest can report the same failures.
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    /// The upstream could not be reached, e.g. it refused the connection.
+    #[error("could not connect to the upstream: {0}")]
+    Connect(String),
+    /// The upstream did not answer within the request timeout.
+    #[error("timed out waiting for the upstream")]
+    Timeout,
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+impl UpstreamError {
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            UpstreamError::Timeout => true,
+            UpstreamError::Http(e) => e.is_timeout(),
+            UpstreamError::Connect(_) => false,
+        }
+    }
+
+    /// Whether the request never reached the upstream, so another endpoint
+    /// or another attempt can be tried without paying twice.
+    pub fn is_connect(&self) -> bool {
+        match self {
+            UpstreamError::Connect(_) => true,
+            UpstreamError::Http(e) => e.is_connect(),
+            UpstreamError::Timeout => false,
+        }
+    }
+
+    /// The status the client should see for this failure.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::Connect(_) => StatusCode::BAD_GATEWAY,
+            UpstreamError::Http(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::Http(e)
+                if e.is_connect() || e.is_request() || e.is_body() || e.is_decode() =>
+            {
+                StatusCode::BAD_GATEWAY
+            }
+            UpstreamError::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Sends a prepared request to the upstream. Production uses reqwest; tests
+/// can supply canned responses without network access.
+pub trait UpstreamClient: Send + Sync {
+    fn send(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'static, Result<reqwest::Response, UpstreamError>>;
+}
+
+impl UpstreamClient for reqwest::Client {
+    fn send(
+        &self,
+        request: reqwest::Request,
+    ) -> BoxFuture<'static, Result<reqwest::Response, UpstreamError>> {
+        let response = self.execute(request);
+        Box::pin(async move { Ok(response.await?) })
+    }
+}
+
+/// Adapts an [`UpstreamClient`] into the tower service the payment layer wraps.
+#[derive(Clone)]
+pub struct UpstreamService(Arc<dyn UpstreamClient>);
+
+impl UpstreamService {
+    pub fn new(client: Arc<dyn UpstreamClient>) -> Self {
+        Self(client)
+    }
+}
+
+impl Service<reqwest::Request> for UpstreamService {
+    type Response = reqwest::Response;
+    type Error = UpstreamError;
+    type Future = BoxFuture<'static, Result<reqwest::Response, UpstreamError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Each attempt gets its own span, whose context travels to the upstream
+    /// as a W3C `traceparent` header.
+    fn call(&mut self, mut request: reqwest::Request) -> Self::Future {
+        let span = info_span!(
+            "upstream_request",
+            method = %request.method(),
+            url = %request.url(),
+        );
+        span.in_scope(|| telemetry::inject_trace_context(request.headers_mut()));
+        Box::pin(self.0.send(request).instrument(span))
+    }
+}
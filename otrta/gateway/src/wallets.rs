@@ -0,0 +1,120 @@
+use crate::auth;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use wallet::api::{
+    BalanceResponse, CashuWalletApi, CashuWalletClient, PaymentResponse, ReceiveResponse,
+    SendResponse,
+};
+
+/// The wallet calls a paid request makes: checking the balance, minting a
+/// payment, taking back change and paying an L402 invoice. Production uses
+/// [`CashuWalletClient`]; tests can supply a wallet that never leaves memory.
+pub trait PaymentWallet: Send + Sync {
+    /// Identifies the wallet backend, e.g. in the dead-letter queue.
+    fn base_url(&self) -> &str;
+    fn balance(&self) -> BoxFuture<'_, anyhow::Result<BalanceResponse>>;
+    fn send<'a>(
+        &'a self,
+        amount: i64,
+        mint: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<SendResponse>>;
+    fn receive<'a>(&'a self, token: &'a str) -> BoxFuture<'a, anyhow::Result<ReceiveResponse>>;
+    fn pay_invoice<'a>(&'a self, bolt11: &'a str)
+    -> BoxFuture<'a, anyhow::Result<PaymentResponse>>;
+}
+
+impl PaymentWallet for CashuWalletClient {
+    fn base_url(&self) -> &str {
+        CashuWalletClient::base_url(self)
+    }
+
+    fn balance(&self) -> BoxFuture<'_, anyhow::Result<BalanceResponse>> {
+        Box::pin(CashuWalletApi::balance(self))
+    }
+
+    fn send<'a>(
+        &'a self,
+        amount: i64,
+        mint: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<SendResponse>> {
+        Box::pin(CashuWalletApi::send(self, amount, None, None, mint, None))
+    }
+
+    fn receive<'a>(&'a self, token: &'a str) -> BoxFuture<'a, anyhow::Result<ReceiveResponse>> {
+        Box::pin(CashuWalletApi::receive(self, Some(token), None, None))
+    }
+
+    fn pay_invoice<'a>(
+        &'a self,
+        bolt11: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<PaymentResponse>> {
+        Box::pin(CashuWalletApi::pay_invoice(self, bolt11, None))
+    }
+}
+
+type TenantWallet = Box<dyn Fn(&str) -> Arc<dyn PaymentWallet> + Send + Sync>;
+
+/// The wallets requests pay from. Client keys with a wallet of their own pay
+/// from it and see only its balance; every other request uses the shared one.
+pub struct WalletRegistry {
+    shared: Arc<dyn PaymentWallet>,
+    tenants: RwLock<HashMap<String, Arc<dyn PaymentWallet>>>,
+    connect: TenantWallet,
+}
+
+impl WalletRegistry {
+    pub fn new(shared: CashuWalletClient) -> Self {
+        let template = shared.clone();
+        Self::with_wallets(Arc::new(shared), move |wallet_url| {
+            Arc::new(template.for_url(wallet_url))
+        })
+    }
+
+    /// A registry over any wallet, connecting to a tenant's wallet with
+    /// `connect` the first time one of its keys makes a request.
+    pub fn with_wallets(
+        shared: Arc<dyn PaymentWallet>,
+        connect: impl Fn(&str) -> Arc<dyn PaymentWallet> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            shared,
+            tenants: RwLock::new(HashMap::new()),
+            connect: Box::new(connect),
+        }
+    }
+
+    /// The wallet at `wallet_url`, or the shared wallet when there is none.
+    pub fn get(&self, wallet_url: Option<&str>) -> Arc<dyn PaymentWallet> {
+        let Some(wallet_url) = wallet_url.filter(|url| *url != self.shared.base_url()) else {
+            return self.shared.clone();
+        };
+        if let Some(wallet) = self.tenants.read().unwrap().get(wallet_url) {
+            return wallet.clone();
+        }
+        self.tenants
+            .write()
+            .unwrap()
+            .entry(wallet_url.to_string())
+            .or_insert_with(|| (self.connect)(wallet_url))
+            .clone()
+    }
+
+    /// The wallet of the client key the current request authenticated with.
+    pub fn for_caller(&self) -> Arc<dyn PaymentWallet> {
+        let wallet_url = auth::current_key().and_then(|key| key.wallet_url);
+        self.get(wallet_url.as_deref())
+    }
+
+    /// The shared wallet followed by every tenant wallet connected so far.
+    pub fn all(&self) -> Vec<Arc<dyn PaymentWallet>> {
+        let tenants = self.tenants.read().unwrap();
+        std::iter::once(self.shared.clone())
+            .chain(tenants.values().cloned())
+            .collect()
+    }
+
+    pub fn is_shared(&self, wallet: &dyn PaymentWallet) -> bool {
+        wallet.base_url() == self.shared.base_url()
+    }
+}
@@ -0,0 +1,47 @@
+//! Opens pooled connections to the upstream at startup, so the first real
+//! request does not pay for DNS, TCP and TLS setup.
+
+use crate::{handlers::get_server_config, models::AppState};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends an unpaid `HEAD /v1/models` to every configured endpoint through both
+/// the buffered and the streaming client. Failures are logged and ignored.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let Some(config) = get_server_config(&state.db).await else {
+            info!("no server config yet, skipping upstream warm-up");
+            return;
+        };
+
+        for endpoint in config.endpoints() {
+            for upstream in [&state.upstream, &state.streaming_upstream] {
+                let request = match state
+                    .http_client
+                    .head(format!("{}/v1/models", endpoint))
+                    .timeout(WARM_UP_TIMEOUT)
+                    .build()
+                {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!(endpoint = %endpoint, error = %e, "skipping warm-up of invalid endpoint");
+                        break;
+                    }
+                };
+                let started = Instant::now();
+                match upstream.send(request).await {
+                    Ok(response) => info!(
+                        endpoint = %endpoint,
+                        status = %response.status(),
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "warmed up upstream connection"
+                    ),
+                    Err(e) => warn!(endpoint = %endpoint, error = %e, "upstream warm-up failed"),
+                }
+            }
+        }
+    });
+}
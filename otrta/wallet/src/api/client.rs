@@ -1,21 +1,251 @@
 use super::base::CashuWalletApi;
 use super::models::*;
 use anyhow::Result;
-use reqwest::Client;
-
+use reqwest::{Client, Response, header};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+
+/// How long a single wallet call may take before it fails.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a call waits for another caller to release the proof lock.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clones share one lock, held across every call that selects or swaps
+/// proofs, so concurrent requests cannot spend the same proofs twice.
+/// Read-only calls such as `balance` do not take it, and neither do the
+/// lightning payments behind `pay_invoice` and `swap`, which can take minutes.
+/// Every call is bounded by the request timeout and every wait for the lock
+/// by the lock timeout, so one hung call cannot stall the others for long.
 #[derive(Clone)]
 pub struct CashuWalletClient {
     client: Client,
     base_url: String,
+    lock_timeout: Duration,
+    proofs: Arc<Mutex<()>>,
+    /// Held for writing by an [`ExclusiveWallet`], so a balance read waits
+    /// instead of reporting a wallet whose proofs are mid-swap as empty.
+    balance_gate: Arc<RwLock<()>>,
 }
 
 impl CashuWalletClient {
     pub fn new(base_url: &str) -> Self {
+        Self::with_timeouts(base_url, DEFAULT_REQUEST_TIMEOUT, DEFAULT_LOCK_TIMEOUT)
+    }
+
+    pub fn with_timeouts(
+        base_url: &str,
+        request_timeout: Duration,
+        lock_timeout: Duration,
+    ) -> Self {
+        let client = Client::builder()
+            .timeout(request_timeout)
+            .build()
+            .unwrap_or_else(|_| Client::new());
+        Self {
+            client,
+            base_url: base_url.to_string(),
+            lock_timeout,
+            proofs: Arc::new(Mutex::new(())),
+            balance_gate: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// A client for another wallet backend with the same timeouts. It has
+    /// locks of its own, since it holds different proofs.
+    pub fn for_url(&self, base_url: &str) -> Self {
         Self {
-            client: Client::new(),
+            client: self.client.clone(),
             base_url: base_url.to_string(),
+            lock_timeout: self.lock_timeout,
+            proofs: Arc::new(Mutex::new(())),
+            balance_gate: Arc::new(RwLock::new(())),
         }
     }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Holds the proof lock for a sequence of calls no other caller may
+    /// interleave with, such as gathering a balance into one token and
+    /// receiving it straight back. Other calls wait until it is dropped.
+    pub async fn exclusive(&self) -> Result<ExclusiveWallet<'_>> {
+        let balance = self.wait_for(self.balance_gate.write()).await?;
+        let proofs = self.wait_for(self.proofs.lock()).await?;
+        Ok(ExclusiveWallet {
+            wallet: self,
+            _balance: balance,
+            _proofs: proofs,
+        })
+    }
+
+    async fn lock_proofs(&self) -> Result<MutexGuard<'_, ()>> {
+        self.wait_for(self.proofs.lock()).await
+    }
+
+    async fn wait_for<G>(&self, lock: impl Future<Output = G>) -> Result<G> {
+        tokio::time::timeout(self.lock_timeout, lock)
+            .await
+            .map_err(|_| {
+                WalletLockTimeout {
+                    waited: self.lock_timeout,
+                }
+                .into()
+            })
+    }
+
+    async fn fetch_balance(&self) -> Result<BalanceResponse> {
+        let url = format!("{}/balance", self.base_url);
+        let response = self.client.get(&url).send().await?;
+        Ok(response.json().await?)
+    }
+
+    async fn send_unlocked(
+        &self,
+        amount: i64,
+        nostr: Option<&str>,
+        lock: Option<&str>,
+        mint: Option<&str>,
+        offline: Option<bool>,
+    ) -> Result<SendResponse> {
+        let mut url = format!("{}/send?amount={}", self.base_url, amount);
+
+        if let Some(nostr_key) = nostr {
+            url = format!("{}&nostr={}", url, nostr_key);
+        }
+
+        if let Some(lock_key) = lock {
+            url = format!("{}&lock={}", url, lock_key);
+        }
+
+        if let Some(mint_url) = mint {
+            url = format!("{}&mint={}", url, mint_url);
+        }
+
+        if let Some(true) = offline {
+            url = format!("{}&offline=true", url);
+        }
+
+        let response = check_status(self.client.post(&url).send().await?).await?;
+        Ok(response.json().await?)
+    }
+
+    async fn receive_unlocked(
+        &self,
+        token: Option<&str>,
+        nostr: Option<bool>,
+        all: Option<bool>,
+    ) -> Result<ReceiveResponse> {
+        let mut url = format!("{}/receive", self.base_url);
+        let mut has_param = true;
+
+        if let Some(token_str) = token {
+            url = format!("{}?token={}", url, token_str);
+            has_param = true;
+        }
+
+        if let Some(true) = nostr {
+            if has_param {
+                url = format!("{}&nostr=true", url);
+            } else {
+                url = format!("{}?nostr=false", url);
+                has_param = true;
+            }
+        }
+
+        if let Some(true) = all {
+            if has_param {
+                url = format!("{}&all=true", url);
+            } else {
+                url = format!("{}?all=false", url);
+            }
+        }
+
+        let response = check_status(self.client.post(&url).send().await?).await?;
+        Ok(response.json().await?)
+    }
+}
+
+/// The wallet with its proof lock held; see [`CashuWalletClient::exclusive`].
+pub struct ExclusiveWallet<'a> {
+    wallet: &'a CashuWalletClient,
+    _balance: RwLockWriteGuard<'a, ()>,
+    _proofs: MutexGuard<'a, ()>,
+}
+
+impl ExclusiveWallet<'_> {
+    pub async fn balance(&self) -> Result<BalanceResponse> {
+        self.wallet.fetch_balance().await
+    }
+
+    pub async fn send(&self, amount: i64, mint: Option<&str>) -> Result<SendResponse> {
+        self.wallet
+            .send_unlocked(amount, None, None, mint, None)
+            .await
+    }
+
+    pub async fn receive(&self, token: &str) -> Result<ReceiveResponse> {
+        self.wallet.receive_unlocked(Some(token), None, None).await
+    }
+}
+
+/// A non-success response from the wallet backend, kept as a typed error so
+/// callers can tell a busy wallet from one that is out of funds.
+#[derive(Debug)]
+pub struct WalletHttpError {
+    pub status: u16,
+    /// Seconds the backend asked the caller to wait, from `Retry-After`.
+    pub retry_after: Option<u64>,
+    pub detail: String,
+}
+
+impl fmt::Display for WalletHttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wallet returned {}: {}", self.status, self.detail)
+    }
+}
+
+impl std::error::Error for WalletHttpError {}
+
+/// Another call held the proof lock for longer than the lock timeout. The
+/// wallet is busy rather than broken, so the call can be tried again.
+#[derive(Debug)]
+pub struct WalletLockTimeout {
+    pub waited: Duration,
+}
+
+impl fmt::Display for WalletLockTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wallet busy: waited {} ms for another call to finish",
+            self.waited.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for WalletLockTimeout {}
+
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let detail = response.text().await.unwrap_or_default();
+    Err(WalletHttpError {
+        status: status.as_u16(),
+        retry_after,
+        detail,
+    }
+    .into())
 }
 
 impl CashuWalletApi for CashuWalletClient {
@@ -104,9 +334,8 @@ impl CashuWalletApi for CashuWalletClient {
     }
 
     async fn balance(&self) -> Result<BalanceResponse> {
-        let url = format!("{}/balance", self.base_url);
-        let response = self.client.get(&url).send().await?;
-        Ok(response.json().await?)
+        let _gate = self.wait_for(self.balance_gate.read()).await?;
+        self.fetch_balance().await
     }
 
     async fn send(
@@ -117,26 +346,8 @@ impl CashuWalletApi for CashuWalletClient {
         mint: Option<&str>,
         offline: Option<bool>,
     ) -> Result<SendResponse> {
-        let mut url = format!("{}/send?amount={}", self.base_url, amount);
-
-        if let Some(nostr_key) = nostr {
-            url = format!("{}&nostr={}", url, nostr_key);
-        }
-
-        if let Some(lock_key) = lock {
-            url = format!("{}&lock={}", url, lock_key);
-        }
-
-        if let Some(mint_url) = mint {
-            url = format!("{}&mint={}", url, mint_url);
-        }
-
-        if let Some(true) = offline {
-            url = format!("{}&offline=true", url);
-        }
-
-        let response = self.client.post(&url).send().await?;
-        Ok(response.json().await?)
+        let _proofs = self.lock_proofs().await?;
+        self.send_unlocked(amount, nostr, lock, mint, offline).await
     }
 
     async fn receive(
@@ -145,33 +356,8 @@ impl CashuWalletApi for CashuWalletClient {
         nostr: Option<bool>,
         all: Option<bool>,
     ) -> Result<ReceiveResponse> {
-        let mut url = format!("{}/receive", self.base_url);
-        let mut has_param = true;
-
-        if let Some(token_str) = token {
-            url = format!("{}?token={}", url, token_str);
-            has_param = true;
-        }
-
-        if let Some(true) = nostr {
-            if has_param {
-                url = format!("{}&nostr=true", url);
-            } else {
-                url = format!("{}?nostr=false", url);
-                has_param = true;
-            }
-        }
-
-        if let Some(true) = all {
-            if has_param {
-                url = format!("{}&all=true", url);
-            } else {
-                url = format!("{}?all=false", url);
-            }
-        }
-
-        let response = self.client.post(&url).send().await?;
-        Ok(response.json().await?)
+        let _proofs = self.lock_proofs().await?;
+        self.receive_unlocked(token, nostr, all).await
     }
 
     async fn burn(
@@ -182,6 +368,7 @@ impl CashuWalletApi for CashuWalletClient {
         delete: Option<&str>,
         mint: Option<&str>,
     ) -> Result<BurnResponse> {
+        let _proofs = self.lock_proofs().await?;
         let mut url = format!("{}/burn", self.base_url);
         let mut has_param = false;
 
@@ -275,6 +462,7 @@ impl CashuWalletApi for CashuWalletClient {
     }
 
     async fn restore(&self, to: i64) -> Result<RestoreResponse> {
+        let _proofs = self.lock_proofs().await?;
         let url = format!("{}/v1/restore?to={}", self.base_url, to);
         let response = self.client.post(&url).send().await?;
         Ok(response.json().await?)
@@ -286,3 +474,108 @@ impl CashuWalletApi for CashuWalletClient {
         Ok(response.json().await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Json, Router,
+        extract::{Query, State},
+        routing::{get, post},
+    };
+    use std::collections::HashMap;
+    use std::sync::Mutex as StdMutex;
+
+    /// A wallet backend that picks proofs for a send without any locking of
+    /// its own, like the real one: it reads the unspent proofs, pauses, then
+    /// marks them spent. Two sends it serves at once pick the same proofs.
+    #[derive(Clone, Default)]
+    struct FakeBackend {
+        unspent: Arc<StdMutex<Vec<u32>>>,
+        handed_out: Arc<StdMutex<Vec<u32>>>,
+    }
+
+    async fn fake_send(
+        State(backend): State<FakeBackend>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Json<SendResponse> {
+        let amount: usize = params["amount"].parse().unwrap();
+        let picked: Vec<u32> = backend.unspent.lock().unwrap()[..amount].to_vec();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let mut unspent = backend.unspent.lock().unwrap();
+        unspent.retain(|proof| !picked.contains(proof));
+        backend.handed_out.lock().unwrap().extend(&picked);
+        Json(SendResponse {
+            balance: unspent.len() as i64,
+            token: format!("{:?}", picked),
+            npub: None,
+        })
+    }
+
+    async fn slow_balance() -> Json<BalanceResponse> {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        unreachable!("the client gives up first")
+    }
+
+    async fn serve(backend: FakeBackend) -> String {
+        let app = Router::new()
+            .route("/send", post(fake_send))
+            .route("/balance", get(slow_balance))
+            .with_state(backend);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn concurrent_sends_never_share_a_proof() {
+        let backend = FakeBackend::default();
+        *backend.unspent.lock().unwrap() = (0..200).collect();
+        let wallet = CashuWalletClient::new(&serve(backend.clone()).await);
+
+        let sends = (0..50).map(|_| {
+            let wallet = wallet.clone();
+            tokio::spawn(async move { wallet.send(2, None, None, None, None).await })
+        });
+        for send in futures_util::future::join_all(sends).await {
+            send.unwrap().unwrap();
+        }
+
+        let mut handed_out = backend.handed_out.lock().unwrap().clone();
+        assert_eq!(handed_out.len(), 100);
+        handed_out.sort();
+        handed_out.dedup();
+        assert_eq!(handed_out.len(), 100, "a proof was handed out twice");
+    }
+
+    #[tokio::test]
+    async fn send_gives_up_waiting_for_a_held_lock() {
+        let backend = FakeBackend::default();
+        *backend.unspent.lock().unwrap() = (0..10).collect();
+        let url = serve(backend).await;
+        let wallet = CashuWalletClient::with_timeouts(
+            &url,
+            DEFAULT_REQUEST_TIMEOUT,
+            Duration::from_millis(50),
+        );
+
+        let _held = wallet.exclusive().await.unwrap();
+        let error = wallet.send(1, None, None, None, None).await.unwrap_err();
+        assert!(error.is::<WalletLockTimeout>(), "{error}");
+    }
+
+    #[tokio::test]
+    async fn a_hung_backend_call_times_out() {
+        let url = serve(FakeBackend::default()).await;
+        let wallet = CashuWalletClient::with_timeouts(
+            &url,
+            Duration::from_millis(100),
+            DEFAULT_LOCK_TIMEOUT,
+        );
+
+        let error = wallet.balance().await.unwrap_err();
+        let error = error.downcast_ref::<reqwest::Error>().unwrap();
+        assert!(error.is_timeout(), "{error}");
+    }
+}
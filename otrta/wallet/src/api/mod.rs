@@ -3,5 +3,5 @@ pub mod client;
 pub mod models;
 
 pub use base::CashuWalletApi;
-pub use client::CashuWalletClient;
+pub use client::{CashuWalletClient, ExclusiveWallet, WalletHttpError, WalletLockTimeout};
 pub use models::*;
@@ -33,6 +33,53 @@ pub struct ChatCompletionRequest {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// A request to OpenAI's Responses API. `input` is a string or a list of
+/// input items; everything else, including `previous_response_id`, passes
+/// through untouched.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResponsesRequest {
+    pub model: String,
+    #[serde(default)]
+    pub input: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
@@ -80,9 +127,79 @@ pub struct ImageGenerationRequest {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeechRequest {
+    pub model: String,
+    pub input: String,
+    pub voice: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModerationRequest {
+    pub input: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RerankRequest {
+    pub model: String,
+    pub query: String,
+    /// Plain strings or objects with a `text` field.
+    pub documents: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_n: Option<u32>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
+    /// Selects this config through the `X-Upstream` header; the unnamed config
+    /// is the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub endpoint: String,
     pub api_key: String,
+    #[serde(default)]
+    pub fallback_endpoints: Vec<String>,
+    #[serde(default = "default_payment_header")]
+    pub payment_header: String,
+    #[serde(default = "default_change_header")]
+    pub change_header: String,
+    /// Mint the upstream accepts tokens from; the wallet's default mint when unset.
+    #[serde(default)]
+    pub mint_url: Option<String>,
+}
+
+// Written out so the upstream API key never ends up in a log line.
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("name", &self.name)
+            .field("endpoint", &self.endpoint)
+            .field("api_key", &"[REDACTED]")
+            .field("fallback_endpoints", &self.fallback_endpoints)
+            .field("payment_header", &self.payment_header)
+            .field("change_header", &self.change_header)
+            .field("mint_url", &self.mint_url)
+            .finish()
+    }
+}
+
+pub fn default_payment_header() -> String {
+    "X-PAYMENT-SATS".to_string()
+}
+
+pub fn default_change_header() -> String {
+    "X-CHANGE-SATS".to_string()
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -172,3 +289,61 @@ pub struct TopLogProb {
     pub logprob: f32,
     pub bytes: Option<Vec<u8>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn forwards_a_legacy_completion_request_unchanged() {
+        let body = json!({
+            "model": "gpt-3.5-turbo-instruct",
+            "prompt": ["first", "second"],
+            "max_tokens": 16,
+            "stream": true,
+            "best_of": 2,
+        });
+
+        let request: CompletionRequest = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(request.stream, Some(true));
+        assert_eq!(serde_json::to_value(&request).unwrap(), body);
+    }
+
+    #[test]
+    fn forwards_a_moderation_request_unchanged() {
+        let body = json!({
+            "input": ["first", "second"],
+            "model": "omni-moderation-latest",
+            "user": "abc",
+        });
+
+        let request: ModerationRequest = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(request.model.as_deref(), Some("omni-moderation-latest"));
+        assert_eq!(serde_json::to_value(&request).unwrap(), body);
+        let without_model: ModerationRequest =
+            serde_json::from_value(json!({ "input": "hi" })).unwrap();
+        assert_eq!(
+            serde_json::to_value(&without_model).unwrap(),
+            json!({ "input": "hi" })
+        );
+    }
+
+    #[test]
+    fn forwards_a_rerank_request_unchanged() {
+        let body = json!({
+            "model": "rerank-english-v3.0",
+            "query": "capital of France",
+            "documents": ["Paris", { "text": "Berlin" }],
+            "top_n": 1,
+            "return_documents": true,
+        });
+
+        let request: RerankRequest = serde_json::from_value(body.clone()).unwrap();
+
+        assert_eq!(request.documents.len(), 2);
+        assert_eq!(serde_json::to_value(&request).unwrap(), body);
+    }
+}